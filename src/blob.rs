@@ -0,0 +1,164 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+use opentelemetry::KeyValue;
+use crate::telemetry;
+
+// Content-addressed storage for object data. Each bucket keeps its blobs
+// under `{bucket}/.blobs/{hash[..2]}/{hash}`, named by the SHA-256 of their
+// content, so identical bodies are stored once. Versions hold a reference
+// to their blob; blobs whose reference count drops to zero are removed by
+// `collect_garbage`.
+pub struct BlobStore {
+    refs: Mutex<HashMap<(String, String), usize>>, // (bucket, hash) -> reference count
+}
+
+impl BlobStore {
+    pub fn new() -> Self {
+        BlobStore {
+            refs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Store `content` and take a reference to it, returning its hash
+    pub fn put(&self, bucket: &str, content: &[u8]) -> io::Result<String> {
+        telemetry::in_span("blob.put", [KeyValue::new("size", content.len() as i64)], || {
+            let hash = format!("{:x}", Sha256::digest(content));
+            let path = blob_path(bucket, &hash);
+            // Write to a temporary file first so readers never see a partial
+            // blob; only moving it into place and counting the reference
+            // happen under the lock, so garbage collection can't remove it
+            // in between
+            let tmp_path = if path.exists() {
+                None
+            } else {
+                fs::create_dir_all(path.parent().unwrap())?;
+                let tmp_path = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4().simple()));
+                let mut file = File::create(&tmp_path)?;
+                file.write_all(content)?;
+                Some(tmp_path)
+            };
+            let mut refs = self.refs.lock().unwrap();
+            match tmp_path {
+                Some(tmp_path) => fs::rename(&tmp_path, &path)?,
+                None if !path.exists() => {
+                    fs::create_dir_all(path.parent().unwrap())?;
+                    fs::write(&path, content)?;
+                }
+                None => {}
+            }
+            *refs.entry((bucket.to_string(), hash.clone())).or_default() += 1;
+            Ok(hash)
+        })
+    }
+
+    // Take an additional reference to an existing blob
+    pub fn retain(&self, bucket: &str, hash: &str) {
+        *self.refs.lock().unwrap()
+            .entry((bucket.to_string(), hash.to_string()))
+            .or_default() += 1;
+    }
+
+    // Drop a reference; unreferenced blobs stay on disk until collected
+    pub fn release(&self, bucket: &str, hash: &str) {
+        if let Some(count) = self.refs.lock().unwrap().get_mut(&(bucket.to_string(), hash.to_string())) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn ref_count(&self, bucket: &str, hash: &str) -> usize {
+        self.refs.lock().unwrap()
+            .get(&(bucket.to_string(), hash.to_string()))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    // Drop every reference held for a bucket, as after a restart
+    #[cfg(test)]
+    pub fn forget_bucket(&self, bucket: &str) {
+        self.refs.lock().unwrap().retain(|(b, _), _| b != bucket);
+    }
+
+    pub fn read(&self, bucket: &str, hash: &str) -> io::Result<Vec<u8>> {
+        telemetry::in_span("blob.read", [], || {
+            let mut file = File::open(blob_path(bucket, hash))?;
+            let mut content = Vec::new();
+            file.read_to_end(&mut content)?;
+            Ok(content)
+        })
+    }
+
+    // Read `len` bytes starting at `offset`
+    pub fn read_range(&self, bucket: &str, hash: &str, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        telemetry::in_span("blob.read_range", [KeyValue::new("size", len as i64)], || {
+            let mut file = File::open(blob_path(bucket, hash))?;
+            file.seek(SeekFrom::Start(offset))?;
+            let mut content = vec![0; len as usize];
+            file.read_exact(&mut content)?;
+            Ok(content)
+        })
+    }
+
+    // Remove every blob that is no longer referenced, returning how many were removed
+    pub fn collect_garbage(&self) -> io::Result<usize> {
+        let mut refs = self.refs.lock().unwrap();
+        let garbage: Vec<(String, String)> = refs.iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for (bucket, hash) in &garbage {
+            let path = blob_path(bucket, hash);
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
+            refs.remove(&(bucket.clone(), hash.clone()));
+        }
+        Ok(garbage.len())
+    }
+}
+
+fn blob_path(bucket: &str, hash: &str) -> PathBuf {
+    Path::new(bucket).join(".blobs").join(&hash[..2]).join(hash)
+}
+
+lazy_static! {
+    pub static ref BLOB_STORE: BlobStore = BlobStore::new();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identical_content_is_stored_once() {
+        let bucket = "testbucket_blob_dedup";
+        let _ = fs::remove_dir_all(bucket);
+        let first = BLOB_STORE.put(bucket, b"same content").unwrap();
+        let second = BLOB_STORE.put(bucket, b"same content").unwrap();
+        assert_eq!(first, second);
+        assert_eq!(BLOB_STORE.ref_count(bucket, &first), 2);
+        assert_eq!(BLOB_STORE.read(bucket, &first).unwrap(), b"same content");
+        let _ = fs::remove_dir_all(bucket);
+    }
+
+    #[test]
+    fn test_garbage_collection_removes_unreferenced_blobs() {
+        let bucket = "testbucket_blob_gc";
+        let _ = fs::remove_dir_all(bucket);
+        let kept = BLOB_STORE.put(bucket, b"kept").unwrap();
+        let dropped = BLOB_STORE.put(bucket, b"dropped").unwrap();
+        BLOB_STORE.retain(bucket, &dropped);
+        BLOB_STORE.release(bucket, &dropped);
+        BLOB_STORE.release(bucket, &dropped);
+
+        BLOB_STORE.collect_garbage().unwrap();
+        assert!(blob_path(bucket, &kept).exists());
+        assert!(!blob_path(bucket, &dropped).exists());
+        let _ = fs::remove_dir_all(bucket);
+    }
+}
@@ -0,0 +1,411 @@
+use serde::Deserialize;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use lazy_static::lazy_static;
+
+// Server settings, read from a TOML file (`--config <path>`, or `r3.toml`
+// in the working directory if present) with `R3_*` environment overrides
+
+const DEFAULT_CONFIG_FILE: &str = "r3.toml";
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    // Requests are accepted unsigned; signatures are checked when present
+    None,
+    // S3 requests must carry a valid SigV4 signature or presigned URL
+    Sigv4,
+}
+
+// A certificate served to clients asking for `server_name` (SNI)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniCertificate {
+    pub server_name: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+// HTTPS listener; certificate and key paths are PEM files
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub listen: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub sni: Vec<SniCertificate>,
+    // CA bundle client certificates are verified against (mTLS)
+    pub client_ca: Option<PathBuf>,
+    // Refuse connections without a client certificate
+    pub require_client_cert: bool,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            listen: "127.0.0.1:8443".to_string(),
+            cert: PathBuf::new(),
+            key: PathBuf::new(),
+            sni: Vec::new(),
+            client_ca: None,
+            require_client_cert: false,
+        }
+    }
+}
+
+impl TlsConfig {
+    // The certificate and key files, which are reloaded when they change;
+    // `client_ca` is only read at startup
+    pub fn certificate_files(&self) -> Vec<&Path> {
+        let mut files = vec![self.cert.as_path(), self.key.as_path()];
+        files.extend(self.sni.iter().flat_map(|sni| [sni.cert.as_path(), sni.key.as_path()]));
+        files
+    }
+}
+
+// OpenTelemetry tracing; spans are exported over OTLP/HTTP
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    // Collector traces endpoint
+    pub otlp_endpoint: String,
+    pub service_name: String,
+    // Fraction of requests traced, from 0.0 to 1.0
+    pub sample_ratio: f64,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        TracingConfig {
+            otlp_endpoint: "http://localhost:4318/v1/traces".to_string(),
+            service_name: "r3".to_string(),
+            sample_ratio: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: String,
+    pub website_listen: String,
+    // Buckets and objects; the server runs from this directory
+    pub data_dir: PathBuf,
+    // Bucket configuration, keys and credentials; defaults to `data_dir`
+    pub metadata_dir: Option<PathBuf>,
+    pub region: String,
+    // Largest object or part body accepted, in bytes
+    pub max_object_size: u64,
+    // HTTP worker threads; 0 uses one per CPU core
+    pub workers: usize,
+    pub log_level: String,
+    pub auth_mode: AuthMode,
+    // Users allowed to manage KMS keys and bypass governance retention
+    pub admins: Vec<String>,
+    // Remote KMS speaking the AWS KMS JSON protocol; keys are kept locally
+    // when unset
+    pub kms_endpoint: Option<String>,
+    // Domain suffixes buckets are addressed under (`{bucket}.{domain}`)
+    pub domains: Vec<String>,
+    pub tls: Option<TlsConfig>,
+    pub tracing: Option<TracingConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: "127.0.0.1:8080".to_string(),
+            website_listen: "127.0.0.1:8081".to_string(),
+            data_dir: PathBuf::from("."),
+            metadata_dir: None,
+            region: crate::sigv4::DEFAULT_REGION.to_string(),
+            max_object_size: 5 * 1024 * 1024 * 1024,
+            workers: 0,
+            log_level: "info".to_string(),
+            auth_mode: AuthMode::None,
+            admins: Vec::new(),
+            kms_endpoint: None,
+            domains: vec!["localhost".to_string()],
+            tls: None,
+            tracing: None,
+        }
+    }
+}
+
+lazy_static! {
+    static ref CONFIG: RwLock<Arc<Config>> = RwLock::new(Arc::new(Config::default()));
+}
+
+pub fn current() -> Arc<Config> {
+    CONFIG.read().unwrap().clone()
+}
+
+pub fn set(config: Config) {
+    *CONFIG.write().unwrap() = Arc::new(config);
+}
+
+// Restores the configuration it replaced when dropped, so a test that
+// changes the configuration doesn't leak it into other tests
+#[cfg(test)]
+pub struct TestConfig(Arc<Config>);
+
+#[cfg(test)]
+impl Drop for TestConfig {
+    fn drop(&mut self) {
+        *CONFIG.write().unwrap() = self.0.clone();
+    }
+}
+
+#[cfg(test)]
+pub fn set_for_test(config: Config) -> TestConfig {
+    TestConfig(std::mem::replace(&mut *CONFIG.write().unwrap(), Arc::new(config)))
+}
+
+// Where the metadata file `name` is kept
+pub fn metadata_path(name: &str) -> PathBuf {
+    match &current().metadata_dir {
+        Some(dir) => dir.join(name),
+        #[cfg(not(test))]
+        None => PathBuf::from(name),
+        #[cfg(test)]
+        None => TEST_METADATA_DIR.join(name),
+    }
+}
+
+#[cfg(test)]
+lazy_static! {
+    // Tests keep their metadata out of the working tree
+    static ref TEST_METADATA_DIR: PathBuf = {
+        let dir = std::env::temp_dir().join(format!("r3-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    };
+}
+
+pub fn parse(contents: &str) -> Result<Config, String> {
+    toml::from_str(contents).map_err(|e| e.message().to_string())
+}
+
+// A comma-separated list, without blank entries
+fn list(value: &str) -> Vec<String> {
+    value.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+}
+
+// Apply `R3_*` overrides from `vars`. An unknown `R3_*` name is an error,
+// like an unknown key in the file, so a misspelled override isn't ignored.
+pub fn apply_env(config: &mut Config, vars: impl IntoIterator<Item = (String, String)>) -> Result<(), String> {
+    for (name, value) in vars {
+        let invalid = |what: &str| format!("{}: {} is not {}", name, value, what);
+        match name.as_str() {
+            "R3_LISTEN" => config.listen = value,
+            "R3_WEBSITE_LISTEN" => config.website_listen = value,
+            "R3_DATA_DIR" => config.data_dir = PathBuf::from(value),
+            "R3_METADATA_DIR" => config.metadata_dir = Some(PathBuf::from(value)),
+            "R3_REGION" => config.region = value,
+            "R3_MAX_OBJECT_SIZE" => {
+                config.max_object_size = value.parse().map_err(|_| invalid("a number of bytes"))?;
+            }
+            "R3_WORKERS" => config.workers = value.parse().map_err(|_| invalid("a number of workers"))?,
+            "R3_LOG_LEVEL" => config.log_level = value,
+            "R3_AUTH_MODE" => {
+                config.auth_mode = match value.as_str() {
+                    "none" => AuthMode::None,
+                    "sigv4" => AuthMode::Sigv4,
+                    _ => return Err(invalid("none or sigv4")),
+                };
+            }
+            "R3_ADMINS" => config.admins = list(&value),
+            "R3_KMS_ENDPOINT" => config.kms_endpoint = Some(value),
+            "R3_DOMAINS" => config.domains = list(&value),
+            "R3_TLS_LISTEN" => config.tls.get_or_insert_with(TlsConfig::default).listen = value,
+            "R3_TLS_CERT" => config.tls.get_or_insert_with(TlsConfig::default).cert = PathBuf::from(value),
+            "R3_TLS_KEY" => config.tls.get_or_insert_with(TlsConfig::default).key = PathBuf::from(value),
+            "R3_TLS_CLIENT_CA" => {
+                config.tls.get_or_insert_with(TlsConfig::default).client_ca = Some(PathBuf::from(value));
+            }
+            "R3_OTLP_ENDPOINT" => config.tracing.get_or_insert_with(TracingConfig::default).otlp_endpoint = value,
+            _ if name.starts_with("R3_") => return Err(format!("{}: unknown setting", name)),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), String> {
+        let mut addresses = vec![("listen", &self.listen), ("website_listen", &self.website_listen)];
+        addresses.extend(self.tls.as_ref().map(|tls| ("tls.listen", &tls.listen)));
+        for (i, (name, address)) in addresses.iter().enumerate() {
+            if !address.to_socket_addrs().is_ok_and(|mut addrs| addrs.next().is_some()) {
+                return Err(format!("{}: {} is not a host:port address", name, address));
+            }
+            if let Some((other, _)) = addresses[..i].iter().find(|(_, other)| other == address) {
+                return Err(format!("{} and {} must differ", other, name));
+            }
+        }
+        if let Some(tls) = &self.tls {
+            if tls.cert.as_os_str().is_empty() || tls.key.as_os_str().is_empty() {
+                return Err("tls: cert and key are required".to_string());
+            }
+            if tls.require_client_cert && tls.client_ca.is_none() {
+                return Err("tls: require_client_cert needs client_ca".to_string());
+            }
+            if let Some(sni) = tls.sni.iter().find(|sni| sni.server_name.is_empty()) {
+                return Err(format!("tls.sni: {} has no server_name", sni.cert.display()));
+            }
+        }
+        if let Some(endpoint) = self.kms_endpoint.as_ref().filter(|e| !e.starts_with("http://") && !e.starts_with("https://")) {
+            return Err(format!("kms_endpoint: {} is not an http(s) URL", endpoint));
+        }
+        if let Some(tracing) = &self.tracing {
+            if !tracing.otlp_endpoint.starts_with("http://") && !tracing.otlp_endpoint.starts_with("https://") {
+                return Err(format!("tracing.otlp_endpoint: {} is not an http(s) URL", tracing.otlp_endpoint));
+            }
+            if !(0.0..=1.0).contains(&tracing.sample_ratio) {
+                return Err(format!("tracing.sample_ratio: {} is not between 0 and 1", tracing.sample_ratio));
+            }
+        }
+        if self.region.is_empty() || !self.region.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(format!("region: {:?} is not a valid region name", self.region));
+        }
+        if self.max_object_size == 0 {
+            return Err("max_object_size must be greater than 0".to_string());
+        }
+        if !LOG_LEVELS.contains(&self.log_level.to_ascii_lowercase().as_str()) {
+            return Err(format!("log_level: {} is not one of {}", self.log_level, LOG_LEVELS.join(", ")));
+        }
+        Ok(())
+    }
+
+    // Create the data and metadata directories and make the metadata
+    // directory and TLS files absolute, so they stay put once the server
+    // moves to `data_dir`
+    pub fn prepare_dirs(&mut self) -> Result<(), String> {
+        let metadata_dir = self.metadata_dir.clone().unwrap_or_else(|| self.data_dir.clone());
+        for (name, dir) in [("data_dir", &self.data_dir), ("metadata_dir", &metadata_dir)] {
+            std::fs::create_dir_all(dir).map_err(|e| format!("{}: cannot create {}: {}", name, dir.display(), e))?;
+        }
+        let metadata_dir = std::fs::canonicalize(&metadata_dir)
+            .map_err(|e| format!("metadata_dir: {}: {}", metadata_dir.display(), e))?;
+        self.metadata_dir = Some(metadata_dir);
+        if let Some(tls) = &mut self.tls {
+            let absolute = |path: &mut PathBuf| -> Result<(), String> {
+                *path = std::path::absolute(&*path).map_err(|e| format!("tls: {}: {}", path.display(), e))?;
+                Ok(())
+            };
+            absolute(&mut tls.cert)?;
+            absolute(&mut tls.key)?;
+            for sni in &mut tls.sni {
+                absolute(&mut sni.cert)?;
+                absolute(&mut sni.key)?;
+            }
+            if let Some(client_ca) = &mut tls.client_ca {
+                absolute(client_ca)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Read the configuration file (if any), apply environment overrides and
+// validate the result
+pub fn load(path: Option<&Path>) -> Result<Config, String> {
+    let mut config = match path {
+        Some(path) => {
+            let contents = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))?
+        }
+        None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+            let contents = std::fs::read_to_string(DEFAULT_CONFIG_FILE)
+                .map_err(|e| format!("{}: {}", DEFAULT_CONFIG_FILE, e))?;
+            parse(&contents).map_err(|e| format!("{}: {}", DEFAULT_CONFIG_FILE, e))?
+        }
+        None => Config::default(),
+    };
+    apply_env(&mut config, std::env::vars().filter(|(name, _)| name.starts_with("R3_")))?;
+    config.validate()?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_with_env_overrides() {
+        let mut config = parse(r#"
+            listen = "0.0.0.0:9000"
+            data_dir = "/srv/r3"
+            max_object_size = 1048576
+            auth_mode = "sigv4"
+        "#).unwrap();
+        assert_eq!(config.listen, "0.0.0.0:9000");
+        assert_eq!(config.website_listen, "127.0.0.1:8081");
+        assert_eq!(config.max_object_size, 1048576);
+        assert_eq!(config.auth_mode, AuthMode::Sigv4);
+        assert!(config.metadata_dir.is_none());
+
+        apply_env(&mut config, [
+            ("R3_LISTEN".to_string(), "127.0.0.1:9100".to_string()),
+            ("R3_REGION".to_string(), "eu-west-1".to_string()),
+            ("R3_WORKERS".to_string(), "4".to_string()),
+            ("R3_AUTH_MODE".to_string(), "none".to_string()),
+            ("R3_ADMINS".to_string(), "alice, bob".to_string()),
+            ("R3_DOMAINS".to_string(), "example.com".to_string()),
+        ]).unwrap();
+        assert_eq!(config.listen, "127.0.0.1:9100");
+        assert_eq!(config.region, "eu-west-1");
+        assert_eq!(config.workers, 4);
+        assert_eq!(config.auth_mode, AuthMode::None);
+        assert_eq!(config.admins, ["alice", "bob"]);
+        assert_eq!(config.domains, ["example.com"]);
+        assert!(config.validate().is_ok());
+        let misspelled = apply_env(&mut config, [("R3_LISTN".to_string(), "0.0.0.0:80".to_string())]);
+        assert_eq!(misspelled.unwrap_err(), "R3_LISTN: unknown setting");
+        assert!(apply_env(&mut config, [("HOME".to_string(), "/root".to_string())]).is_ok());
+
+        assert!(config.tls.is_none());
+        apply_env(&mut config, [
+            ("R3_TLS_CERT".to_string(), "server.crt".to_string()),
+            ("R3_TLS_KEY".to_string(), "server.key".to_string()),
+        ]).unwrap();
+        let tls = config.tls.as_ref().unwrap();
+        assert_eq!(tls.listen, "127.0.0.1:8443");
+        assert_eq!(tls.certificate_files(), [Path::new("server.crt"), Path::new("server.key")]);
+        assert!(config.validate().is_ok());
+
+        assert!(config.tracing.is_none());
+        apply_env(&mut config, [("R3_OTLP_ENDPOINT".to_string(), "http://collector:4318/v1/traces".to_string())]).unwrap();
+        let tracing = config.tracing.as_ref().unwrap();
+        assert_eq!((tracing.otlp_endpoint.as_str(), tracing.service_name.as_str()), ("http://collector:4318/v1/traces", "r3"));
+        assert!(config.validate().is_ok());
+
+        let err = apply_env(&mut config, [("R3_WORKERS".to_string(), "many".to_string())]).unwrap_err();
+        assert!(err.contains("R3_WORKERS"), "{}", err);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(parse("listen = 8080").is_err());
+        assert!(parse("port = 8080").unwrap_err().contains("port"));
+        assert!(parse("auth_mode = \"basic\"").is_err());
+
+        for (config, field) in [
+            (Config { listen: "localhost".to_string(), ..Default::default() }, "listen"),
+            (Config { region: String::new(), ..Default::default() }, "region"),
+            (Config { max_object_size: 0, ..Default::default() }, "max_object_size"),
+            (Config { log_level: "verbose".to_string(), ..Default::default() }, "log_level"),
+            (Config { website_listen: "127.0.0.1:8080".to_string(), ..Default::default() }, "website_listen"),
+            (Config { tls: Some(TlsConfig::default()), ..Default::default() }, "cert and key"),
+            (Config {
+                tracing: Some(TracingConfig { otlp_endpoint: "localhost:4318".to_string(), ..Default::default() }),
+                ..Default::default()
+            }, "otlp_endpoint"),
+        ] {
+            let err = config.validate().unwrap_err();
+            assert!(err.contains(field), "{}", err);
+        }
+    }
+}
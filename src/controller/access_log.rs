@@ -0,0 +1,415 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use crate::config;
+use crate::controller::{encryption, router, versioning};
+use crate::controller::versioning::VERSION_STORE;
+use crate::controller::virtual_host::OriginalPath;
+use crate::telemetry::{self, RequestId};
+use crate::tls;
+use crate::xml;
+
+// Server access logging: every request gets an ID and a structured log
+// record, and requests to buckets with logging enabled are also written in
+// the S3 access log format, delivered periodically as objects under the
+// bucket's target prefix
+
+const LOGGING_FILE: &str = "bucket_logging.json";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct LoggingTarget {
+    target_bucket: String,
+    target_prefix: String,
+}
+
+lazy_static! {
+    static ref BUCKET_LOGGING: Mutex<HashMap<String, LoggingTarget>> = Mutex::new(HashMap::new());
+    // Log lines not yet delivered, by source bucket
+    static ref PENDING: Mutex<HashMap<String, Vec<String>>> = Mutex::new(HashMap::new());
+}
+
+pub fn load_logging() -> std::io::Result<()> {
+    if config::metadata_path(LOGGING_FILE).exists() {
+        let mut file = File::open(config::metadata_path(LOGGING_FILE))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let targets: HashMap<String, LoggingTarget> = serde_json::from_str(&contents)?;
+        *BUCKET_LOGGING.lock().unwrap() = targets;
+    }
+    Ok(())
+}
+
+fn save_logging() -> std::io::Result<()> {
+    let targets = BUCKET_LOGGING.lock().unwrap();
+    let contents = serde_json::to_string_pretty(&*targets)?;
+    let mut file = File::create(config::metadata_path(LOGGING_FILE))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+// `<BucketLoggingStatus>` with `LoggingEnabled` enables logging; an empty
+// one disables it
+fn parse_logging(body: &[u8]) -> Result<Option<LoggingTarget>, Error> {
+    let body = std::str::from_utf8(body)
+        .map_err(|_| actix_web::error::ErrorBadRequest("MalformedXML"))?;
+    if !body.contains("<BucketLoggingStatus") {
+        return Err(actix_web::error::ErrorBadRequest("MalformedXML: expected BucketLoggingStatus"));
+    }
+    let Some(enabled) = xml::element_text(body, "LoggingEnabled") else {
+        return Ok(None);
+    };
+    let target_bucket = xml::element_text(enabled, "TargetBucket")
+        .map(xml::unescape)
+        .filter(|bucket| !bucket.is_empty())
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("MalformedXML: LoggingEnabled requires a TargetBucket"))?;
+    let target_prefix = xml::element_text(enabled, "TargetPrefix").map(xml::unescape).unwrap_or_default();
+    Ok(Some(LoggingTarget { target_bucket, target_prefix }))
+}
+
+// Whether `bucket` names an existing bucket: a plain name, not hidden or
+// reserved, with a directory in the data directory
+fn bucket_exists(bucket: &str) -> bool {
+    !bucket.is_empty()
+        && !bucket.starts_with('.')
+        && bucket.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        && !router::is_reserved_bucket(bucket)
+        && Path::new(bucket).is_dir()
+}
+
+pub async fn put_bucket_logging(path: web::Path<String>, body: web::Bytes) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    match parse_logging(&body)? {
+        Some(target) => {
+            if !bucket_exists(&target.target_bucket) {
+                return Err(actix_web::error::ErrorBadRequest(
+                    "InvalidTargetBucketForLogging: the target bucket does not exist",
+                ));
+            }
+            BUCKET_LOGGING.lock().unwrap().insert(bucket, target);
+        }
+        None => {
+            BUCKET_LOGGING.lock().unwrap().remove(&bucket);
+        }
+    }
+    save_logging().map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn get_bucket_logging(path: web::Path<String>) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    let enabled = BUCKET_LOGGING.lock().unwrap().get(&bucket).map(|target| format!(
+        "<LoggingEnabled><TargetBucket>{}</TargetBucket><TargetPrefix>{}</TargetPrefix></LoggingEnabled>",
+        xml::escape(&target.target_bucket),
+        xml::escape(&target.target_prefix)
+    )).unwrap_or_default();
+    let response = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<BucketLoggingStatus xmlns="http://s3.amazonaws.com/doc/2006-03-01/">{}</BucketLoggingStatus>"#,
+        enabled
+    );
+    Ok(HttpResponse::Ok().content_type("application/xml").body(response))
+}
+
+// Access log operation: `REST.GET.OBJECT`, `REST.PUT.TAGGING`, ...
+fn log_operation(method: &Method, path: &str, query: &str) -> String {
+    let object = path.trim_start_matches('/').split_once('/').is_some_and(|(_, key)| !key.is_empty());
+    let resource = match router::SUBRESOURCES.iter().find(|name| router::query_has_key(query, name)) {
+        Some(&"uploadId") if *method == Method::PUT => "PART".to_string(),
+        Some(&"uploadId") => "UPLOAD".to_string(),
+        Some(&"logging") => "LOGGING_STATUS".to_string(),
+        Some(subresource) => subresource.replace('-', "_").to_ascii_uppercase(),
+        None if object => "OBJECT".to_string(),
+        None => "BUCKET".to_string(),
+    };
+    format!("REST.{}.{}", method, resource)
+}
+
+// `-` for missing values; values with spaces are quoted
+fn field(value: Option<&str>) -> String {
+    match value {
+        None | Some("") => "-".to_string(),
+        Some(value) if value.contains(' ') => format!("\"{}\"", value.replace('"', "\\\"")),
+        Some(value) => value.to_string(),
+    }
+}
+
+fn quoted(value: Option<&str>) -> String {
+    match value {
+        None | Some("") => "-".to_string(),
+        Some(value) => format!("\"{}\"", value.replace('"', "\\\"")),
+    }
+}
+
+// The S3 error code of a failed request: the `Code: message` prefix of its
+// error, or one derived from its status
+pub fn error_code_for(status: StatusCode, message: Option<&str>, path: &str) -> String {
+    let code = message.and_then(|message| message.split(':').next()).map(str::trim);
+    if let Some(code) = code.filter(|code| !code.is_empty() && code.chars().all(|c| c.is_ascii_alphanumeric())) {
+        return code.to_string();
+    }
+    let object = path.trim_start_matches('/').split_once('/').is_some_and(|(_, key)| !key.is_empty());
+    match status {
+        StatusCode::FORBIDDEN => "AccessDenied".to_string(),
+        StatusCode::NOT_FOUND if object => "NoSuchKey".to_string(),
+        StatusCode::NOT_FOUND => "NoSuchBucket".to_string(),
+        StatusCode::INTERNAL_SERVER_ERROR => "InternalError".to_string(),
+        status => status.canonical_reason().unwrap_or("Error").replace(' ', ""),
+    }
+}
+
+// The access key ID a request is signed with, if any
+fn access_key_id(req: &ServiceRequest) -> Option<String> {
+    let credential = req.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split("Credential=").nth(1))
+        .map(str::to_string)
+        .or_else(|| web::Query::<HashMap<String, String>>::from_query(req.query_string()).ok()
+            .and_then(|query| query.get("X-Amz-Credential").cloned()))?;
+    credential.split('/').next().map(str::to_string)
+}
+
+// Middleware giving each request its ID, logging it, and recording it in
+// its bucket's access log
+pub async fn log_requests(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    let request_id = telemetry::new_request_id();
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+    let method = req.method().clone();
+    let path = req.path().to_string();
+    let query = req.query_string().to_string();
+    let bucket = path.trim_start_matches('/').split('/').next().unwrap_or_default().to_string();
+    let logging = BUCKET_LOGGING.lock().unwrap().contains_key(&bucket);
+
+    // Fields of the access log line known before the request is handled
+    let line = logging.then(|| {
+        let header = |name: header::HeaderName| req.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+        let original = req.extensions().get::<OriginalPath>().map(|path| path.0.clone()).unwrap_or_else(|| path.clone());
+        let request_uri = match query.as_str() {
+            "" => original,
+            query => format!("{}?{}", original, query),
+        };
+        let signed_by_header = req.headers().contains_key(header::AUTHORIZATION);
+        let presigned = router::query_has_key(&query, "X-Amz-Signature");
+        let key = path.trim_start_matches('/').split_once('/').map(|(_, key)| key.to_string());
+        AccessLine {
+            remote_ip: req.connection_info().realip_remote_addr().map(str::to_string),
+            requester: access_key_id(&req),
+            operation: log_operation(&method, &path, &query),
+            key,
+            request_uri: format!("{} {} {:?}", method, request_uri, req.version()),
+            referer: header(header::REFERER),
+            user_agent: header(header::USER_AGENT),
+            version_id: web::Query::<HashMap<String, String>>::from_query(&query).ok()
+                .and_then(|query| query.get("versionId").cloned()),
+            signature_version: (signed_by_header || presigned).then(|| "SigV4".to_string()),
+            auth_type: if signed_by_header {
+                Some("AuthHeader".to_string())
+            } else if presigned {
+                Some("QueryString".to_string())
+            } else {
+                None
+            },
+            host: req.connection_info().host().to_string(),
+            tls_version: req.conn_data::<tls::TlsVersion>().map(|version| version.0),
+        }
+    });
+
+    let result = next.call(req).await;
+    let elapsed = started.elapsed();
+    let (status, bytes_sent, error_code) = match &result {
+        Ok(res) => (
+            res.status(),
+            match res.response().body().size() {
+                BodySize::Sized(size) => Some(size),
+                _ => None,
+            },
+            res.response().error().map(|e| e.to_string()),
+        ),
+        Err(e) => (e.as_response_error().status_code(), None, Some(e.to_string())),
+    };
+    let error_code = (status.as_u16() >= 400).then(|| error_code_for(status, error_code.as_deref(), &path));
+    log::info!(
+        target: "r3::request",
+        request_id = request_id.as_str(),
+        method = method.as_str(),
+        path = path.as_str(),
+        status = status.as_u16(),
+        bytes_sent = bytes_sent.unwrap_or(0),
+        duration_ms = elapsed.as_millis() as u64;
+        "request"
+    );
+
+    if let Some(line) = line {
+        let object_size = line.key.as_deref()
+            .and_then(|key| VERSION_STORE.get_latest_version(&bucket, key))
+            .filter(|version| !version.is_delete_marker)
+            .map(|version| version.size.to_string());
+        let millis = elapsed.as_millis().to_string();
+        let fields = [
+            "-".to_string(), // bucket owner
+            field(Some(&bucket)),
+            chrono::Utc::now().format("[%d/%b/%Y:%H:%M:%S +0000]").to_string(),
+            field(line.remote_ip.as_deref()),
+            field(line.requester.as_deref()),
+            field(Some(&request_id)),
+            field(Some(&line.operation)),
+            field(line.key.as_deref()),
+            quoted(Some(&line.request_uri)),
+            status.as_u16().to_string(),
+            field(error_code.as_deref()),
+            field(bytes_sent.filter(|size| *size > 0).map(|size| size.to_string()).as_deref()),
+            field(object_size.as_deref()),
+            millis.clone(), // total time
+            millis, // turnaround time
+            quoted(line.referer.as_deref()),
+            quoted(line.user_agent.as_deref()),
+            field(line.version_id.as_deref()),
+            telemetry::host_id(&request_id),
+            field(line.signature_version.as_deref()),
+            "-".to_string(), // cipher suite
+            field(line.auth_type.as_deref()),
+            field(Some(&line.host)),
+            field(line.tls_version),
+            "-".to_string(), // access point ARN
+            "-".to_string(), // ACL required
+        ];
+        PENDING.lock().unwrap().entry(bucket).or_default().push(fields.join(" "));
+    }
+    result
+}
+
+struct AccessLine {
+    remote_ip: Option<String>,
+    requester: Option<String>,
+    operation: String,
+    key: Option<String>,
+    request_uri: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    version_id: Option<String>,
+    signature_version: Option<String>,
+    auth_type: Option<String>,
+    host: String,
+    // Negotiated by this server; unknown behind a TLS-terminating proxy
+    tls_version: Option<&'static str>,
+}
+
+// Write pending log lines as one object per source bucket under its target
+// prefix, named `{prefix}YYYY-mm-dd-HH-MM-SS-{unique}`. Returns the number
+// of objects written; lines that could not be delivered are kept.
+pub fn deliver_logs() -> Result<usize, Error> {
+    let pending = std::mem::take(&mut *PENDING.lock().unwrap());
+    let targets = BUCKET_LOGGING.lock().unwrap().clone();
+    let mut delivered = 0;
+    let mut result = Ok(());
+    for (bucket, lines) in pending {
+        // Logging was disabled since the requests were made
+        let Some(target) = targets.get(&bucket) else {
+            continue;
+        };
+        let key = format!(
+            "{}{}-{}",
+            target.target_prefix,
+            chrono::Utc::now().format("%Y-%m-%d-%H-%M-%S"),
+            telemetry::new_request_id()
+        );
+        let content = lines.join("\n") + "\n";
+        let stored = versioning::create_version_from(
+            content.as_bytes(),
+            encryption::bucket_default(&target.target_bucket).as_ref(),
+            versioning::VersionInfo {
+                bucket: target.target_bucket.clone(),
+                key,
+                etag: format!("{:x}", md5::compute(&content)),
+                metadata: HashMap::from([("content-type".to_string(), "text/plain".to_string())]),
+                ..Default::default()
+            },
+        );
+        match stored {
+            Ok(_) => delivered += 1,
+            Err(e) => {
+                PENDING.lock().unwrap().entry(bucket).or_default().splice(0..0, lines);
+                result = Err(e);
+            }
+        }
+    }
+    result.map(|_| delivered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::router;
+    use actix_web::{test, App};
+
+    #[actix_rt::test]
+    async fn test_access_logs_delivered() {
+        let (bucket, logs) = ("testbucket_accesslog", "testbucket_accesslog_target");
+        let _ = std::fs::remove_dir_all(bucket);
+        let _ = std::fs::remove_dir_all(logs);
+        std::fs::create_dir_all(logs).unwrap();
+        let app = test::init_service(App::new()
+            .wrap(actix_web::middleware::from_fn(log_requests))
+            .configure(router::configure)).await;
+
+        let body = format!(
+            "<BucketLoggingStatus><LoggingEnabled><TargetBucket>{}</TargetBucket>\
+             <TargetPrefix>logs/</TargetPrefix></LoggingEnabled></BucketLoggingStatus>",
+            logs
+        );
+        let req = test::TestRequest::put().uri(&format!("/{}?logging", bucket)).set_payload(body).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let req = test::TestRequest::get().uri(&format!("/{}?logging", bucket)).to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert!(String::from_utf8(body.to_vec()).unwrap().contains("<TargetPrefix>logs/</TargetPrefix>"));
+
+        let req = test::TestRequest::put().uri(&format!("/{}/a.txt", bucket)).set_payload("logged").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::get().uri(&format!("/{}/missing.txt", bucket))
+            .insert_header(("User-Agent", "r3 test"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 404);
+
+        assert!(deliver_logs().unwrap() >= 1);
+        let objects = VERSION_STORE.list_bucket_versions(logs);
+        let log = objects.iter().find(|version| version.key.starts_with("logs/")).unwrap();
+        let content = String::from_utf8(versioning::read_version_with(log, None).unwrap()).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert!(lines.iter().any(|line| line.contains(" REST.PUT.OBJECT a.txt \"PUT /testbucket_accesslog/a.txt HTTP/1.1\" 201 ")), "{}", content);
+        let missing = lines.iter().find(|line| line.contains("REST.GET.OBJECT missing.txt")).unwrap();
+        assert!(missing.contains(" 404 NoSuchKey ") && missing.contains("\"r3 test\""), "{}", missing);
+        // Plain HTTP has no TLS version
+        assert!(missing.ends_with(" - - -"), "{}", missing);
+
+        // Disabling logging; a target bucket must exist
+        let req = test::TestRequest::put().uri(&format!("/{}?logging", bucket))
+            .set_payload("<BucketLoggingStatus/>").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let req = test::TestRequest::get().uri(&format!("/{}?logging", bucket)).to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert!(!String::from_utf8(body.to_vec()).unwrap().contains("LoggingEnabled"));
+        let req = test::TestRequest::put().uri(&format!("/{}?logging", bucket))
+            .set_payload("<BucketLoggingStatus><LoggingEnabled><TargetBucket>testbucket_nowhere</TargetBucket></LoggingEnabled></BucketLoggingStatus>")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+        // Only bucket names, not paths, are targets
+        for target in ["..", "/tmp", &format!("{}/.blobs", logs)] {
+            let req = test::TestRequest::put().uri(&format!("/{}?logging", bucket))
+                .set_payload(format!("<BucketLoggingStatus><LoggingEnabled><TargetBucket>{}</TargetBucket></LoggingEnabled></BucketLoggingStatus>", target))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 400, "{}", target);
+        }
+        let _ = std::fs::remove_dir_all(bucket);
+        let _ = std::fs::remove_dir_all(logs);
+    }
+}
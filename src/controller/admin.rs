@@ -0,0 +1,264 @@
+use actix_web::{web, HttpRequest, HttpResponse, Responder, Error, get, post};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::controller::{auth, versioning};
+use crate::kms;
+use crate::sigv4;
+use crate::xml;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RestoreRequest {
+    pub bucket: String,
+    #[serde(default)]
+    pub prefix: String,
+    // RFC 3339 timestamp, e.g. "2024-05-01T12:00:00Z"
+    pub timestamp: String,
+}
+
+// Parse an RFC 3339 timestamp into unix seconds
+pub fn parse_timestamp(timestamp: &str) -> Result<u64, Error> {
+    xml::parse_timestamp(timestamp)
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("Invalid timestamp"))
+}
+
+// Restore every key under a prefix to the state it had at a point in time
+#[post("/admin/restore")]
+pub async fn restore_prefix(req: HttpRequest, request: web::Json<RestoreRequest>) -> Result<impl Responder, Error> {
+    auth::require_admin(&req)?;
+    let timestamp = parse_timestamp(&request.timestamp)?;
+    let summary = versioning::restore_prefix(&request.bucket, &request.prefix, timestamp)?;
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PresignRequest {
+    pub access_key_id: String,
+    pub method: String,
+    pub bucket: String,
+    pub key: String,
+    #[serde(default)]
+    pub query: BTreeMap<String, String>, // e.g. partNumber and uploadId for part uploads
+    #[serde(default = "default_expiry")]
+    pub expires: u64, // seconds, at most 7 days
+}
+
+fn default_expiry() -> u64 {
+    3600
+}
+
+// Generate a presigned URL to this server for one of the caller's own
+// access keys; the caller has to be authenticated
+#[post("/admin/presign")]
+pub async fn presign(req: HttpRequest, request: web::Json<PresignRequest>) -> Result<impl Responder, Error> {
+    let caller = auth::principal(&req)
+        .ok_or_else(|| actix_web::error::ErrorForbidden("AccessDenied: presigning requires an authenticated request"))?;
+    if auth::key_owner(&request.access_key_id).as_ref() != Some(&caller) {
+        return Err(actix_web::error::ErrorForbidden("AccessDenied: the access key does not belong to the caller"));
+    }
+    let secret = auth::secret_key(&request.access_key_id)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Unknown access key"))?;
+    let query: Vec<String> = request.query.iter()
+        .map(|(name, value)| format!("{}={}", sigv4::uri_encode(name, true), sigv4::uri_encode(value, true)))
+        .collect();
+    let connection = req.connection_info();
+    let mut url = format!(
+        "{}://{}/{}/{}",
+        connection.scheme(),
+        connection.host(),
+        sigv4::uri_encode(&request.bucket, true),
+        sigv4::uri_encode(&request.key, false)
+    );
+    if !query.is_empty() {
+        url = format!("{}?{}", url, query.join("&"));
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let url = sigv4::presign_url(&request.method.to_uppercase(), &url, &request.access_key_id, &secret, request.expires, now)
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("expires must be between 1 second and 7 days"))?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "url": url })))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateKeyRequest {
+    pub key_id: String,
+}
+
+fn kms_error(e: std::io::Error) -> Error {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => actix_web::error::ErrorNotFound(e),
+        std::io::ErrorKind::AlreadyExists => actix_web::error::ErrorConflict(e),
+        std::io::ErrorKind::Unsupported => actix_web::error::ErrorNotImplemented(e),
+        _ => actix_web::error::ErrorInternalServerError(e),
+    }
+}
+
+// Create a named master key in the local KMS
+#[post("/admin/kms/keys")]
+pub async fn create_kms_key(req: HttpRequest, request: web::Json<CreateKeyRequest>) -> Result<impl Responder, Error> {
+    auth::require_admin(&req)?;
+    if request.key_id.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("Key ID must not be empty"));
+    }
+    let key = kms::key_manager().create_key(&request.key_id).map_err(kms_error)?;
+    Ok(HttpResponse::Ok().json(key))
+}
+
+#[get("/admin/kms/keys")]
+pub async fn list_kms_keys(req: HttpRequest) -> Result<impl Responder, Error> {
+    auth::require_admin(&req)?;
+    let keys = kms::key_manager().list_keys().map_err(kms_error)?;
+    Ok(HttpResponse::Ok().json(keys))
+}
+
+// Add new key material; data keys wrapped under older material stay readable
+#[post("/admin/kms/keys/{key_id:.+}/rotate")]
+pub async fn rotate_kms_key(req: HttpRequest, path: web::Path<String>) -> Result<impl Responder, Error> {
+    auth::require_admin(&req)?;
+    let key = kms::key_manager().rotate_key(&path.into_inner()).map_err(kms_error)?;
+    Ok(HttpResponse::Ok().json(key))
+}
+
+// A disabled key can neither encrypt new versions nor decrypt existing ones
+#[post("/admin/kms/keys/{key_id:.+}/{action:enable|disable}")]
+pub async fn set_kms_key_enabled(req: HttpRequest, path: web::Path<(String, String)>) -> Result<impl Responder, Error> {
+    auth::require_admin(&req)?;
+    let (key_id, action) = path.into_inner();
+    let key = kms::key_manager().set_key_enabled(&key_id, action == "enable").map_err(kms_error)?;
+    Ok(HttpResponse::Ok().json(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::versioning::{add_version_at as add_version, VersioningStatus, VERSION_STORE};
+    use actix_web::{test, App};
+    use serial_test::serial;
+    use std::fs;
+
+    #[actix_rt::test]
+    #[serial(config)]
+    async fn test_restore_prefix_to_point_in_time() {
+        let bucket = "testbucket_admin_restore";
+        let _ = fs::remove_dir_all(bucket);
+        VERSION_STORE.set_status(bucket, VersioningStatus::Enabled);
+        // docs/a: v1 at 100, v2 at 300 -> restored to v1
+        add_version(bucket, "docs/a", "a1", Some(b"a-old"), 100);
+        add_version(bucket, "docs/a", "a2", Some(b"a-new"), 300);
+        // docs/b: created at 300 -> deleted
+        add_version(bucket, "docs/b", "b1", Some(b"b"), 300);
+        // docs/c: deleted at 150, recreated at 300 -> deleted
+        add_version(bucket, "docs/c", "c1", Some(b"c"), 100);
+        add_version(bucket, "docs/c", "c2", None, 150);
+        add_version(bucket, "docs/c", "c3", Some(b"c-again"), 300);
+        // docs/d: untouched since 100
+        add_version(bucket, "docs/d", "d1", Some(b"d"), 100);
+        // outside the prefix
+        add_version(bucket, "other", "o1", Some(b"o"), 300);
+
+        let app = test::init_service(App::new().service(restore_prefix)).await;
+        let restore_request = || RestoreRequest {
+            bucket: bucket.to_string(),
+            prefix: "docs/".to_string(),
+            timestamp: "1970-01-01T00:03:20Z".to_string(), // 200
+        };
+
+        // Only admins can restore
+        let req = test::TestRequest::post().uri("/admin/restore").set_json(restore_request()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+        assert!(!VERSION_STORE.get_latest_version(bucket, "docs/b").unwrap().is_delete_marker);
+
+        let _admin = auth::as_test_admin();
+        let req = test::TestRequest::post()
+            .uri(&auth::test_admin_uri("POST", "/admin/restore"))
+            .insert_header(("Host", "localhost:8080"))
+            .set_json(restore_request())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["restored"], serde_json::json!(["docs/a"]));
+        assert_eq!(body["deleted"], serde_json::json!(["docs/b", "docs/c"]));
+        assert_eq!(body["unchanged"], 1);
+
+        let latest = VERSION_STORE.get_latest_version(bucket, "docs/a").unwrap();
+        assert_eq!(versioning::read_version(&latest).unwrap(), b"a-old");
+        assert!(VERSION_STORE.get_latest_version(bucket, "docs/b").unwrap().is_delete_marker);
+        assert!(!VERSION_STORE.get_latest_version(bucket, "other").unwrap().is_delete_marker);
+        let _ = fs::remove_dir_all(bucket);
+    }
+
+    #[actix_rt::test]
+    #[serial(config)]
+    async fn test_restore_requires_versioning() {
+        let _admin = auth::as_test_admin();
+        let app = test::init_service(App::new().service(restore_prefix)).await;
+        let req = test::TestRequest::post()
+            .uri(&auth::test_admin_uri("POST", "/admin/restore"))
+            .insert_header(("Host", "localhost:8080"))
+            .set_json(RestoreRequest {
+                bucket: "testbucket_admin_unversioned".to_string(),
+                prefix: String::new(),
+                timestamp: "2024-01-01T00:00:00Z".to_string(),
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[actix_rt::test]
+    async fn test_kms_administration_requires_an_admin() {
+        let app = test::init_service(App::new()
+            .service(create_kms_key)
+            .service(list_kms_keys)
+            .service(rotate_kms_key)).await;
+        let req = test::TestRequest::post()
+            .uri("/admin/kms/keys")
+            .set_json(CreateKeyRequest { key_id: "testkey_admin_denied".to_string() })
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+        let req = test::TestRequest::get().uri("/admin/kms/keys").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+        let req = test::TestRequest::post().uri("/admin/kms/keys/aws/s3/rotate").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+        assert!(kms::key_manager().describe_key("testkey_admin_denied").is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_presign_only_for_own_keys() {
+        for (id, secret, username) in [("R3TESTPRESIGNOWNER0", "owner-secret", "owner"), ("R3TESTPRESIGNOTHER0", "other-secret", "other")] {
+            auth::add_access_key(auth::AccessKey {
+                access_key_id: id.to_string(),
+                secret_access_key: secret.to_string(),
+                username: username.to_string(),
+            }).unwrap();
+        }
+        let app = test::init_service(App::new().service(presign)).await;
+        let presign_request = |access_key_id: &str| PresignRequest {
+            access_key_id: access_key_id.to_string(),
+            method: "GET".to_string(),
+            bucket: "testbucket_presign".to_string(),
+            key: "a.txt".to_string(),
+            query: BTreeMap::new(),
+            expires: 60,
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let signed = sigv4::presign_url("POST", "http://localhost:8080/admin/presign", "R3TESTPRESIGNOWNER0", "owner-secret", 60, now)
+            .unwrap();
+        let signed = signed.trim_start_matches("http://localhost:8080");
+
+        // Unauthenticated callers get nothing signed
+        let req = test::TestRequest::post().uri("/admin/presign").set_json(presign_request("R3TESTPRESIGNOWNER0")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+
+        // Callers can only presign with their own keys
+        let req = test::TestRequest::post().uri(signed).insert_header(("Host", "localhost:8080"))
+            .set_json(presign_request("R3TESTPRESIGNOTHER0")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+        let req = test::TestRequest::post().uri(signed).insert_header(("Host", "localhost:8080"))
+            .set_json(presign_request("R3TESTPRESIGNOWNER0")).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert!(body["url"].as_str().unwrap().contains("X-Amz-Credential=R3TESTPRESIGNOWNER0"));
+    }
+}
@@ -1,10 +1,29 @@
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse, Responder};
 use actix_web::post;
 use serde::{Deserialize, Serialize};
 use bcrypt::{hash, verify, DEFAULT_COST};
 use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
 use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 use lazy_static::lazy_static;
+use aes_gcm::aead::OsRng;
+use aes_gcm::aead::rand_core::RngCore;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use bytes::BytesMut;
+use futures_util::StreamExt;
+use crate::config;
+use crate::controller::object;
+use crate::sigv4;
+use crate::tls;
+
+const ACCESS_KEYS_FILE: &str = "access_keys.json";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
@@ -12,23 +31,165 @@ pub struct User {
     pub password: String,
 }
 
+// Key pair for signing S3 requests (SigV4). The secret has to be kept in
+// the clear to verify signatures, so the key file is only readable by the owner.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessKey {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub username: String,
+}
+
 lazy_static! {
     static ref USER_STORE: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+    static ref ACCESS_KEYS: Mutex<HashMap<String, AccessKey>> = Mutex::new(HashMap::new()); // access key ID -> key
+}
+
+pub fn load_access_keys() -> std::io::Result<()> {
+    if config::metadata_path(ACCESS_KEYS_FILE).exists() {
+        let mut file = File::open(config::metadata_path(ACCESS_KEYS_FILE))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let keys: HashMap<String, AccessKey> = serde_json::from_str(&contents)?;
+        *ACCESS_KEYS.lock().unwrap() = keys;
+    }
+    Ok(())
+}
+
+fn save_access_keys(keys: &HashMap<String, AccessKey>) -> std::io::Result<()> {
+    let contents = serde_json::to_string_pretty(keys)?;
+    let mut file = File::create(config::metadata_path(ACCESS_KEYS_FILE))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+pub fn add_access_key(key: AccessKey) -> std::io::Result<()> {
+    let mut keys = ACCESS_KEYS.lock().unwrap();
+    keys.insert(key.access_key_id.clone(), key);
+    save_access_keys(&keys)
+}
+
+pub fn secret_key(access_key_id: &str) -> Option<String> {
+    ACCESS_KEYS.lock().unwrap().get(access_key_id).map(|key| key.secret_access_key.clone())
+}
+
+// Requests that need no S3 signature: the version banner, metrics, account
+// endpoints, CORS preflights and browser POST uploads (signed by their policy)
+fn signature_exempt(req: &ServiceRequest) -> bool {
+    let path = req.path();
+    let form_upload = req.method() == Method::POST
+        && path.trim_matches('/').split('/').count() == 1
+        && req.headers().get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("multipart/form-data"));
+    (req.method() == Method::GET && ["/", "/metrics"].contains(&path))
+        || ["/register", "/login", "/access-keys"].contains(&path)
+        || req.method() == Method::OPTIONS
+        || form_upload
+}
+
+// A registered user, or one that access keys were issued to
+fn is_user(username: &str) -> bool {
+    USER_STORE.lock().unwrap().contains_key(username)
+        || ACCESS_KEYS.lock().unwrap().values().any(|key| key.username == username)
+}
+
+// Connections with a client certificate for a known user
+fn has_client_identity(req: &ServiceRequest) -> bool {
+    req.conn_data::<tls::ClientIdentity>().is_some_and(|identity| is_user(&identity.0))
+}
+
+// The user a request is authenticated as: by a client certificate, a
+// presigned URL or an `Authorization` header, in either auth mode.
+// Unsigned requests have none.
+pub fn principal(req: &HttpRequest) -> Option<String> {
+    if let Some(identity) = req.conn_data::<tls::ClientIdentity>().filter(|identity| is_user(&identity.0)) {
+        return Some(identity.0.clone());
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let context = match sigv4::verify_presigned(req, now) {
+        Ok(Some(context)) => context,
+        Ok(None) if req.headers().contains_key(header::AUTHORIZATION) => sigv4::verify_request(req, now).ok()?,
+        _ => return None,
+    };
+    key_owner(&context.access_key_id)
+}
+
+// The user an access key was issued to
+pub fn key_owner(access_key_id: &str) -> Option<String> {
+    ACCESS_KEYS.lock().unwrap().get(access_key_id).map(|key| key.username.clone())
+}
+
+// Whether the request comes from one of the configured `admins`
+pub fn is_admin(req: &HttpRequest) -> bool {
+    principal(req).is_some_and(|username| config::current().admins.contains(&username))
+}
+
+pub fn require_admin(req: &HttpRequest) -> Result<(), Error> {
+    if !is_admin(req) {
+        return Err(actix_web::error::ErrorForbidden("AccessDenied: an admin is required"));
+    }
+    Ok(())
+}
+
+// Middleware for `auth_mode = "sigv4"`: requests must carry a valid
+// `Authorization` header or presigned URL signature, or come over a
+// connection with a client certificate
+pub async fn require_signature(req: ServiceRequest, next: Next<impl MessageBody>)
+    -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    if !signature_exempt(&req) && !has_client_identity(&req) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let verified = match sigv4::verify_presigned(req.request(), now) {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => sigv4::verify_request(req.request(), now).map(|_| ()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = verified {
+            return Ok(req.error_response(e).map_into_right_body());
+        }
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+// Middleware checking bodies against their `x-amz-content-sha256`; bodies
+// sent with a hash are read in full first
+pub async fn verify_payload_hash(mut req: ServiceRequest, next: Next<impl MessageBody>)
+    -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    if sigv4::payload_hash(req.request()).is_some() {
+        let limit = usize::try_from(config::current().max_object_size).unwrap_or(usize::MAX);
+        let mut payload = req.take_payload();
+        let mut body = BytesMut::new();
+        while let Some(chunk) = payload.next().await {
+            body.extend_from_slice(&chunk?);
+            if body.len() > limit {
+                return Ok(req.error_response(object::entity_too_large()).map_into_right_body());
+            }
+        }
+        if let Err(e) = sigv4::check_payload_hash(req.request(), &body) {
+            return Ok(req.error_response(e).map_into_right_body());
+        }
+        req.set_payload(Payload::from(body.freeze()));
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
 }
 
 #[post("/register")]
 pub async fn register(user: web::Json<User>) -> impl Responder {
-    println!("Registering user: {}", user.username);
     let mut store = USER_STORE.lock().unwrap();
     if store.contains_key(&user.username) {
-        println!("User {} already exists", user.username);
+        log::debug!(username = user.username.as_str(); "User already exists");
         return HttpResponse::Conflict().json("User already exists");
     }
 
     match hash(&user.password, DEFAULT_COST) {
         Ok(hashed) => {
             store.insert(user.username.clone(), hashed);
-            println!("Successfully registered user: {}", user.username);
+            log::info!(username = user.username.as_str(); "Registered user");
             HttpResponse::Created().json("User registered successfully")
         }
         Err(_) => HttpResponse::InternalServerError().json("Failed to hash password")
@@ -49,6 +210,55 @@ pub async fn login(user: web::Json<User>) -> impl Responder {
     }
 }
 
+// Issue an access key pair to a registered user
+#[post("/access-keys")]
+pub async fn create_access_key(user: web::Json<User>) -> impl Responder {
+    let authenticated = USER_STORE.lock().unwrap().get(&user.username)
+        .is_some_and(|hashed| verify(&user.password, hashed).unwrap_or(false));
+    if !authenticated {
+        return HttpResponse::Unauthorized().json("Invalid credentials");
+    }
+    let mut secret = [0u8; 30];
+    OsRng.fill_bytes(&mut secret);
+    let id = uuid::Uuid::new_v4().simple().to_string().to_uppercase();
+    let key = AccessKey {
+        access_key_id: format!("R3{}", &id[..18]),
+        secret_access_key: BASE64.encode(secret),
+        username: user.username.clone(),
+    };
+    match add_access_key(key.clone()) {
+        Ok(()) => HttpResponse::Created().json(key),
+        Err(_) => HttpResponse::InternalServerError().json("Failed to store access key"),
+    }
+}
+
+// An access key whose user `as_test_admin` makes an admin
+#[cfg(test)]
+const TEST_ADMIN_KEY: (&str, &str, &str) = ("R3TESTADMINKEY00000", "test-admin-secret", "test-admin");
+
+// Make the test admin an admin until the returned guard is dropped; tests
+// using it are `#[serial(config)]`
+#[cfg(test)]
+pub fn as_test_admin() -> config::TestConfig {
+    let (access_key_id, secret_access_key, username) = TEST_ADMIN_KEY;
+    add_access_key(AccessKey {
+        access_key_id: access_key_id.to_string(),
+        secret_access_key: secret_access_key.to_string(),
+        username: username.to_string(),
+    }).unwrap();
+    config::set_for_test(config::Config { admins: vec![username.to_string()], ..(*config::current()).clone() })
+}
+
+// `path` presigned by the test admin, for `TestRequest::uri` with a
+// `Host: localhost:8080` header
+#[cfg(test)]
+pub fn test_admin_uri(method: &str, path: &str) -> String {
+    let (access_key_id, secret_access_key, _) = TEST_ADMIN_KEY;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let url = sigv4::presign_url(method, &format!("http://localhost:8080{}", path), access_key_id, secret_access_key, 60, now).unwrap();
+    url.trim_start_matches("http://localhost:8080").to_string()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -130,4 +340,96 @@ mod tests {
         
         clear_store();
     }
-} 
\ No newline at end of file
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_create_access_key() {
+        clear_store();
+        let app = test::init_service(
+            actix_web::App::new()
+                .service(register)
+                .service(create_access_key)
+        ).await;
+        let user = User { username: "keyuser".to_string(), password: "keypass".to_string() };
+        let req = test::TestRequest::post().uri("/register").set_json(&user).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 201);
+
+        let req = test::TestRequest::post()
+            .uri("/access-keys")
+            .set_json(&User { username: "keyuser".to_string(), password: "wrong".to_string() })
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+        let req = test::TestRequest::post().uri("/access-keys").set_json(&user).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 201);
+        let key: AccessKey = test::read_body_json(resp).await;
+        assert_eq!(key.access_key_id.len(), 20);
+        assert_eq!(secret_key(&key.access_key_id), Some(key.secret_access_key));
+        clear_store();
+    }
+
+    #[actix_web::test]
+    async fn test_require_signature() {
+        add_access_key(AccessKey {
+            access_key_id: "R3TESTSIGNATUREKEY0".to_string(),
+            secret_access_key: "signature-test-secret".to_string(),
+            username: "signer".to_string(),
+        }).unwrap();
+        let app = test::init_service(
+            actix_web::App::new()
+                .wrap(actix_web::middleware::from_fn(require_signature))
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/{bucket}/{key:.*}", web::to(HttpResponse::Ok))
+        ).await;
+
+        let req = test::TestRequest::get().uri("/").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let req = test::TestRequest::get().uri("/testbucket_auth/a.txt").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let url = sigv4::presign_url(
+            "GET", "http://localhost:8080/testbucket_auth/a.txt", "R3TESTSIGNATUREKEY0", "signature-test-secret", 60, now,
+        ).unwrap();
+        let req = test::TestRequest::get()
+            .uri(url.trim_start_matches("http://localhost:8080"))
+            .insert_header(("Host", "localhost:8080"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let req = test::TestRequest::put()
+            .uri(url.trim_start_matches("http://localhost:8080"))
+            .insert_header(("Host", "localhost:8080"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+    }
+
+    #[actix_web::test]
+    async fn test_verify_payload_hash() {
+        let app = test::init_service(
+            actix_web::App::new()
+                .wrap(actix_web::middleware::from_fn(verify_payload_hash))
+                .route("/{bucket}/{key:.*}", web::put().to(|body: web::Bytes| async move {
+                    HttpResponse::Ok().body(body)
+                }))
+        ).await;
+        let req = test::TestRequest::put()
+            .uri("/testbucket_auth/a.txt")
+            .insert_header(("x-amz-content-sha256", sigv4::sha256_hex(b"signed body")))
+            .set_payload("signed body")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(test::read_body(resp).await, "signed body");
+
+        let req = test::TestRequest::put()
+            .uri("/testbucket_auth/a.txt")
+            .insert_header(("x-amz-content-sha256", sigv4::sha256_hex(b"signed body")))
+            .set_payload("other body")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let body = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&body).unwrap().starts_with("XAmzContentSHA256Mismatch"));
+    }
+}
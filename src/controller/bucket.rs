@@ -1,13 +1,14 @@
-use actix_web::{web, HttpResponse, Responder, post, get, delete};
+use actix_web::{web, HttpResponse, Responder, post};
 use serde::{Deserialize, Serialize};
 use std::fs::{self, File};
 use std::io::{Read, Write};
 use std::sync::Mutex;
 use std::collections::HashMap;
 use std::path::Path;
+use crate::config;
 
 #[derive(Serialize, Deserialize)]
-struct BucketPolicy {
+pub struct BucketPolicy {
     policy: String,
     acl: String,
 }
@@ -19,8 +20,8 @@ lazy_static::lazy_static! {
 const POLICY_FILE: &str = "bucket_policies.json";
 
 pub fn load_policies() -> std::io::Result<()> {
-    if Path::new(POLICY_FILE).exists() {
-        let mut file = File::open(POLICY_FILE)?;
+    if config::metadata_path(POLICY_FILE).exists() {
+        let mut file = File::open(config::metadata_path(POLICY_FILE))?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
         let policies: HashMap<String, BucketPolicy> = serde_json::from_str(&contents)?;
@@ -33,26 +34,96 @@ pub fn load_policies() -> std::io::Result<()> {
 fn save_policies() -> std::io::Result<()> {
     let policies = BUCKET_POLICIES.lock().unwrap();
     let contents = serde_json::to_string_pretty(&*policies)?;
-    let mut file = File::create(POLICY_FILE)?;
+    let mut file = File::create(config::metadata_path(POLICY_FILE))?;
     file.write_all(contents.as_bytes())?;
     Ok(())
 }
 
-#[post("/{bucket}?policy")]
+// Whether `action` matches a policy `Action` entry such as `s3:GetObject` or `s3:*`
+fn action_matches(pattern: &str, action: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => action.starts_with(prefix),
+        None => pattern == action,
+    }
+}
+
+// A string or array of strings in a policy document
+fn policy_strings(value: &serde_json::Value) -> Vec<&str> {
+    match value {
+        serde_json::Value::String(s) => vec![s.as_str()],
+        serde_json::Value::Array(values) => values.iter().filter_map(|v| v.as_str()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Whether every condition of a statement holds for the request context.
+// Only the string operators are understood; statements using any other
+// operator never apply.
+fn conditions_hold(conditions: &serde_json::Value, context: &HashMap<String, String>) -> bool {
+    let Some(conditions) = conditions.as_object() else { return true };
+    conditions.iter().all(|(operator, entries)| {
+        let Some(entries) = entries.as_object() else { return false };
+        entries.iter().all(|(key, expected)| {
+            let expected = policy_strings(expected);
+            let actual = context.get(key).map(String::as_str);
+            match operator.as_str() {
+                "StringEquals" => actual.is_some_and(|a| expected.contains(&a)),
+                "StringNotEquals" => !actual.is_some_and(|a| expected.contains(&a)),
+                _ => false,
+            }
+        })
+    })
+}
+
+// The statements of a bucket's policy; none when it has no (valid) policy
+fn policy_statements(bucket: &str) -> Vec<serde_json::Value> {
+    let policies = BUCKET_POLICIES.lock().unwrap();
+    let Some(policy) = policies.get(bucket) else { return Vec::new() };
+    let Ok(mut document) = serde_json::from_str::<serde_json::Value>(&policy.policy) else { return Vec::new() };
+    match document["Statement"].take() {
+        serde_json::Value::Array(statements) => statements,
+        statement @ serde_json::Value::Object(_) => vec![statement],
+        _ => Vec::new(),
+    }
+}
+
+// Whether a statement with `effect` covers `action` in the request context
+fn statement_applies(statement: &serde_json::Value, effect: &str, action: &str, context: &HashMap<String, String>) -> bool {
+    statement["Effect"] == effect
+        && policy_strings(&statement["Action"]).into_iter().any(|p| action_matches(p, action))
+        && conditions_hold(&statement["Condition"], context)
+}
+
+// Whether the bucket policy explicitly denies `action`. There are no
+// principals to match yet, so only `Deny` statements and their conditions
+// (e.g. `s3:ExistingObjectTag/<key>`) are evaluated; everything else is allowed.
+pub fn policy_denies(bucket: &str, action: &str, context: &HashMap<String, String>) -> bool {
+    policy_statements(bucket).iter().any(|statement| statement_applies(statement, "Deny", action, context))
+}
+
+// Whether the bucket policy allows `action` to anyone: an `Allow` statement
+// whose `Principal` is `"*"` or `{"AWS": "*"}`. Anonymous requests, such as
+// website visits, are only served what this allows.
+pub fn policy_allows_public(bucket: &str, action: &str, context: &HashMap<String, String>) -> bool {
+    policy_statements(bucket).iter().any(|statement| {
+        let principal = &statement["Principal"];
+        (principal == "*" || policy_strings(&principal["AWS"]).contains(&"*"))
+            && statement_applies(statement, "Allow", action, context)
+    })
+}
+
 pub async fn set_bucket_policy(
     path: web::Path<String>,
     policy: web::Json<BucketPolicy>,
 ) -> impl Responder {
     let bucket = path.into_inner();
-    let mut policies = BUCKET_POLICIES.lock().unwrap();
-    policies.insert(bucket, policy.into_inner());
+    BUCKET_POLICIES.lock().unwrap().insert(bucket, policy.into_inner());
     if let Err(e) = save_policies() {
         return HttpResponse::InternalServerError().body(format!("Failed to save policies: {}", e));
     }
     HttpResponse::Ok().finish()
 }
 
-#[get("/{bucket}?policy")]
 pub async fn get_bucket_policy(path: web::Path<String>) -> impl Responder {
     let bucket = path.into_inner();
     let policies = BUCKET_POLICIES.lock().unwrap();
@@ -64,14 +135,12 @@ pub async fn get_bucket_policy(path: web::Path<String>) -> impl Responder {
 
 #[post("/")]
 pub async fn create_bucket() -> impl Responder {
-    println!("Creating bucket");
     match fs::create_dir("buckets") {
         Ok(_) => HttpResponse::Created().finish(),
         Err(e) => HttpResponse::InternalServerError().body(e.to_string())
     }
 }
 
-#[get("/{bucket}")]
 pub async fn read_bucket(path: web::Path<String>) -> impl Responder {
     let bucket = path.into_inner();
     let bucket_path = format!("buckets/{}", bucket);
@@ -82,18 +151,6 @@ pub async fn read_bucket(path: web::Path<String>) -> impl Responder {
     }
 }
 
-#[get("/{bucket}")]
-pub async fn update_bucket(path: web::Path<String>) -> impl Responder {
-    let bucket = path.into_inner();
-    let bucket_path = format!("buckets/{}", bucket);
-    if Path::new(&bucket_path).exists() {
-        HttpResponse::Ok().body("Bucket updated")
-    } else {
-        HttpResponse::NotFound().finish()
-    }
-}
-
-#[delete("/{bucket}")]
 pub async fn delete_bucket(path: web::Path<String>) -> impl Responder {
     let bucket = path.into_inner();
     let bucket_path = format!("buckets/{}", bucket);
@@ -110,6 +167,7 @@ pub async fn delete_bucket(path: web::Path<String>) -> impl Responder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::controller::router;
     use actix_web::{test, App};
     use std::fs;
     use std::path::Path;
@@ -125,7 +183,7 @@ mod tests {
     async fn test_create_and_read_bucket() {
         let bucket_name = "testbucket";
         cleanup_bucket(bucket_name);
-        let app = test::init_service(App::new().service(create_bucket).service(read_bucket)).await;
+        let app = test::init_service(App::new().service(create_bucket).configure(router::configure)).await;
 
         // Create bucket directory manually for this test
         fs::create_dir_all("buckets").unwrap();
@@ -144,18 +202,22 @@ mod tests {
     async fn test_read_nonexistent_bucket() {
         let bucket_name = "nonexistentbucket";
         cleanup_bucket(bucket_name);
-        let app = test::init_service(App::new().service(read_bucket)).await;
+        let app = test::init_service(App::new().configure(router::configure)).await;
         let resp = test::TestRequest::get().uri(&format!("/{}", bucket_name)).send_request(&app).await;
         assert_eq!(resp.status(), 404);
     }
 
     #[actix_rt::test]
-    async fn test_update_bucket() {
+    async fn test_head_bucket() {
         let bucket_name = "updatebucket";
         cleanup_bucket(bucket_name);
         fs::create_dir_all(format!("buckets/{}", bucket_name)).unwrap();
-        let app = test::init_service(App::new().service(update_bucket)).await;
-        let resp = test::TestRequest::get().uri(&format!("/{}", bucket_name)).send_request(&app).await;
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let resp = test::TestRequest::default()
+            .method(actix_web::http::Method::HEAD)
+            .uri(&format!("/{}", bucket_name))
+            .send_request(&app)
+            .await;
         assert!(resp.status().is_success());
         cleanup_bucket(bucket_name);
     }
@@ -165,17 +227,32 @@ mod tests {
         let bucket_name = "deletebucket";
         cleanup_bucket(bucket_name);
         fs::create_dir_all(format!("buckets/{}", bucket_name)).unwrap();
-        let app = test::init_service(App::new().service(delete_bucket)).await;
+        let app = test::init_service(App::new().configure(router::configure)).await;
         let resp = test::TestRequest::delete().uri(&format!("/{}", bucket_name)).send_request(&app).await;
         assert_eq!(resp.status(), 204);
         assert!(!Path::new(&format!("buckets/{}", bucket_name)).exists());
     }
 
+    #[actix_rt::test]
+    async fn test_policy_denies_on_conditions() {
+        let bucket = "testbucket_policy_conditions";
+        BUCKET_POLICIES.lock().unwrap().insert(bucket.to_string(), BucketPolicy {
+            policy: r#"{"Statement": [{"Effect": "Deny", "Action": ["s3:GetObject*"],
+                "Condition": {"StringEquals": {"s3:ExistingObjectTag/classification": "secret"}}}]}"#.to_string(),
+            acl: String::new(),
+        });
+        let secret = HashMap::from([("s3:ExistingObjectTag/classification".to_string(), "secret".to_string())]);
+        assert!(policy_denies(bucket, "s3:GetObject", &secret));
+        assert!(!policy_denies(bucket, "s3:GetObject", &HashMap::new()));
+        assert!(!policy_denies(bucket, "s3:DeleteObject", &secret));
+        BUCKET_POLICIES.lock().unwrap().remove(bucket);
+    }
+
     #[actix_rt::test]
     async fn test_delete_nonexistent_bucket() {
         let bucket_name = "nonexistentbucket2";
         cleanup_bucket(bucket_name);
-        let app = test::init_service(App::new().service(delete_bucket)).await;
+        let app = test::init_service(App::new().configure(router::configure)).await;
         let resp = test::TestRequest::delete().uri(&format!("/{}", bucket_name)).send_request(&app).await;
         assert_eq!(resp.status(), 404);
     }
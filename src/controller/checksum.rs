@@ -0,0 +1,359 @@
+use actix_web::{web, HttpRequest, HttpResponse, Error};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use crate::controller::{bucket, encryption, tagging, versioning};
+use crate::controller::chunked::Trailers;
+use crate::controller::versioning::{VersionInfo, VERSION_STORE};
+use crate::xml;
+
+const DEFAULT_MAX_PARTS: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChecksumAlgorithm {
+    Crc32,
+    Crc32c,
+    Sha1,
+    Sha256,
+}
+
+const ALGORITHMS: [ChecksumAlgorithm; 4] = [
+    ChecksumAlgorithm::Crc32,
+    ChecksumAlgorithm::Crc32c,
+    ChecksumAlgorithm::Sha1,
+    ChecksumAlgorithm::Sha256,
+];
+
+impl ChecksumAlgorithm {
+    pub fn parse(name: &str) -> Option<Self> {
+        ALGORITHMS.into_iter().find(|algorithm| algorithm.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Crc32 => "CRC32",
+            ChecksumAlgorithm::Crc32c => "CRC32C",
+            ChecksumAlgorithm::Sha1 => "SHA1",
+            ChecksumAlgorithm::Sha256 => "SHA256",
+        }
+    }
+
+    pub fn header(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Crc32 => "x-amz-checksum-crc32",
+            ChecksumAlgorithm::Crc32c => "x-amz-checksum-crc32c",
+            ChecksumAlgorithm::Sha1 => "x-amz-checksum-sha1",
+            ChecksumAlgorithm::Sha256 => "x-amz-checksum-sha256",
+        }
+    }
+
+    // XML element holding a checksum, e.g. `ChecksumCRC32`
+    pub fn element(&self) -> String {
+        format!("Checksum{}", self.name())
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ChecksumAlgorithm::Crc32 => crc32fast::hash(data).to_be_bytes().to_vec(),
+            ChecksumAlgorithm::Crc32c => crc32c::crc32c(data).to_be_bytes().to_vec(),
+            ChecksumAlgorithm::Sha1 => Sha1::digest(data).to_vec(),
+            ChecksumAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+        }
+    }
+}
+
+// A checksum of a version or part. Multipart objects carry a composite
+// checksum (checksum of the part checksums, suffixed with the part count)
+// along with the checksum of every part.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub value: String, // base64
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<String>,
+}
+
+impl Checksum {
+    pub fn compute(algorithm: ChecksumAlgorithm, data: &[u8]) -> Self {
+        Checksum { algorithm, value: BASE64.encode(algorithm.digest(data)), parts: Vec::new() }
+    }
+
+    // Composite checksum of an object assembled from `parts`, in part order
+    pub fn composite(algorithm: ChecksumAlgorithm, parts: &[Checksum]) -> Result<Self, Error> {
+        let mut digests = Vec::new();
+        for part in parts {
+            if part.algorithm != algorithm {
+                return Err(actix_web::error::ErrorBadRequest("InvalidPart: part checksum algorithm does not match the upload"));
+            }
+            let digest = BASE64.decode(&part.value)
+                .map_err(|_| actix_web::error::ErrorInternalServerError("Invalid part checksum"))?;
+            digests.extend_from_slice(&digest);
+        }
+        Ok(Checksum {
+            algorithm,
+            value: format!("{}-{}", BASE64.encode(algorithm.digest(&digests)), parts.len()),
+            parts: parts.iter().map(|part| part.value.clone()).collect(),
+        })
+    }
+
+    pub fn checksum_type(&self) -> &'static str {
+        if self.parts.is_empty() { "FULL_OBJECT" } else { "COMPOSITE" }
+    }
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Result<Option<&'a str>, Error> {
+    req.headers().get(name)
+        .map(|value| value.to_str())
+        .transpose()
+        .map_err(|_| actix_web::error::ErrorBadRequest(format!("InvalidRequest: invalid {} header", name)))
+}
+
+// `x-amz-checksum-algorithm` sent when a multipart upload is created
+pub fn algorithm_from_request(req: &HttpRequest) -> Result<Option<ChecksumAlgorithm>, Error> {
+    header(req, "x-amz-checksum-algorithm")?
+        .map(|name| ChecksumAlgorithm::parse(name)
+            .ok_or_else(|| actix_web::error::ErrorBadRequest("InvalidRequest: unsupported checksum algorithm")))
+        .transpose()
+}
+
+fn check_content_md5(req: &HttpRequest, data: &[u8]) -> Result<(), Error> {
+    let Some(content_md5) = header(req, "Content-MD5")? else { return Ok(()) };
+    let expected = BASE64.decode(content_md5.trim()).ok()
+        .filter(|digest| digest.len() == 16)
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("InvalidDigest: Content-MD5 is not a valid MD5 digest"))?;
+    if md5::compute(data).0[..] != expected[..] {
+        return Err(actix_web::error::ErrorBadRequest("BadDigest: Content-MD5 does not match the data"));
+    }
+    Ok(())
+}
+
+// Verify `Content-MD5` and an `x-amz-checksum-*` header or trailer against
+// `data`, and compute the checksum to store: the one sent, the one named by
+// `x-amz-sdk-checksum-algorithm`, or `expected` (the algorithm of a multipart
+// upload)
+pub fn requested(
+    req: &HttpRequest,
+    data: &[u8],
+    trailers: &Trailers,
+    expected: Option<ChecksumAlgorithm>,
+) -> Result<Option<Checksum>, Error> {
+    check_content_md5(req, data)?;
+    let mut sent = None;
+    for algorithm in ALGORITHMS {
+        let trailer = trailers.iter()
+            .find(|(name, _)| name == algorithm.header())
+            .map(|(_, value)| value.as_str());
+        if let Some(value) = header(req, algorithm.header())?.or(trailer) {
+            if sent.replace((algorithm, value)).is_some() {
+                return Err(actix_web::error::ErrorBadRequest("InvalidRequest: expecting a single x-amz-checksum- header"));
+            }
+        }
+    }
+    let sdk_algorithm = header(req, "x-amz-sdk-checksum-algorithm")?
+        .map(|name| ChecksumAlgorithm::parse(name)
+            .ok_or_else(|| actix_web::error::ErrorBadRequest("InvalidRequest: unsupported x-amz-sdk-checksum-algorithm")))
+        .transpose()?;
+    let algorithm = match (sent.map(|(algorithm, _)| algorithm), sdk_algorithm) {
+        (Some(sent), Some(sdk)) if sent != sdk => {
+            return Err(actix_web::error::ErrorBadRequest("InvalidRequest: x-amz-sdk-checksum-algorithm does not match the checksum sent"));
+        }
+        (Some(algorithm), _) | (None, Some(algorithm)) => algorithm,
+        (None, None) => match expected {
+            Some(algorithm) => algorithm,
+            None => return Ok(None),
+        },
+    };
+    if expected.is_some_and(|expected| expected != algorithm) {
+        return Err(actix_web::error::ErrorBadRequest("InvalidRequest: checksum algorithm does not match the upload"));
+    }
+    let checksum = Checksum::compute(algorithm, data);
+    if let Some((_, value)) = sent {
+        if value.trim() != checksum.value {
+            return Err(actix_web::error::ErrorBadRequest(format!(
+                "BadDigest: the {} checksum you specified did not match the calculated checksum",
+                algorithm.name()
+            )));
+        }
+    }
+    Ok(Some(checksum))
+}
+
+// Whether a GET or HEAD asks for checksums with `x-amz-checksum-mode: ENABLED`
+pub fn mode_enabled(req: &HttpRequest) -> bool {
+    req.headers().get("x-amz-checksum-mode")
+        .is_some_and(|mode| mode.as_bytes().eq_ignore_ascii_case(b"ENABLED"))
+}
+
+pub fn response_headers(checksum: Option<&Checksum>) -> Vec<(&'static str, String)> {
+    checksum.map(|checksum| vec![
+        (checksum.algorithm.header(), checksum.value.clone()),
+        ("x-amz-checksum-type", checksum.checksum_type().to_string()),
+    ]).unwrap_or_default()
+}
+
+// Checksum of part `part_number` (1-based) of a version. An object that was
+// not uploaded in parts is its own single part.
+pub fn part_checksum(version: &VersionInfo, part_number: usize) -> Option<Checksum> {
+    let checksum = version.checksum.as_ref()?;
+    if checksum.parts.is_empty() {
+        return (part_number == 1 && version.part_sizes.len() <= 1).then(|| checksum.clone());
+    }
+    checksum.parts.get(part_number.checked_sub(1)?).map(|value| Checksum {
+        algorithm: checksum.algorithm,
+        value: value.clone(),
+        parts: Vec::new(),
+    })
+}
+
+fn render_parts(version: &VersionInfo, req: &HttpRequest) -> Result<String, Error> {
+    let parse = |name: &str, default: usize| -> Result<usize, Error> {
+        header(req, name)?
+            .map(|value| value.parse::<usize>()
+                .map_err(|_| actix_web::error::ErrorBadRequest(format!("InvalidArgument: invalid {}", name))))
+            .transpose()
+            .map(|value| value.unwrap_or(default))
+    };
+    let max_parts = parse("x-amz-max-parts", DEFAULT_MAX_PARTS)?;
+    let marker = parse("x-amz-part-number-marker", 0)?;
+    let total = version.part_sizes.len();
+    let end = marker.saturating_add(max_parts).min(total);
+    let parts: String = (marker.min(total)..end)
+        .map(|i| {
+            let checksum = part_checksum(version, i + 1)
+                .map(|checksum| format!("<{0}>{1}</{0}>", checksum.algorithm.element(), checksum.value))
+                .unwrap_or_default();
+            format!("<Part><PartNumber>{}</PartNumber><Size>{}</Size>{}</Part>", i + 1, version.part_sizes[i], checksum)
+        })
+        .collect();
+    Ok(format!(
+        "<ObjectParts><TotalPartsCount>{}</TotalPartsCount><PartNumberMarker>{}</PartNumberMarker>\
+         <NextPartNumberMarker>{}</NextPartNumberMarker><MaxParts>{}</MaxParts><IsTruncated>{}</IsTruncated>{}</ObjectParts>",
+        total, marker, end, max_parts, end < total, parts
+    ))
+}
+
+// GetObjectAttributes: the attributes listed in `x-amz-object-attributes`
+pub async fn get_object_attributes(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, Error> {
+    let (bucket, key) = path.into_inner();
+    let version = versioning::target_version(&bucket, &key, &query)?;
+    if bucket::policy_denies(&bucket, "s3:GetObjectAttributes", &tagging::condition_values(&version)) {
+        return Err(actix_web::error::ErrorForbidden("AccessDenied"));
+    }
+    let customer_key = encryption::customer_key(&req, encryption::CUSTOMER_KEY_HEADERS)?;
+    encryption::check_customer_key(&version, customer_key.as_ref())?;
+
+    let attributes = header(&req, "x-amz-object-attributes")?
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("InvalidArgument: x-amz-object-attributes is required"))?;
+    let mut body = String::new();
+    for attribute in attributes.split(',').map(str::trim) {
+        match attribute {
+            "ETag" => body.push_str(&format!("<ETag>{}</ETag>", xml::escape(&version.etag))),
+            "Checksum" => if let Some(checksum) = &version.checksum {
+                body.push_str(&format!(
+                    "<Checksum><{0}>{1}</{0}><ChecksumType>{2}</ChecksumType></Checksum>",
+                    checksum.algorithm.element(),
+                    checksum.value,
+                    checksum.checksum_type()
+                ));
+            },
+            "ObjectParts" => if !version.part_sizes.is_empty() {
+                body.push_str(&render_parts(&version, &req)?);
+            },
+            "StorageClass" => body.push_str("<StorageClass>STANDARD</StorageClass>"),
+            "ObjectSize" => body.push_str(&format!("<ObjectSize>{}</ObjectSize>", version.size)),
+            _ => return Err(actix_web::error::ErrorBadRequest(format!("InvalidArgument: unknown attribute {}", attribute))),
+        }
+    }
+
+    let mut response = HttpResponse::Ok();
+    response.content_type("application/xml");
+    if VERSION_STORE.get_status(&bucket).is_versioned() {
+        response.insert_header(("x-amz-version-id", version.version_id.clone()));
+    }
+    Ok(response.body(format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<GetObjectAttributesResponse xmlns="http://s3.amazonaws.com/doc/2006-03-01/">{}</GetObjectAttributesResponse>"#,
+        body
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::router;
+    use actix_web::{test, App};
+    use std::fs;
+
+    #[actix_rt::test]
+    async fn test_checksum_headers_verified() {
+        // Check values for "123456789"
+        let crc32 = BASE64.encode(0xCBF43926u32.to_be_bytes());
+        let crc32c = BASE64.encode(0xE3069283u32.to_be_bytes());
+        let req = test::TestRequest::default()
+            .insert_header(("x-amz-checksum-crc32", crc32.as_str()))
+            .insert_header(("Content-MD5", BASE64.encode(md5::compute(b"123456789").0)))
+            .to_http_request();
+        assert_eq!(requested(&req, b"123456789", &Trailers::new(), None).unwrap().unwrap().value, crc32);
+        assert!(requested(&req, b"12345678", &Trailers::new(), None).is_err());
+
+        let req = test::TestRequest::default()
+            .insert_header(("x-amz-sdk-checksum-algorithm", "CRC32C"))
+            .to_http_request();
+        assert_eq!(requested(&req, b"123456789", &Trailers::new(), None).unwrap().unwrap().value, crc32c);
+        assert!(requested(&req, b"123456789", &Trailers::new(), Some(ChecksumAlgorithm::Sha256)).is_err());
+
+        let req = test::TestRequest::default().to_http_request();
+        assert!(requested(&req, b"data", &Trailers::new(), None).unwrap().is_none());
+        let sha256 = requested(&req, b"data", &Trailers::new(), Some(ChecksumAlgorithm::Sha256)).unwrap().unwrap();
+        assert_eq!(BASE64.decode(sha256.value).unwrap(), Sha256::digest(b"data").to_vec());
+
+        let req = test::TestRequest::default()
+            .insert_header(("Content-MD5", BASE64.encode([0u8; 16])))
+            .to_http_request();
+        assert!(requested(&req, b"data", &Trailers::new(), None).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_get_object_attributes() {
+        let bucket = "testbucket_attributes";
+        let _ = fs::remove_dir_all(bucket);
+        let parts = [
+            Checksum::compute(ChecksumAlgorithm::Sha1, b"part one"),
+            Checksum::compute(ChecksumAlgorithm::Sha1, b"part two"),
+        ];
+        let checksum = Checksum::composite(ChecksumAlgorithm::Sha1, &parts).unwrap();
+        assert!(checksum.value.ends_with("-2"));
+        versioning::create_version_from(b"part onepart two", None, VersionInfo {
+            bucket: bucket.to_string(),
+            key: "doc".to_string(),
+            etag: "abc-2".to_string(),
+            part_sizes: vec![8, 8],
+            checksum: Some(checksum.clone()),
+            ..Default::default()
+        }).unwrap();
+
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/doc?attributes", bucket))
+            .insert_header(("x-amz-object-attributes", "ETag,Checksum,ObjectParts,ObjectSize"))
+            .insert_header(("x-amz-max-parts", "1"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("<ETag>abc-2</ETag>"));
+        assert!(body.contains(&format!("<ChecksumSHA1>{}</ChecksumSHA1><ChecksumType>COMPOSITE</ChecksumType>", checksum.value)));
+        assert!(body.contains("<TotalPartsCount>2</TotalPartsCount>"));
+        assert!(body.contains(&format!("<PartNumber>1</PartNumber><Size>8</Size><ChecksumSHA1>{}</ChecksumSHA1>", parts[0].value)));
+        assert!(body.contains("<IsTruncated>true</IsTruncated>"));
+        assert!(body.contains("<ObjectSize>16</ObjectSize>"));
+        let _ = fs::remove_dir_all(bucket);
+    }
+}
@@ -0,0 +1,392 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpRequest, HttpResponse, Error};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use crate::config;
+use crate::controller::website;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use crate::xml;
+
+const CORS_FILE: &str = "bucket_cors.json";
+const MAX_RULES: usize = 100;
+const METHODS: [&str; 5] = ["GET", "PUT", "POST", "DELETE", "HEAD"];
+
+// One CORSRule of a bucket's CORS configuration. Origins and headers may
+// contain a single `*` wildcard.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CorsRule {
+    #[serde(default)]
+    id: Option<String>,
+    allowed_origins: Vec<String>,
+    allowed_methods: Vec<String>,
+    #[serde(default)]
+    allowed_headers: Vec<String>,
+    #[serde(default)]
+    expose_headers: Vec<String>,
+    #[serde(default)]
+    max_age_seconds: Option<u64>,
+}
+
+lazy_static! {
+    static ref BUCKET_CORS: Mutex<HashMap<String, Vec<CorsRule>>> = Mutex::new(HashMap::new());
+}
+
+pub fn load_cors() -> std::io::Result<()> {
+    if config::metadata_path(CORS_FILE).exists() {
+        let mut file = File::open(config::metadata_path(CORS_FILE))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let configs: HashMap<String, Vec<CorsRule>> = serde_json::from_str(&contents)?;
+        *BUCKET_CORS.lock().unwrap() = configs;
+    }
+    Ok(())
+}
+
+fn save_cors() -> std::io::Result<()> {
+    let configs = BUCKET_CORS.lock().unwrap();
+    let contents = serde_json::to_string_pretty(&*configs)?;
+    let mut file = File::create(config::metadata_path(CORS_FILE))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+// Match `value` against a pattern with at most one `*`
+fn wildcard_match(pattern: &str, value: &str) -> bool {
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) => {
+            value.len() >= prefix.len() + suffix.len() && value.starts_with(prefix) && value.ends_with(suffix)
+        }
+        None => pattern == value,
+    }
+}
+
+impl CorsRule {
+    fn allows(&self, origin: &str, method: &str, headers: &[String]) -> bool {
+        self.allowed_origins.iter().any(|pattern| wildcard_match(pattern, origin))
+            && self.allowed_methods.iter().any(|allowed| allowed == method)
+            && headers.iter().all(|header| {
+                self.allowed_headers.iter().any(|pattern| wildcard_match(&pattern.to_ascii_lowercase(), header))
+            })
+    }
+
+    // Headers granting `origin` access, shared by preflight and actual responses
+    fn headers(&self, origin: &str) -> Vec<(HeaderName, String)> {
+        let mut headers = Vec::new();
+        if self.allowed_origins.iter().any(|pattern| pattern == "*") {
+            headers.push((header::ACCESS_CONTROL_ALLOW_ORIGIN, "*".to_string()));
+        } else {
+            headers.push((header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.to_string()));
+            headers.push((header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true".to_string()));
+        }
+        headers.push((header::ACCESS_CONTROL_ALLOW_METHODS, self.allowed_methods.join(", ")));
+        if !self.expose_headers.is_empty() {
+            headers.push((header::ACCESS_CONTROL_EXPOSE_HEADERS, self.expose_headers.join(", ")));
+        }
+        if let Some(max_age) = self.max_age_seconds {
+            headers.push((header::ACCESS_CONTROL_MAX_AGE, max_age.to_string()));
+        }
+        headers
+    }
+}
+
+// The first rule of `bucket` allowing the request, if any
+fn matching_rule(bucket: &str, origin: &str, method: &str, headers: &[String]) -> Option<CorsRule> {
+    BUCKET_CORS.lock().unwrap().get(bucket)?
+        .iter()
+        .find(|rule| rule.allows(origin, method, headers))
+        .cloned()
+}
+
+fn parse_cors(body: &[u8]) -> Result<Vec<CorsRule>, Error> {
+    let body = std::str::from_utf8(body)
+        .map_err(|_| actix_web::error::ErrorBadRequest("MalformedXML"))?;
+    let values = |rule: &str, tag: &str| -> Vec<String> {
+        xml::elements(rule, tag).into_iter().map(|value| xml::unescape(value.trim())).collect()
+    };
+    let mut rules = Vec::new();
+    for rule in xml::elements(body, "CORSRule") {
+        let rule = CorsRule {
+            id: xml::element_text(rule, "ID").map(xml::unescape),
+            allowed_origins: values(rule, "AllowedOrigin"),
+            allowed_methods: values(rule, "AllowedMethod"),
+            allowed_headers: values(rule, "AllowedHeader"),
+            expose_headers: values(rule, "ExposeHeader"),
+            max_age_seconds: xml::element_text(rule, "MaxAgeSeconds")
+                .map(|age| age.parse().map_err(|_| actix_web::error::ErrorBadRequest("MalformedXML")))
+                .transpose()?,
+        };
+        if rule.allowed_origins.is_empty() || rule.allowed_methods.is_empty() {
+            return Err(actix_web::error::ErrorBadRequest("MalformedXML: CORSRule needs AllowedOrigin and AllowedMethod"));
+        }
+        if let Some(method) = rule.allowed_methods.iter().find(|method| !METHODS.contains(&method.as_str())) {
+            return Err(actix_web::error::ErrorBadRequest(format!(
+                "InvalidRequest: Found unsupported HTTP method in CORS config. Unsupported method is {}", method
+            )));
+        }
+        let patterns = rule.allowed_origins.iter().chain(&rule.allowed_headers);
+        if let Some(pattern) = patterns.into_iter().find(|pattern| pattern.matches('*').count() > 1) {
+            return Err(actix_web::error::ErrorBadRequest(format!(
+                "InvalidRequest: {} can not have more than one wildcard", pattern
+            )));
+        }
+        rules.push(rule);
+    }
+    if rules.is_empty() || rules.len() > MAX_RULES {
+        return Err(actix_web::error::ErrorBadRequest("MalformedXML: between 1 and 100 CORSRules are required"));
+    }
+    Ok(rules)
+}
+
+fn render_rule(rule: &CorsRule) -> String {
+    let list = |tag: &str, values: &[String]| -> String {
+        values.iter().map(|value| format!("<{}>{}</{}>", tag, xml::escape(value), tag)).collect()
+    };
+    format!(
+        "<CORSRule>{}{}{}{}{}{}</CORSRule>",
+        rule.id.as_deref().map(|id| format!("<ID>{}</ID>", xml::escape(id))).unwrap_or_default(),
+        list("AllowedOrigin", &rule.allowed_origins),
+        list("AllowedMethod", &rule.allowed_methods),
+        list("AllowedHeader", &rule.allowed_headers),
+        list("ExposeHeader", &rule.expose_headers),
+        rule.max_age_seconds.map(|age| format!("<MaxAgeSeconds>{}</MaxAgeSeconds>", age)).unwrap_or_default()
+    )
+}
+
+pub async fn put_bucket_cors(path: web::Path<String>, body: web::Bytes) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    let rules = parse_cors(&body)?;
+    BUCKET_CORS.lock().unwrap().insert(bucket, rules);
+    save_cors().map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn get_bucket_cors(path: web::Path<String>) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    let rules = BUCKET_CORS.lock().unwrap().get(&bucket).cloned()
+        .ok_or_else(|| actix_web::error::ErrorNotFound("NoSuchCORSConfiguration"))?;
+    let response = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<CORSConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/">{}</CORSConfiguration>"#,
+        rules.iter().map(render_rule).collect::<String>()
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(response))
+}
+
+pub async fn delete_bucket_cors(path: web::Path<String>) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    BUCKET_CORS.lock().unwrap().remove(&bucket);
+    save_cors().map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Answer a preflight request from the bucket's CORS rules
+pub fn preflight(req: &HttpRequest, bucket: &str) -> Result<HttpResponse, Error> {
+    let value = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let (Some(origin), Some(method)) = (value("Origin"), value("Access-Control-Request-Method")) else {
+        return Err(actix_web::error::ErrorBadRequest(
+            "BadRequest: Insufficient information. Origin request header needed.",
+        ));
+    };
+    let requested_headers: Vec<String> = value("Access-Control-Request-Headers")
+        .into_iter()
+        .flat_map(|headers| headers.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    let rule = matching_rule(bucket, origin, method, &requested_headers)
+        .ok_or_else(|| actix_web::error::ErrorForbidden("AccessForbidden: CORSResponse: This CORS request is not allowed."))?;
+    let mut response = HttpResponse::Ok();
+    for (name, value) in rule.headers(origin) {
+        response.insert_header((name, value));
+    }
+    if !requested_headers.is_empty() {
+        response.insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, requested_headers.join(", ")));
+    }
+    response.insert_header((header::VARY, "Origin, Access-Control-Request-Headers, Access-Control-Request-Method"));
+    Ok(response.finish())
+}
+
+pub async fn preflight_bucket(req: HttpRequest, path: web::Path<String>) -> Result<HttpResponse, Error> {
+    preflight(&req, &path)
+}
+
+pub async fn preflight_object(req: HttpRequest, path: web::Path<(String, String)>) -> Result<HttpResponse, Error> {
+    preflight(&req, &path.0)
+}
+
+// Middleware adding `Access-Control-*` headers to responses for requests
+// with an `Origin` allowed by the bucket's CORS rules
+pub async fn cors_headers(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let bucket = req.path().trim_start_matches('/').split('/').next().unwrap_or_default().to_string();
+    add_headers(req, next, bucket).await
+}
+
+// `cors_headers` for the website listener, where the bucket comes from the
+// Host header
+pub async fn website_cors_headers(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let bucket = website::bucket_from_host(req.connection_info().host());
+    add_headers(req, next, bucket).await
+}
+
+async fn add_headers<B: MessageBody>(req: ServiceRequest, next: Next<B>, bucket: String) -> Result<ServiceResponse<B>, Error> {
+    let origin = req.headers().get("Origin").and_then(|v| v.to_str().ok()).map(str::to_string);
+    let method = req.method().clone();
+    let mut res = next.call(req).await?;
+    let Some(origin) = origin.filter(|_| method != actix_web::http::Method::OPTIONS) else {
+        return Ok(res);
+    };
+    if let Some(rule) = matching_rule(&bucket, &origin, method.as_str(), &[]) {
+        let headers = res.headers_mut();
+        for (name, value) in rule.headers(&origin) {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name, value);
+            }
+        }
+        headers.insert(header::VARY, HeaderValue::from_static("Origin"));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::router;
+    use actix_web::{test, App};
+
+    const CONFIG: &str = "<CORSConfiguration><CORSRule>\
+        <AllowedOrigin>https://*.example.com</AllowedOrigin>\
+        <AllowedMethod>GET</AllowedMethod><AllowedMethod>PUT</AllowedMethod>\
+        <AllowedHeader>x-amz-*</AllowedHeader><AllowedHeader>Content-Type</AllowedHeader>\
+        <ExposeHeader>ETag</ExposeHeader><MaxAgeSeconds>600</MaxAgeSeconds>\
+        </CORSRule></CORSConfiguration>";
+
+    #[actix_rt::test]
+    async fn test_cors_preflight() {
+        let bucket = "testbucket_cors_preflight";
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?cors", bucket))
+            .set_payload(CONFIG)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        let preflight = |origin: &str, method: &str, headers: &str| test::TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri(&format!("/{}/photos/cat.png", bucket))
+            .insert_header(("Origin", origin))
+            .insert_header(("Access-Control-Request-Method", method))
+            .insert_header(("Access-Control-Request-Headers", headers))
+            .to_request();
+        let resp = test::call_service(&app, preflight("https://app.example.com", "PUT", "Content-Type, X-Amz-Date")).await;
+        assert_eq!(resp.status(), 200);
+        let header = |name: &str| resp.headers().get(name).unwrap().to_str().unwrap().to_string();
+        assert_eq!(header("Access-Control-Allow-Origin"), "https://app.example.com");
+        assert_eq!(header("Access-Control-Allow-Methods"), "GET, PUT");
+        assert_eq!(header("Access-Control-Allow-Headers"), "content-type, x-amz-date");
+        assert_eq!(header("Access-Control-Max-Age"), "600");
+
+        // Other origins, methods and headers are refused
+        let resp = test::call_service(&app, preflight("https://evil.com", "PUT", "")).await;
+        assert_eq!(resp.status(), 403);
+        let resp = test::call_service(&app, preflight("https://app.example.com", "DELETE", "")).await;
+        assert_eq!(resp.status(), 403);
+        let resp = test::call_service(&app, preflight("https://app.example.com", "GET", "Authorization")).await;
+        assert_eq!(resp.status(), 403);
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?cors", bucket))
+            .set_payload(CONFIG.replace("GET", "PATCH"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+    }
+
+    #[actix_rt::test]
+    async fn test_cors_headers_on_responses() {
+        let bucket = "testbucket_cors_middleware";
+        let app = test::init_service(App::new()
+            .wrap(actix_web::middleware::from_fn(cors_headers))
+            .configure(router::configure)).await;
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?cors", bucket))
+            .set_payload(CONFIG)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}?cors", bucket))
+            .insert_header(("Origin", "https://app.example.com"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("Access-Control-Allow-Origin").unwrap(), "https://app.example.com");
+        assert_eq!(resp.headers().get("Access-Control-Expose-Headers").unwrap(), "ETag");
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("<AllowedOrigin>https://*.example.com</AllowedOrigin>"));
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}?cors", bucket))
+            .insert_header(("Origin", "https://evil.com"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.headers().get("Access-Control-Allow-Origin").is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_cors_on_website_listener() {
+        let bucket = "testbucket_cors_website";
+        let _ = std::fs::remove_dir_all(bucket);
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        for (subresource, body) in [
+            ("cors", CONFIG),
+            ("website", "<WebsiteConfiguration><IndexDocument><Suffix>index.html</Suffix></IndexDocument></WebsiteConfiguration>"),
+        ] {
+            let req = test::TestRequest::put()
+                .uri(&format!("/{}?{}", bucket, subresource))
+                .set_payload(body)
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 200);
+        }
+        let req = test::TestRequest::put().uri(&format!("/{}/index.html", bucket)).set_payload("home").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 201);
+        let req = test::TestRequest::put().uri(&format!("/{}?policy", bucket)).set_json(website::public_read_policy()).to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        // The bucket comes from the Host header, not the path
+        let site = test::init_service(App::new()
+            .wrap(actix_web::middleware::from_fn(website_cors_headers))
+            .default_service(web::to(website::serve))).await;
+        let host = format!("{}.s3-website.localhost:8081", bucket);
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header(("Host", host.as_str()))
+            .insert_header(("Origin", "https://app.example.com"))
+            .to_request();
+        let resp = test::call_service(&site, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("Access-Control-Allow-Origin").unwrap(), "https://app.example.com");
+
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri("/index.html")
+            .insert_header(("Host", host.as_str()))
+            .insert_header(("Origin", "https://app.example.com"))
+            .insert_header(("Access-Control-Request-Method", "GET"))
+            .to_request();
+        let resp = test::call_service(&site, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("Access-Control-Allow-Methods").unwrap(), "GET, PUT");
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?policy", bucket))
+            .set_json(serde_json::json!({ "policy": "{}", "acl": "" }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let _ = std::fs::remove_dir_all(bucket);
+    }
+}
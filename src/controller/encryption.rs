@@ -0,0 +1,415 @@
+use actix_web::{web, HttpRequest, HttpResponse, Error};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use crate::config;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use crate::controller::versioning::VersionInfo;
+use crate::crypto::{self, CustomerKey, EncryptionMode, Sse};
+use crate::kms;
+use crate::xml;
+
+const ENCRYPTION_FILE: &str = "bucket_encryption.json";
+
+// Header prefixes carrying SSE-C keys for the object written or read, and
+// for the source of a copy
+pub const CUSTOMER_KEY_HEADERS: &str = "x-amz-server-side-encryption-customer-";
+pub const COPY_SOURCE_KEY_HEADERS: &str = "x-amz-copy-source-server-side-encryption-customer-";
+const KMS_KEY_ID_HEADER: &str = "x-amz-server-side-encryption-aws-kms-key-id";
+
+// Default encryption applied to new versions in a bucket
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "StoredEncryption")]
+struct DefaultEncryption {
+    sse_algorithm: String,
+    kms_master_key_id: Option<String>,
+}
+
+// Default encryption as found in the encryption file, which used to hold
+// the bare encryption kind (`"S3"`) before SSE-KMS was supported
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredEncryption {
+    Current {
+        sse_algorithm: String,
+        #[serde(default)]
+        kms_master_key_id: Option<String>,
+    },
+    Legacy(String),
+}
+
+impl From<StoredEncryption> for DefaultEncryption {
+    fn from(stored: StoredEncryption) -> Self {
+        match stored {
+            StoredEncryption::Current { sse_algorithm, kms_master_key_id } => {
+                DefaultEncryption { sse_algorithm, kms_master_key_id }
+            }
+            StoredEncryption::Legacy(kind) => DefaultEncryption {
+                sse_algorithm: if kind == "S3" { "AES256".to_string() } else { kind },
+                kms_master_key_id: None,
+            },
+        }
+    }
+}
+
+lazy_static! {
+    static ref BUCKET_ENCRYPTION: Mutex<HashMap<String, DefaultEncryption>> = Mutex::new(HashMap::new());
+}
+
+pub fn load_encryption() -> std::io::Result<()> {
+    if config::metadata_path(ENCRYPTION_FILE).exists() {
+        let mut file = File::open(config::metadata_path(ENCRYPTION_FILE))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let configs: HashMap<String, DefaultEncryption> = serde_json::from_str(&contents)?;
+        *BUCKET_ENCRYPTION.lock().unwrap() = configs;
+    }
+    Ok(())
+}
+
+fn save_encryption() -> std::io::Result<()> {
+    let configs = BUCKET_ENCRYPTION.lock().unwrap();
+    let contents = serde_json::to_string_pretty(&*configs)?;
+    let mut file = File::create(config::metadata_path(ENCRYPTION_FILE))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+// `aws:kms` without a key ID uses the default KMS key
+fn parse_algorithm(algorithm: &str, kms_key_id: Option<&str>) -> Option<Sse> {
+    match algorithm {
+        "AES256" => Some(Sse::S3),
+        "aws:kms" => Some(Sse::Kms(kms_key_id.unwrap_or(kms::DEFAULT_KEY_ID).to_string())),
+        _ => None,
+    }
+}
+
+// Map key manager and decryption failures to S3 errors
+pub fn key_error(e: std::io::Error) -> Error {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => actix_web::error::ErrorBadRequest(format!("KMS.NotFoundException: {}", e)),
+        std::io::ErrorKind::PermissionDenied => actix_web::error::ErrorForbidden(format!("AccessDenied: {}", e)),
+        _ => actix_web::error::ErrorInternalServerError(e),
+    }
+}
+
+// Make sure a KMS key exists and is enabled before writing with it
+fn check_kms_key(key_id: &str) -> Result<(), Error> {
+    let key = kms::key_manager().describe_key(key_id).map_err(key_error)?;
+    if !key.enabled {
+        return Err(actix_web::error::ErrorBadRequest(format!("KMS.DisabledException: KMS key {} is disabled", key_id)));
+    }
+    Ok(())
+}
+
+// Read an SSE-C key from the `{prefix}algorithm`, `{prefix}key` and
+// `{prefix}key-MD5` headers
+pub fn customer_key(req: &HttpRequest, prefix: &str) -> Result<Option<CustomerKey>, Error> {
+    customer_key_from(|name| header(req, name), prefix)
+}
+
+// A request header as a string; one that isn't valid UTF-8 reads as empty
+// so that it is rejected rather than ignored
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).map(|v| v.to_str().unwrap_or_default())
+}
+
+// The SSE-C key from the `prefix`ed fields that `field` looks up, checked
+// against its MD5
+fn customer_key_from<'a>(field: impl Fn(&str) -> Option<&'a str>, prefix: &str) -> Result<Option<CustomerKey>, Error> {
+    let field = |name: &str| field(&format!("{}{}", prefix, name));
+    let (algorithm, key, key_md5) = match (field("algorithm"), field("key"), field("key-MD5")) {
+        (None, None, None) => return Ok(None),
+        (Some(algorithm), Some(key), Some(key_md5)) => (algorithm, key, key_md5),
+        _ => return Err(actix_web::error::ErrorBadRequest(
+            "InvalidArgument: the customer algorithm, key and key MD5 must be provided together",
+        )),
+    };
+    if algorithm != "AES256" {
+        return Err(actix_web::error::ErrorBadRequest("InvalidEncryptionAlgorithmError"));
+    }
+    let key: [u8; 32] = BASE64.decode(key).ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("InvalidArgument: customer key must be 256 bits"))?;
+    if BASE64.encode(md5::compute(key).0) != key_md5 {
+        return Err(actix_web::error::ErrorBadRequest("InvalidArgument: customer key MD5 does not match"));
+    }
+    Ok(Some(CustomerKey::new(key, key_md5.to_string())))
+}
+
+// The bucket's default encryption, if it has one
+pub fn bucket_default(bucket: &str) -> Option<Sse> {
+    BUCKET_ENCRYPTION.lock().unwrap().get(bucket)
+        .and_then(|default| parse_algorithm(&default.sse_algorithm, default.kms_master_key_id.as_deref()))
+}
+
+// Encryption for a new version: an SSE-C key or `x-amz-server-side-encryption`
+// if sent, otherwise the bucket default
+pub fn requested(req: &HttpRequest, bucket: &str) -> Result<Option<Sse>, Error> {
+    requested_from(|name| header(req, name), bucket)
+}
+
+// Like `requested`, with the headers looked up by `field` (e.g. the fields
+// of a POST form)
+pub fn requested_from<'a>(field: impl Fn(&str) -> Option<&'a str>, bucket: &str) -> Result<Option<Sse>, Error> {
+    let customer_key = customer_key_from(&field, CUSTOMER_KEY_HEADERS)?;
+    let kms_key_id = field(KMS_KEY_ID_HEADER);
+    let sse = match (field("x-amz-server-side-encryption"), customer_key) {
+        (Some(_), Some(_)) => return Err(actix_web::error::ErrorBadRequest(
+            "InvalidArgument: server-side and customer-provided encryption cannot be combined",
+        )),
+        (None, Some(key)) => Some(Sse::Customer(key)),
+        (Some(algorithm), None) => parse_algorithm(algorithm, kms_key_id)
+            .map(Some)
+            .ok_or_else(|| actix_web::error::ErrorBadRequest("InvalidArgument: unsupported x-amz-server-side-encryption"))?,
+        (None, None) => bucket_default(bucket),
+    };
+    match &sse {
+        Some(Sse::Kms(key_id)) => check_kms_key(key_id)?,
+        _ if kms_key_id.is_some() => return Err(actix_web::error::ErrorBadRequest(
+            "InvalidArgument: a KMS key ID requires x-amz-server-side-encryption: aws:kms",
+        )),
+        _ => {}
+    }
+    Ok(sse)
+}
+
+// Make sure a read of `version` supplies the customer key it was written with:
+// 400 when the key is missing or not needed, 403 when it is the wrong one
+pub fn check_customer_key(version: &VersionInfo, key: Option<&CustomerKey>) -> Result<(), Error> {
+    let customer_encrypted = version.encryption.as_ref()
+        .filter(|info| info.mode == EncryptionMode::Customer);
+    match (customer_encrypted, key) {
+        (Some(info), Some(key)) if !crypto::key_matches(info, key) => Err(actix_web::error::ErrorForbidden(
+            "AccessDenied: the customer key does not match the one the object was encrypted with",
+        )),
+        (Some(_), None) => Err(actix_web::error::ErrorBadRequest(
+            "InvalidRequest: the object was stored using a customer-provided key",
+        )),
+        (None, Some(_)) => Err(actix_web::error::ErrorBadRequest(
+            "InvalidRequest: the object was not stored using a customer-provided key",
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Headers acknowledging the encryption requested for a write
+pub fn requested_headers(sse: &Sse) -> Vec<(&'static str, String)> {
+    match sse {
+        Sse::S3 => vec![("x-amz-server-side-encryption", sse.algorithm().to_string())],
+        Sse::Kms(key_id) => vec![
+            ("x-amz-server-side-encryption", sse.algorithm().to_string()),
+            (KMS_KEY_ID_HEADER, key_id.clone()),
+        ],
+        Sse::Customer(key) => vec![
+            ("x-amz-server-side-encryption-customer-algorithm", sse.algorithm().to_string()),
+            ("x-amz-server-side-encryption-customer-key-MD5", key.key_md5.clone()),
+        ],
+    }
+}
+
+// Headers describing how a version is encrypted
+pub fn response_headers(version: &VersionInfo, key: Option<&CustomerKey>) -> Vec<(&'static str, String)> {
+    let mut headers = Vec::new();
+    match version.encryption.as_ref().map(|info| (info.mode, info)) {
+        Some((EncryptionMode::S3, _)) => {
+            headers.push(("x-amz-server-side-encryption", "AES256".to_string()));
+        }
+        Some((EncryptionMode::Kms, info)) => {
+            headers.push(("x-amz-server-side-encryption", "aws:kms".to_string()));
+            if let Some(key_id) = &info.key_id {
+                headers.push((KMS_KEY_ID_HEADER, key_id.clone()));
+            }
+        }
+        Some((EncryptionMode::Customer, _)) => {
+            headers.push(("x-amz-server-side-encryption-customer-algorithm", "AES256".to_string()));
+            if let Some(key) = key {
+                headers.push(("x-amz-server-side-encryption-customer-key-MD5", key.key_md5.clone()));
+            }
+        }
+        None => {}
+    }
+    headers
+}
+
+pub async fn put_bucket_encryption(path: web::Path<String>, body: web::Bytes) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    let body = std::str::from_utf8(&body)
+        .map_err(|_| actix_web::error::ErrorBadRequest("MalformedXML"))?;
+    let default = xml::element_text(body, "ApplyServerSideEncryptionByDefault")
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("MalformedXML"))?;
+    let algorithm = xml::element_text(default, "SSEAlgorithm")
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("MalformedXML"))?;
+    let kms_master_key_id = xml::element_text(default, "KMSMasterKeyID").map(xml::unescape);
+    match parse_algorithm(algorithm, kms_master_key_id.as_deref()) {
+        Some(Sse::Kms(key_id)) => check_kms_key(&key_id)?,
+        Some(_) if kms_master_key_id.is_some() => {
+            return Err(actix_web::error::ErrorBadRequest("InvalidArgument: KMSMasterKeyID requires aws:kms"));
+        }
+        Some(_) => {}
+        None => return Err(actix_web::error::ErrorBadRequest("InvalidArgument: unsupported SSEAlgorithm")),
+    }
+    let default = DefaultEncryption { sse_algorithm: algorithm.to_string(), kms_master_key_id };
+    BUCKET_ENCRYPTION.lock().unwrap().insert(bucket, default);
+    save_encryption().map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn get_bucket_encryption(path: web::Path<String>) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    let default = BUCKET_ENCRYPTION.lock().unwrap().get(&bucket).cloned()
+        .ok_or_else(|| actix_web::error::ErrorNotFound("ServerSideEncryptionConfigurationNotFoundError"))?;
+    let kms_master_key_id = default.kms_master_key_id.as_deref()
+        .map(|key_id| format!("<KMSMasterKeyID>{}</KMSMasterKeyID>", xml::escape(key_id)))
+        .unwrap_or_default();
+    let response = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<ServerSideEncryptionConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><Rule><ApplyServerSideEncryptionByDefault><SSEAlgorithm>{}</SSEAlgorithm>{}</ApplyServerSideEncryptionByDefault></Rule></ServerSideEncryptionConfiguration>"#,
+        xml::escape(&default.sse_algorithm),
+        kms_master_key_id
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(response))
+}
+
+pub async fn delete_bucket_encryption(path: web::Path<String>) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    BUCKET_ENCRYPTION.lock().unwrap().remove(&bucket);
+    save_encryption().map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::router;
+    use crate::controller::versioning;
+    use actix_web::{test, App};
+
+    #[actix_rt::test]
+    async fn test_bucket_default_encryption() {
+        let bucket = "testbucket_default_encryption";
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?encryption", bucket))
+            .set_payload("<ServerSideEncryptionConfiguration><Rule><ApplyServerSideEncryptionByDefault>\
+                <SSEAlgorithm>AES256</SSEAlgorithm></ApplyServerSideEncryptionByDefault></Rule>\
+                </ServerSideEncryptionConfiguration>")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let req = test::TestRequest::default().to_http_request();
+        assert!(matches!(requested(&req, bucket).unwrap(), Some(Sse::S3)));
+
+        let req = test::TestRequest::default()
+            .insert_header(("x-amz-server-side-encryption", "DES"))
+            .to_http_request();
+        assert!(requested(&req, bucket).is_err());
+
+        let req = test::TestRequest::delete().uri(&format!("/{}?encryption", bucket)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 204);
+        let req = test::TestRequest::default().to_http_request();
+        assert!(requested(&req, bucket).unwrap().is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_legacy_encryption_file() {
+        let configs: HashMap<String, DefaultEncryption> = serde_json::from_str(
+            r#"{"old": "S3", "new": {"sse_algorithm": "aws:kms", "kms_master_key_id": "app"}}"#,
+        ).unwrap();
+        assert_eq!(configs["old"].sse_algorithm, "AES256");
+        assert!(configs["old"].kms_master_key_id.is_none());
+        assert_eq!(configs["new"].kms_master_key_id.as_deref(), Some("app"));
+    }
+
+    #[actix_rt::test]
+    async fn test_head_customer_encrypted_object() {
+        let bucket = "testbucket_sse_c_head";
+        let _ = std::fs::remove_dir_all(bucket);
+        let key = [9u8; 32];
+        let key_headers = [
+            ("x-amz-server-side-encryption-customer-algorithm", "AES256".to_string()),
+            ("x-amz-server-side-encryption-customer-key", BASE64.encode(key)),
+            ("x-amz-server-side-encryption-customer-key-MD5", BASE64.encode(md5::compute(key).0)),
+        ];
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let mut req = test::TestRequest::put().uri(&format!("/{}/secret.txt", bucket)).set_payload("customer data");
+        for header in key_headers.clone() {
+            req = req.insert_header(header);
+        }
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), 201);
+
+        // Without the key HEAD is refused, with it the metadata is returned
+        let req = test::TestRequest::default().method(actix_web::http::Method::HEAD)
+            .uri(&format!("/{}/secret.txt", bucket)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+        let mut req = test::TestRequest::default().method(actix_web::http::Method::HEAD)
+            .uri(&format!("/{}/secret.txt", bucket));
+        for header in key_headers.clone() {
+            req = req.insert_header(header);
+        }
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("x-amz-server-side-encryption-customer-algorithm").unwrap(), "AES256");
+        assert_eq!(resp.headers().get("x-amz-server-side-encryption-customer-key-MD5").unwrap(), key_headers[2].1.as_str());
+        let _ = std::fs::remove_dir_all(bucket);
+    }
+
+    #[actix_rt::test]
+    async fn test_customer_key_headers() {
+        let key = [42u8; 32];
+        let req = test::TestRequest::default()
+            .insert_header(("x-amz-server-side-encryption-customer-algorithm", "AES256"))
+            .insert_header(("x-amz-server-side-encryption-customer-key", BASE64.encode(key)))
+            .insert_header(("x-amz-server-side-encryption-customer-key-MD5", BASE64.encode(md5::compute(key).0)))
+            .to_http_request();
+        assert!(matches!(requested(&req, "testbucket_sse_c").unwrap(), Some(Sse::Customer(_))));
+
+        let req = test::TestRequest::default()
+            .insert_header(("x-amz-server-side-encryption-customer-algorithm", "AES256"))
+            .insert_header(("x-amz-server-side-encryption-customer-key", BASE64.encode(key)))
+            .insert_header(("x-amz-server-side-encryption-customer-key-MD5", BASE64.encode([0u8; 16])))
+            .to_http_request();
+        assert!(customer_key(&req, CUSTOMER_KEY_HEADERS).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_kms_key_recorded_per_version() {
+        let bucket = "testbucket_sse_kms";
+        let key_id = "testkey_sse_kms";
+        let _ = std::fs::remove_dir_all(bucket);
+        let _ = kms::key_manager().create_key(key_id);
+        kms::key_manager().set_key_enabled(key_id, true).unwrap();
+
+        let req = test::TestRequest::default()
+            .insert_header(("x-amz-server-side-encryption", "aws:kms"))
+            .insert_header((KMS_KEY_ID_HEADER, key_id))
+            .to_http_request();
+        let sse = requested(&req, bucket).unwrap().unwrap();
+        let version = versioning::create_version_from(b"kms data", Some(&sse), VersionInfo {
+            bucket: bucket.to_string(),
+            key: "doc".to_string(),
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(version.encryption.as_ref().unwrap().key_id.as_deref(), Some(key_id));
+        assert!(response_headers(&version, None).contains(&(KMS_KEY_ID_HEADER, key_id.to_string())));
+
+        // Rotation keeps old data readable; disabling the key blocks reads and writes
+        kms::key_manager().rotate_key(key_id).unwrap();
+        assert_eq!(versioning::read_version_with(&version, None).unwrap(), b"kms data");
+        kms::key_manager().set_key_enabled(key_id, false).unwrap();
+        assert!(versioning::read_version_with(&version, None).is_err());
+        assert!(requested(&req, bucket).is_err());
+        kms::key_manager().set_key_enabled(key_id, true).unwrap();
+
+        let req = test::TestRequest::default()
+            .insert_header((KMS_KEY_ID_HEADER, key_id))
+            .to_http_request();
+        assert!(requested(&req, bucket).is_err());
+        let _ = std::fs::remove_dir_all(bucket);
+    }
+}
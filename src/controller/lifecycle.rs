@@ -0,0 +1,480 @@
+use actix_web::{web, HttpResponse, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use crate::config;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+use crate::controller::multipart;
+use crate::controller::versioning::{self, VersionInfo, VERSION_STORE};
+use crate::xml;
+
+const LIFECYCLE_FILE: &str = "bucket_lifecycle.json";
+const MAX_RULES: usize = 1000;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// Which objects a rule applies to. All conditions must hold.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LifecycleFilter {
+    pub prefix: String,
+    pub tags: Vec<(String, String)>,
+    pub size_greater_than: Option<u64>,
+    pub size_less_than: Option<u64>,
+}
+
+impl LifecycleFilter {
+    fn matches_key(&self, key: &str) -> bool {
+        key.starts_with(&self.prefix)
+    }
+
+    fn matches(&self, version: &VersionInfo) -> bool {
+        if !self.matches_key(&version.key) {
+            return false;
+        }
+        // Delete markers carry neither tags nor data
+        if version.is_delete_marker {
+            return self.tags.is_empty();
+        }
+        self.tags.iter().all(|(k, v)| version.tags.get(k) == Some(v))
+            && self.size_greater_than.is_none_or(|min| version.size > min)
+            && self.size_less_than.is_none_or(|max| version.size < max)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LifecycleRule {
+    pub id: String,
+    pub enabled: bool,
+    pub filter: LifecycleFilter,
+    pub expiration_days: Option<u64>,
+    pub expiration_date: Option<u64>,
+    pub expired_object_delete_marker: bool,
+    pub noncurrent_days: Option<u64>,
+    pub newer_noncurrent_versions: Option<usize>,
+    pub abort_incomplete_upload_days: Option<u64>,
+}
+
+impl LifecycleRule {
+    // Whether the current version of an object has expired at `now`
+    fn current_expired(&self, version: &VersionInfo, now: u64) -> bool {
+        self.expiration_days.is_some_and(|days| now >= version.last_modified + days * SECONDS_PER_DAY)
+            || self.expiration_date.is_some_and(|date| now >= date)
+    }
+
+    // Whether a noncurrent version has expired at `now`. `noncurrent_since` is
+    // when it was superseded and `newer` the number of noncurrent versions
+    // newer than this one.
+    fn noncurrent_expired(&self, noncurrent_since: u64, newer: usize, now: u64) -> bool {
+        self.noncurrent_days.is_some_and(|days| now >= noncurrent_since + days * SECONDS_PER_DAY)
+            && self.newer_noncurrent_versions.is_none_or(|keep| newer >= keep)
+    }
+}
+
+lazy_static! {
+    static ref BUCKET_LIFECYCLE: Mutex<HashMap<String, Vec<LifecycleRule>>> = Mutex::new(HashMap::new());
+}
+
+pub fn load_lifecycle() -> std::io::Result<()> {
+    if config::metadata_path(LIFECYCLE_FILE).exists() {
+        let mut file = File::open(config::metadata_path(LIFECYCLE_FILE))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let configs: HashMap<String, Vec<LifecycleRule>> = serde_json::from_str(&contents)?;
+        *BUCKET_LIFECYCLE.lock().unwrap() = configs;
+    }
+    Ok(())
+}
+
+fn save_lifecycle() -> std::io::Result<()> {
+    let configs = BUCKET_LIFECYCLE.lock().unwrap();
+    let contents = serde_json::to_string_pretty(&*configs)?;
+    let mut file = File::create(config::metadata_path(LIFECYCLE_FILE))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+fn parse_number<T: std::str::FromStr>(body: &str, tag: &str) -> Result<Option<T>, String> {
+    xml::element_text(body, tag)
+        .map(|value| value.parse::<T>().map_err(|_| format!("Invalid {}", tag)))
+        .transpose()
+}
+
+fn parse_filter(rule: &str) -> Result<LifecycleFilter, String> {
+    let Some(filter) = xml::element_text(rule, "Filter") else {
+        // Rules written before filters existed put the prefix on the rule itself
+        return Ok(LifecycleFilter {
+            prefix: xml::element_text(rule, "Prefix").map(xml::unescape).unwrap_or_default(),
+            ..Default::default()
+        });
+    };
+    let conditions = xml::element_text(filter, "And").unwrap_or(filter);
+    let tags = xml::elements(conditions, "Tag").into_iter()
+        .map(|tag| match (xml::element_text(tag, "Key"), xml::element_text(tag, "Value")) {
+            (Some(key), Some(value)) => Ok((xml::unescape(key), xml::unescape(value))),
+            _ => Err("Tag requires a Key and a Value".to_string()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(LifecycleFilter {
+        prefix: xml::element_text(conditions, "Prefix").map(xml::unescape).unwrap_or_default(),
+        tags,
+        size_greater_than: parse_number(conditions, "ObjectSizeGreaterThan")?,
+        size_less_than: parse_number(conditions, "ObjectSizeLessThan")?,
+    })
+}
+
+// Parse a `LifecycleConfiguration` document
+pub fn parse_lifecycle(body: &str) -> Result<Vec<LifecycleRule>, String> {
+    let rules = xml::elements(body, "Rule");
+    if rules.is_empty() || rules.len() > MAX_RULES {
+        return Err(format!("A configuration must have between 1 and {} rules", MAX_RULES));
+    }
+    rules.into_iter().map(|rule| {
+        let enabled = match xml::element_text(rule, "Status") {
+            Some("Enabled") => true,
+            Some("Disabled") => false,
+            _ => return Err("Status must be Enabled or Disabled".to_string()),
+        };
+        let mut parsed = LifecycleRule {
+            id: xml::element_text(rule, "ID").map(xml::unescape).unwrap_or_default(),
+            enabled,
+            filter: parse_filter(rule)?,
+            ..Default::default()
+        };
+        if let Some(expiration) = xml::element_text(rule, "Expiration") {
+            parsed.expiration_days = parse_number(expiration, "Days")?;
+            parsed.expiration_date = xml::element_text(expiration, "Date")
+                .map(|date| xml::parse_timestamp(date).ok_or("Invalid Date"))
+                .transpose()?;
+            parsed.expired_object_delete_marker =
+                xml::element_text(expiration, "ExpiredObjectDeleteMarker") == Some("true");
+        }
+        if let Some(noncurrent) = xml::element_text(rule, "NoncurrentVersionExpiration") {
+            parsed.noncurrent_days = parse_number(noncurrent, "NoncurrentDays")?;
+            parsed.newer_noncurrent_versions = parse_number(noncurrent, "NewerNoncurrentVersions")?;
+            if parsed.noncurrent_days.is_none() {
+                return Err("NoncurrentVersionExpiration requires NoncurrentDays".to_string());
+            }
+        }
+        if let Some(abort) = xml::element_text(rule, "AbortIncompleteMultipartUpload") {
+            parsed.abort_incomplete_upload_days = parse_number(abort, "DaysAfterInitiation")?;
+            if !parsed.filter.tags.is_empty() {
+                return Err("AbortIncompleteMultipartUpload cannot be used with tag filters".to_string());
+            }
+        }
+        if parsed.expiration_days.is_none()
+            && parsed.expiration_date.is_none()
+            && !parsed.expired_object_delete_marker
+            && parsed.noncurrent_days.is_none()
+            && parsed.abort_incomplete_upload_days.is_none()
+        {
+            return Err("A rule must specify at least one action".to_string());
+        }
+        Ok(parsed)
+    }).collect()
+}
+
+fn render_filter(filter: &LifecycleFilter) -> String {
+    let mut conditions = Vec::new();
+    if !filter.prefix.is_empty() || (filter.tags.is_empty()
+        && filter.size_greater_than.is_none() && filter.size_less_than.is_none()) {
+        conditions.push(format!("<Prefix>{}</Prefix>", xml::escape(&filter.prefix)));
+    }
+    for (key, value) in &filter.tags {
+        conditions.push(format!(
+            "<Tag><Key>{}</Key><Value>{}</Value></Tag>",
+            xml::escape(key),
+            xml::escape(value)
+        ));
+    }
+    if let Some(size) = filter.size_greater_than {
+        conditions.push(format!("<ObjectSizeGreaterThan>{}</ObjectSizeGreaterThan>", size));
+    }
+    if let Some(size) = filter.size_less_than {
+        conditions.push(format!("<ObjectSizeLessThan>{}</ObjectSizeLessThan>", size));
+    }
+    if conditions.len() > 1 {
+        format!("<Filter><And>{}</And></Filter>", conditions.concat())
+    } else {
+        format!("<Filter>{}</Filter>", conditions.concat())
+    }
+}
+
+fn render_rule(rule: &LifecycleRule) -> String {
+    let mut xml_rule = format!(
+        "\n    <Rule>\n        <ID>{}</ID>\n        {}\n        <Status>{}</Status>",
+        xml::escape(&rule.id),
+        render_filter(&rule.filter),
+        if rule.enabled { "Enabled" } else { "Disabled" }
+    );
+    if rule.expiration_days.is_some() || rule.expiration_date.is_some() || rule.expired_object_delete_marker {
+        let mut expiration = String::new();
+        if let Some(days) = rule.expiration_days {
+            expiration.push_str(&format!("<Days>{}</Days>", days));
+        }
+        if let Some(date) = rule.expiration_date {
+            expiration.push_str(&format!("<Date>{}</Date>", xml::timestamp(date)));
+        }
+        if rule.expired_object_delete_marker {
+            expiration.push_str("<ExpiredObjectDeleteMarker>true</ExpiredObjectDeleteMarker>");
+        }
+        xml_rule.push_str(&format!("\n        <Expiration>{}</Expiration>", expiration));
+    }
+    if let Some(days) = rule.noncurrent_days {
+        let newer = rule.newer_noncurrent_versions
+            .map(|n| format!("<NewerNoncurrentVersions>{}</NewerNoncurrentVersions>", n))
+            .unwrap_or_default();
+        xml_rule.push_str(&format!(
+            "\n        <NoncurrentVersionExpiration><NoncurrentDays>{}</NoncurrentDays>{}</NoncurrentVersionExpiration>",
+            days, newer
+        ));
+    }
+    if let Some(days) = rule.abort_incomplete_upload_days {
+        xml_rule.push_str(&format!(
+            "\n        <AbortIncompleteMultipartUpload><DaysAfterInitiation>{}</DaysAfterInitiation></AbortIncompleteMultipartUpload>",
+            days
+        ));
+    }
+    xml_rule.push_str("\n    </Rule>");
+    xml_rule
+}
+
+pub async fn put_bucket_lifecycle(
+    path: web::Path<String>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    let body = std::str::from_utf8(&body)
+        .map_err(|_| actix_web::error::ErrorBadRequest("MalformedXML"))?;
+    let rules = parse_lifecycle(body)
+        .map_err(|e| actix_web::error::ErrorBadRequest(format!("MalformedXML: {}", e)))?;
+    BUCKET_LIFECYCLE.lock().unwrap().insert(bucket, rules);
+    if let Err(e) = save_lifecycle() {
+        return Ok(HttpResponse::InternalServerError().body(format!("Failed to save lifecycle: {}", e)));
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn get_bucket_lifecycle(path: web::Path<String>) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    let rules = BUCKET_LIFECYCLE.lock().unwrap().get(&bucket).cloned()
+        .ok_or_else(|| actix_web::error::ErrorNotFound("NoSuchLifecycleConfiguration"))?;
+    let response = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<LifecycleConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/">{}
+</LifecycleConfiguration>"#,
+        rules.iter().map(render_rule).collect::<String>()
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(response))
+}
+
+pub async fn delete_bucket_lifecycle(path: web::Path<String>) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    BUCKET_LIFECYCLE.lock().unwrap().remove(&bucket);
+    if let Err(e) = save_lifecycle() {
+        return Ok(HttpResponse::InternalServerError().body(format!("Failed to save lifecycle: {}", e)));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// What a lifecycle run did
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct LifecycleReport {
+    pub expired: usize,
+    pub noncurrent_removed: usize,
+    pub delete_markers_removed: usize,
+    pub uploads_aborted: usize,
+    pub skipped: usize, // versions that could not be removed (e.g. pinned)
+}
+
+// Apply every bucket's lifecycle rules as of `now`
+pub fn run_lifecycle(now: u64) -> LifecycleReport {
+    let configs: Vec<(String, Vec<LifecycleRule>)> = BUCKET_LIFECYCLE.lock().unwrap()
+        .iter()
+        .map(|(bucket, rules)| (bucket.clone(), rules.iter().filter(|r| r.enabled).cloned().collect()))
+        .collect();
+    let mut report = LifecycleReport::default();
+    for (bucket, rules) in configs {
+        apply_rules(&bucket, &rules, now, &mut report);
+    }
+    report
+}
+
+fn apply_rules(bucket: &str, rules: &[LifecycleRule], now: u64, report: &mut LifecycleReport) {
+    // Versions come grouped by key, newest first
+    let mut by_key: Vec<Vec<VersionInfo>> = Vec::new();
+    for version in VERSION_STORE.list_bucket_versions(bucket) {
+        match by_key.last_mut() {
+            Some(group) if group[0].key == version.key => group.push(version),
+            _ => by_key.push(vec![version]),
+        }
+    }
+
+    for versions in by_key {
+        let key = versions[0].key.clone();
+
+        // Expire the current version
+        let current = &versions[0];
+        if !current.is_delete_marker
+            && rules.iter().any(|r| r.filter.matches(current) && r.current_expired(current, now))
+        {
+            match versioning::delete_object(bucket, &key) {
+                Ok(_) => report.expired += 1,
+                Err(_) => report.skipped += 1,
+            }
+        }
+
+        // Expire noncurrent versions; each became noncurrent when its successor was written
+        for (index, version) in versions.iter().enumerate().skip(1) {
+            let noncurrent_since = versions[index - 1].last_modified;
+            let expired = rules.iter().any(|r| {
+                r.filter.matches(version) && r.noncurrent_expired(noncurrent_since, index - 1, now)
+            });
+            if expired {
+                match versioning::remove_version(bucket, &key, &version.version_id, false) {
+                    Ok(_) => report.noncurrent_removed += 1,
+                    Err(_) => report.skipped += 1,
+                }
+            }
+        }
+
+        // Remove delete markers that no longer hide anything
+        let remaining = VERSION_STORE.get_versions(bucket, &key);
+        if let [marker] = remaining.as_slice() {
+            if marker.is_delete_marker
+                && rules.iter().any(|r| r.expired_object_delete_marker && r.filter.matches(marker))
+            {
+                match versioning::remove_version(bucket, &key, &marker.version_id, false) {
+                    Ok(_) => report.delete_markers_removed += 1,
+                    Err(_) => report.skipped += 1,
+                }
+            }
+        }
+    }
+
+    for upload in multipart::incomplete_uploads(bucket) {
+        let expired = rules.iter().any(|r| {
+            r.filter.matches_key(&upload.key)
+                && r.abort_incomplete_upload_days
+                    .is_some_and(|days| now >= upload.created_at + days * SECONDS_PER_DAY)
+        });
+        if expired {
+            match multipart::abort_upload(bucket, &upload.upload_id) {
+                Ok(_) => report.uploads_aborted += 1,
+                Err(_) => report.skipped += 1,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::router;
+    use crate::controller::versioning::{add_version_at, VersioningStatus};
+    use actix_web::{test, App};
+    use std::fs;
+
+    const CONFIG: &str = r#"<LifecycleConfiguration>
+        <Rule>
+            <ID>logs</ID>
+            <Filter><And><Prefix>logs/</Prefix><ObjectSizeGreaterThan>1</ObjectSizeGreaterThan></And></Filter>
+            <Status>Enabled</Status>
+            <Expiration><Days>1</Days></Expiration>
+            <NoncurrentVersionExpiration>
+                <NoncurrentDays>1</NoncurrentDays>
+                <NewerNoncurrentVersions>1</NewerNoncurrentVersions>
+            </NoncurrentVersionExpiration>
+        </Rule>
+        <Rule>
+            <ID>markers</ID>
+            <Filter><Prefix>old/</Prefix></Filter>
+            <Status>Enabled</Status>
+            <Expiration><ExpiredObjectDeleteMarker>true</ExpiredObjectDeleteMarker></Expiration>
+            <AbortIncompleteMultipartUpload><DaysAfterInitiation>7</DaysAfterInitiation></AbortIncompleteMultipartUpload>
+        </Rule>
+    </LifecycleConfiguration>"#;
+
+    #[actix_rt::test]
+    async fn test_put_and_get_lifecycle() {
+        let bucket = "testbucket_lifecycle_api";
+        let app = test::init_service(App::new().configure(router::configure))
+            .await;
+
+        let req = test::TestRequest::put().uri(&format!("/{}?lifecycle", bucket)).set_payload(CONFIG).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        let req = test::TestRequest::get().uri(&format!("/{}?lifecycle", bucket)).to_request();
+        let body = test::read_body(test::call_service(&app, req).await).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        // What we render parses back into the same rules
+        assert_eq!(parse_lifecycle(body).unwrap(), parse_lifecycle(CONFIG).unwrap());
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?lifecycle", bucket))
+            .set_payload("<LifecycleConfiguration><Rule><Status>Enabled</Status></Rule></LifecycleConfiguration>")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+        BUCKET_LIFECYCLE.lock().unwrap().remove(bucket);
+    }
+
+    #[actix_rt::test]
+    async fn test_escaped_prefix_and_id() {
+        let bucket = "testbucket_lifecycle_escaped";
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?lifecycle", bucket))
+            .set_payload(concat!(
+                "<LifecycleConfiguration><Rule><ID>a&amp;b</ID><Filter><Prefix>a&amp;b/</Prefix></Filter>",
+                "<Status>Enabled</Status><Expiration><Days>1</Days></Expiration></Rule></LifecycleConfiguration>",
+            ))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let rule = BUCKET_LIFECYCLE.lock().unwrap()[bucket][0].clone();
+        assert_eq!(rule.id, "a&b");
+        assert!(rule.filter.matches_key("a&b/x"));
+        assert!(!rule.filter.matches_key("a&amp;b/x"));
+
+        let req = test::TestRequest::get().uri(&format!("/{}?lifecycle", bucket)).to_request();
+        let body = test::read_body(test::call_service(&app, req).await).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("<ID>a&amp;b</ID>"));
+        assert!(body.contains("<Prefix>a&amp;b/</Prefix>"));
+        BUCKET_LIFECYCLE.lock().unwrap().remove(bucket);
+    }
+
+    #[actix_rt::test]
+    async fn test_run_lifecycle() {
+        let bucket = "testbucket_lifecycle_run";
+        let _ = fs::remove_dir_all(bucket);
+        VERSION_STORE.set_status(bucket, VersioningStatus::Enabled);
+        let day = SECONDS_PER_DAY;
+        // Three versions of a log; the oldest noncurrent one goes, one noncurrent is kept
+        add_version_at(bucket, "logs/a", "a1", Some(b"one"), 0);
+        add_version_at(bucket, "logs/a", "a2", Some(b"two"), day);
+        add_version_at(bucket, "logs/a", "a3", Some(b"three"), 2 * day);
+        // Too small to match the size filter
+        add_version_at(bucket, "logs/tiny", "t1", Some(b"t"), 0);
+        // A lone delete marker
+        add_version_at(bucket, "old/gone", "g1", None, 0);
+        BUCKET_LIFECYCLE.lock().unwrap().insert(bucket.to_string(), parse_lifecycle(CONFIG).unwrap());
+
+        let report = run_lifecycle(10 * day);
+        BUCKET_LIFECYCLE.lock().unwrap().remove(bucket);
+        assert_eq!(report.expired, 1);
+        assert_eq!(report.noncurrent_removed, 1);
+        assert_eq!(report.delete_markers_removed, 1);
+
+        let versions: Vec<String> = VERSION_STORE.get_versions(bucket, "logs/a").into_iter()
+            .map(|v| v.version_id)
+            .collect();
+        assert_eq!(versions.len(), 3);
+        assert_eq!(&versions[..2], ["a2", "a3"]);
+        assert!(VERSION_STORE.get_latest_version(bucket, "logs/a").unwrap().is_delete_marker);
+        assert_eq!(VERSION_STORE.get_versions(bucket, "logs/tiny").len(), 1);
+        assert!(VERSION_STORE.get_versions(bucket, "old/gone").is_empty());
+        let _ = fs::remove_dir_all(bucket);
+    }
+}
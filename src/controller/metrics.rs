@@ -0,0 +1,286 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::{get, Error, HttpResponse};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+use lazy_static::lazy_static;
+use crate::controller::lifecycle::LifecycleReport;
+use crate::controller::{multipart, router};
+use crate::controller::versioning::VERSION_STORE;
+
+// Prometheus metrics: request counters and latency histograms recorded by
+// middleware, background task outcomes, and gauges read from the stores
+// when scraped
+
+// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct RequestStats {
+    count: u64,
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    seconds: f64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+#[derive(Default)]
+struct Metrics {
+    requests: BTreeMap<(String, u16), RequestStats>, // (operation, status) -> stats
+    tasks: BTreeMap<(&'static str, &'static str), u64>, // (task, outcome) -> runs
+    lifecycle_actions: BTreeMap<&'static str, u64>,
+    blobs_collected: u64,
+}
+
+lazy_static! {
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::default());
+}
+
+fn verb(method: &Method) -> Option<&'static str> {
+    match *method {
+        Method::GET => Some("Get"),
+        Method::HEAD => Some("Head"),
+        Method::PUT => Some("Put"),
+        Method::POST => Some("Post"),
+        Method::DELETE => Some("Delete"),
+        _ => None,
+    }
+}
+
+// `legal-hold` -> `LegalHold`, `policyStatus` -> `PolicyStatus`
+fn camel_case(name: &str) -> String {
+    name.split('-')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next().map(|first| first.to_ascii_uppercase().to_string() + chars.as_str()).unwrap_or_default()
+        })
+        .collect()
+}
+
+// The S3 operation a request performs, named as in the S3 API
+// (`GetObject`, `PutBucketCors`); non-S3 endpoints are grouped by area
+fn operation(method: &Method, path: &str, query: &str, copy: bool) -> String {
+    let path = path.trim_start_matches('/');
+    match path.split('/').next().unwrap_or_default() {
+        "" if *method == Method::POST => return "CreateBucket".to_string(),
+        "" => return "Version".to_string(),
+        "admin" => return "Admin".to_string(),
+        "metrics" => return "Metrics".to_string(),
+        "register" | "login" | "access-keys" => return "Account".to_string(),
+        _ => {}
+    }
+    if *method == Method::OPTIONS {
+        return "PreflightRequest".to_string();
+    }
+    let Some(verb) = verb(method) else {
+        return "Other".to_string();
+    };
+    let object = path.split_once('/').is_some_and(|(_, key)| !key.is_empty());
+    let subresource = router::SUBRESOURCES.iter().find(|name| router::query_has_key(query, name));
+    let name = match (verb, object, subresource.copied()) {
+        (_, false, Some("versions")) => "ListObjectVersions",
+        (verb, false, Some("object-lock")) => return format!("{}ObjectLockConfiguration", verb),
+        (_, true, Some("uploads")) => "CreateMultipartUpload",
+        ("Get", true, Some("uploadId")) => "ListParts",
+        ("Put", true, Some("uploadId")) => "UploadPart",
+        ("Post", true, Some("uploadId")) => "CompleteMultipartUpload",
+        ("Delete", true, Some("uploadId")) => "AbortMultipartUpload",
+        (verb, object, Some(subresource)) => {
+            return format!("{}{}{}", verb, if object { "Object" } else { "Bucket" }, camel_case(subresource));
+        }
+        ("Get", false, None) => "ListObjects",
+        ("Post", false, None) => "PostObject",
+        ("Put", true, None) if copy => "CopyObject",
+        (verb, object, None) => return format!("{}{}", verb, if object { "Object" } else { "Bucket" }),
+    };
+    name.to_string()
+}
+
+pub fn request_operation(req: &ServiceRequest) -> String {
+    operation(req.method(), req.path(), req.query_string(), req.headers().contains_key("x-amz-copy-source"))
+}
+
+fn record_request(operation: String, status: u16, seconds: f64, bytes_in: u64, bytes_out: u64) {
+    let mut metrics = METRICS.lock().unwrap();
+    let stats = metrics.requests.entry((operation, status)).or_default();
+    stats.count += 1;
+    stats.seconds += seconds;
+    stats.bytes_in += bytes_in;
+    stats.bytes_out += bytes_out;
+    if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+        stats.buckets[bucket] += 1;
+    }
+}
+
+// Record the outcome (`ok` or `error`) of a background task run
+pub fn record_task(task: &'static str, ok: bool) {
+    *METRICS.lock().unwrap().tasks.entry((task, if ok { "ok" } else { "error" })).or_default() += 1;
+}
+
+pub fn record_lifecycle(report: &LifecycleReport) {
+    let mut metrics = METRICS.lock().unwrap();
+    for (action, count) in [
+        ("expired", report.expired),
+        ("noncurrent_removed", report.noncurrent_removed),
+        ("delete_markers_removed", report.delete_markers_removed),
+        ("uploads_aborted", report.uploads_aborted),
+        ("skipped", report.skipped),
+    ] {
+        *metrics.lifecycle_actions.entry(action).or_default() += count as u64;
+    }
+}
+
+pub fn record_blobs_collected(count: usize) {
+    METRICS.lock().unwrap().blobs_collected += count as u64;
+}
+
+// Middleware counting requests by operation and status, with their latency
+// and body sizes. Runs on path-style URIs (after virtual host rewriting).
+pub async fn record(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    let operation = request_operation(&req);
+    let bytes_in = req.headers().get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    let result = next.call(req).await;
+    let (status, bytes_out) = match &result {
+        Ok(res) => (res.status(), match res.response().body().size() {
+            BodySize::Sized(size) => size,
+            _ => 0,
+        }),
+        Err(e) => (e.as_response_error().status_code(), 0),
+    };
+    record_request(operation, status.as_u16(), started.elapsed().as_secs_f64(), bytes_in, bytes_out);
+    result
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+fn render() -> String {
+    let mut out = String::new();
+    {
+        let metrics = METRICS.lock().unwrap();
+        family(&mut out, "r3_requests_total", "counter", "Requests by S3 operation and status.");
+        for ((operation, status), stats) in &metrics.requests {
+            let _ = writeln!(out, "r3_requests_total{{operation=\"{}\",status=\"{}\"}} {}", operation, status, stats.count);
+        }
+        family(&mut out, "r3_request_duration_seconds", "histogram", "Request latency by S3 operation and status.");
+        for ((operation, status), stats) in &metrics.requests {
+            let labels = format!("operation=\"{}\",status=\"{}\"", operation, status);
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(stats.buckets) {
+                cumulative += count;
+                let _ = writeln!(out, "r3_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, cumulative);
+            }
+            let _ = writeln!(out, "r3_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, stats.count);
+            let _ = writeln!(out, "r3_request_duration_seconds_sum{{{}}} {}", labels, stats.seconds);
+            let _ = writeln!(out, "r3_request_duration_seconds_count{{{}}} {}", labels, stats.count);
+        }
+        let mut bytes: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+        for ((operation, _), stats) in &metrics.requests {
+            let entry = bytes.entry(operation).or_default();
+            entry.0 += stats.bytes_in;
+            entry.1 += stats.bytes_out;
+        }
+        family(&mut out, "r3_received_bytes_total", "counter", "Request body bytes received by S3 operation.");
+        for (operation, (bytes_in, _)) in &bytes {
+            let _ = writeln!(out, "r3_received_bytes_total{{operation=\"{}\"}} {}", operation, bytes_in);
+        }
+        family(&mut out, "r3_sent_bytes_total", "counter", "Response body bytes sent by S3 operation.");
+        for (operation, (_, bytes_out)) in &bytes {
+            let _ = writeln!(out, "r3_sent_bytes_total{{operation=\"{}\"}} {}", operation, bytes_out);
+        }
+        family(&mut out, "r3_background_task_runs_total", "counter", "Background task runs by outcome.");
+        for ((task, outcome), runs) in &metrics.tasks {
+            let _ = writeln!(out, "r3_background_task_runs_total{{task=\"{}\",outcome=\"{}\"}} {}", task, outcome, runs);
+        }
+        family(&mut out, "r3_lifecycle_actions_total", "counter", "Versions and uploads handled by lifecycle rules.");
+        for (action, count) in &metrics.lifecycle_actions {
+            let _ = writeln!(out, "r3_lifecycle_actions_total{{action=\"{}\"}} {}", action, count);
+        }
+        family(&mut out, "r3_blobs_collected_total", "counter", "Unreferenced blobs removed by garbage collection.");
+        let _ = writeln!(out, "r3_blobs_collected_total {}", metrics.blobs_collected);
+    }
+
+    let mut buckets = VERSION_STORE.bucket_stats();
+    buckets.sort_by(|a, b| a.0.cmp(&b.0));
+    family(&mut out, "r3_bucket_objects", "gauge", "Objects per bucket, excluding deleted ones.");
+    for (bucket, stats) in &buckets {
+        let _ = writeln!(out, "r3_bucket_objects{{bucket=\"{}\"}} {}", escape(bucket), stats.objects);
+    }
+    family(&mut out, "r3_bucket_stored_bytes", "gauge", "Bytes held by all versions per bucket.");
+    for (bucket, stats) in &buckets {
+        let _ = writeln!(out, "r3_bucket_stored_bytes{{bucket=\"{}\"}} {}", escape(bucket), stats.bytes);
+    }
+    family(&mut out, "r3_version_store_versions", "gauge", "Versions and delete markers in the version store.");
+    let _ = writeln!(out, "r3_version_store_versions {}", buckets.iter().map(|(_, stats)| stats.versions).sum::<u64>());
+    family(&mut out, "r3_multipart_uploads_in_progress", "gauge", "Multipart uploads not yet completed or aborted.");
+    let _ = writeln!(out, "r3_multipart_uploads_in_progress {}", multipart::upload_count());
+    out
+}
+
+#[get("/metrics")]
+pub async fn get_metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::router;
+    use actix_web::{test, App};
+
+    #[actix_rt::test]
+    async fn test_operation_names() {
+        let query_op = |method: Method, path: &str, query: &str| operation(&method, path, query, false);
+        assert_eq!(query_op(Method::GET, "/bucket/a/b.txt", ""), "GetObject");
+        assert_eq!(query_op(Method::HEAD, "/bucket", ""), "HeadBucket");
+        assert_eq!(query_op(Method::GET, "/bucket", "prefix=a&max-keys=2"), "ListObjects");
+        assert_eq!(query_op(Method::PUT, "/bucket", "cors"), "PutBucketCors");
+        assert_eq!(query_op(Method::GET, "/bucket/key", "legal-hold"), "GetObjectLegalHold");
+        assert_eq!(query_op(Method::GET, "/bucket", "object-lock"), "GetObjectLockConfiguration");
+        assert_eq!(query_op(Method::PUT, "/bucket/key", "partNumber=1&uploadId=x"), "UploadPart");
+        assert_eq!(query_op(Method::DELETE, "/bucket/key", "versionId=1"), "DeleteObject");
+        assert_eq!(query_op(Method::POST, "/", ""), "CreateBucket");
+        assert_eq!(query_op(Method::PATCH, "/bucket/key", ""), "Other");
+        assert_eq!(operation(&Method::PUT, "/bucket/key", "", true), "CopyObject");
+    }
+
+    #[actix_rt::test]
+    async fn test_metrics_endpoint() {
+        let bucket = "testbucket_metrics";
+        let _ = std::fs::remove_dir_all(bucket);
+        let app = test::init_service(App::new()
+            .wrap(actix_web::middleware::from_fn(record))
+            .service(get_metrics)
+            .configure(router::configure)).await;
+        let req = test::TestRequest::put().uri(&format!("/{}/a.txt", bucket)).set_payload("metrics").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::get().uri(&format!("/{}/a.txt", bucket)).to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "metrics");
+        record_task("blob_gc", true);
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = String::from_utf8(test::call_and_read_body(&app, req).await.to_vec()).unwrap();
+        assert!(body.contains("r3_requests_total{operation=\"PutObject\",status=\"201\"}"), "{}", body);
+        assert!(body.contains("r3_request_duration_seconds_bucket{operation=\"GetObject\",status=\"200\",le=\"+Inf\"}"));
+        assert!(body.contains(&format!("r3_bucket_objects{{bucket=\"{}\"}} 1", bucket)));
+        assert!(body.contains(&format!("r3_bucket_stored_bytes{{bucket=\"{}\"}} 7", bucket)));
+        assert!(body.contains("r3_background_task_runs_total{task=\"blob_gc\",outcome=\"ok\"}"));
+        assert!(body.contains("# TYPE r3_multipart_uploads_in_progress gauge"));
+        let _ = std::fs::remove_dir_all(bucket);
+    }
+}
@@ -3,6 +3,21 @@ pub mod bucket;
 pub mod multipart;
 pub mod versioning;
 pub mod auth;
+pub mod admin;
+pub mod snapshot;
+pub mod lifecycle;
+pub mod object_lock;
+pub mod tagging;
+pub mod encryption;
+pub mod checksum;
+pub mod chunked;
+pub mod post_object;
+pub mod cors;
+pub mod website;
+pub mod virtual_host;
+pub mod router;
+pub mod metrics;
+pub mod access_log;
 
 use actix_web::{Responder, get};
 
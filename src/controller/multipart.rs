@@ -1,21 +1,40 @@
-use actix_web::{web, HttpResponse, Responder, Error, post, put, delete};
+use actix_web::{web, HttpRequest, HttpResponse, Responder, Error};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::{self, File};
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::Path;
 use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 use lazy_static::lazy_static;
+use crate::controller::{bucket, checksum, chunked, encryption, object, object_lock, tagging, versioning};
+use crate::controller::checksum::{Checksum, ChecksumAlgorithm};
+use crate::crypto::{self, EncryptionInfo, Sse};
+use crate::sigv4;
+
+// An uploaded part; parts of encrypted uploads are stored encrypted
+#[derive(Debug, Clone)]
+struct PartInfo {
+    etag: String,
+    encryption: Option<EncryptionInfo>,
+    checksum: Option<Checksum>,
+}
 
-// Structure to store upload information
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// Structure to store upload information. Uploads are only kept in memory,
+// which is also the only place the customer key of an SSE-C upload lives
+// until the upload is completed or aborted.
+#[derive(Clone, Default)]
 struct UploadInfo {
     upload_id: String,
     bucket: String,
     key: String,
-    parts: HashMap<u32, String>, // part number -> etag
+    parts: HashMap<u32, PartInfo>, // part number -> part
     created_at: u64,
+    metadata: HashMap<String, String>, // headers supplied at initiation
+    tags: BTreeMap<String, String>,
+    lock: object_lock::LockSettings,
+    sse: Option<Sse>,
+    checksum_algorithm: Option<ChecksumAlgorithm>, // parts must carry checksums of this kind
 }
 
 // In-memory storage for upload information
@@ -30,21 +49,17 @@ impl UploadStore {
         }
     }
 
-    fn _create_upload(&self, bucket: String, key: String) -> String {
-        let upload_id = format!("{:x}", SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_nanos());
-        
+    // Register a new upload with the settings from `template`
+    fn create_upload(&self, template: UploadInfo) -> String {
+        let upload_id = uuid::Uuid::new_v4().to_string();
         let upload_info = UploadInfo {
             upload_id: upload_id.clone(),
-            bucket,
-            key,
             parts: HashMap::new(),
             created_at: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
+            ..template
         };
 
         self.uploads.lock().unwrap().insert(upload_id.clone(), upload_info);
@@ -55,14 +70,62 @@ impl UploadStore {
         self.uploads.lock().unwrap().get(upload_id).cloned()
     }
 
-    fn add_part(&self, upload_id: &str, part_number: u32, etag: String) -> bool {
+    fn add_part(&self, upload_id: &str, part_number: u32, part: PartInfo) -> bool {
         if let Some(upload) = self.uploads.lock().unwrap().get_mut(upload_id) {
-            upload.parts.insert(part_number, etag);
+            upload.parts.insert(part_number, part);
             true
         } else {
             false
         }
     }
+
+    fn remove_upload(&self, upload_id: &str) -> Option<UploadInfo> {
+        self.uploads.lock().unwrap().remove(upload_id)
+    }
+}
+
+// An upload that has been initiated but not completed or aborted
+pub struct IncompleteUpload {
+    pub upload_id: String,
+    pub key: String,
+    pub created_at: u64,
+}
+
+pub fn incomplete_uploads(bucket: &str) -> Vec<IncompleteUpload> {
+    UPLOAD_STORE.uploads.lock().unwrap()
+        .values()
+        .filter(|upload| upload.bucket == bucket)
+        .map(|upload| IncompleteUpload {
+            upload_id: upload.upload_id.clone(),
+            key: upload.key.clone(),
+            created_at: upload.created_at,
+        })
+        .collect()
+}
+
+// Uploads initiated in any bucket and not yet completed or aborted
+pub fn upload_count() -> usize {
+    UPLOAD_STORE.uploads.lock().unwrap().len()
+}
+
+// The upload `upload_id` of `key` in `bucket`. Upload IDs name directories,
+// so one from a request is only used once it is known to be ours.
+fn find_upload(bucket: &str, key: &str, upload_id: &str) -> Result<UploadInfo, Error> {
+    UPLOAD_STORE.get_upload(upload_id)
+        .filter(|upload| upload.bucket == bucket && upload.key == key)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("NoSuchUpload: the upload does not exist"))
+}
+
+// Forget an upload and remove its parts
+pub fn abort_upload(bucket: &str, upload_id: &str) -> std::io::Result<()> {
+    let Some(upload) = UPLOAD_STORE.remove_upload(upload_id).filter(|upload| upload.bucket == bucket) else {
+        return Ok(());
+    };
+    let upload_dir = format!("{}/{}", upload.bucket, upload.upload_id);
+    if Path::new(&upload_dir).exists() {
+        fs::remove_dir_all(&upload_dir)?;
+    }
+    Ok(())
 }
 
 // Initialize upload store
@@ -78,8 +141,8 @@ pub struct InitiateMultipartUploadResponse {
     upload_id: String,
 }
 
-#[post("/{bucket}/{key}")]
 pub async fn initiate_multipart_upload(
+    req: HttpRequest,
     path: web::Path<(String, String)>,
     query: web::Query<HashMap<String, String>>,
 ) -> Result<impl Responder, Error> {
@@ -89,7 +152,23 @@ pub async fn initiate_multipart_upload(
         return Ok(HttpResponse::BadRequest().body("Missing uploads parameter"));
     }
 
-    let upload_id = uuid::Uuid::new_v4().to_string();
+    let lock = object_lock::settings_from_request(&req, &bucket)?;
+    let tags = tagging::tags_from_request(&req)?;
+    if bucket::policy_denies(&bucket, "s3:PutObject", &tagging::request_condition_values(&tags)) {
+        return Err(actix_web::error::ErrorForbidden("AccessDenied"));
+    }
+    let sse = encryption::requested(&req, &bucket)?;
+    let checksum_algorithm = checksum::algorithm_from_request(&req)?;
+    let upload_id = UPLOAD_STORE.create_upload(UploadInfo {
+        bucket: bucket.clone(),
+        key: key.clone(),
+        metadata: object::extract_metadata(&req),
+        tags,
+        lock,
+        sse: sse.clone(),
+        checksum_algorithm,
+        ..Default::default()
+    });
     let response = InitiateMultipartUploadResponse {
         bucket,
         key,
@@ -106,18 +185,25 @@ pub async fn initiate_multipart_upload(
         response.bucket, response.key, response.upload_id
     );
 
-    Ok(HttpResponse::Ok()
-        .content_type("application/xml")
-        .body(xml))
+    let mut http_response = HttpResponse::Ok();
+    http_response.content_type("application/xml");
+    for header in sse.iter().flat_map(encryption::requested_headers) {
+        http_response.insert_header(header);
+    }
+    if let Some(algorithm) = checksum_algorithm {
+        http_response.insert_header(("x-amz-checksum-algorithm", algorithm.name()));
+    }
+    Ok(http_response.body(xml))
 }
 
-#[put("/{bucket}/{key}")]
 pub async fn upload_part(
+    req: HttpRequest,
     path: web::Path<(String, String)>,
     query: web::Query<HashMap<String, String>>,
     payload: web::Payload,
 ) -> Result<impl Responder, Error> {
-    let (bucket, _key) = path.into_inner();
+    sigv4::check_presigned(&req)?;
+    let (bucket, key) = path.into_inner();
     let upload_id = query.get("uploadId").ok_or_else(|| {
         actix_web::error::ErrorBadRequest("Missing uploadId parameter")
     })?;
@@ -127,120 +213,174 @@ pub async fn upload_part(
             actix_web::error::ErrorBadRequest("Invalid partNumber parameter")
         })?;
 
+    let upload = find_upload(&bucket, &key, upload_id)?;
+
+    // Parts of SSE-C uploads must be sent with the key the upload was initiated with
+    let customer_key = encryption::customer_key(&req, encryption::CUSTOMER_KEY_HEADERS)?;
+    match (upload.sse.as_ref().and_then(Sse::customer_key), customer_key.as_ref()) {
+        (Some(expected), Some(key)) if expected != key => {
+            return Err(actix_web::error::ErrorForbidden("AccessDenied: customer key does not match the upload"));
+        }
+        (Some(_), None) | (None, Some(_)) => {
+            return Err(actix_web::error::ErrorBadRequest("InvalidRequest: customer key does not match the upload"));
+        }
+        _ => {}
+    }
+
     // Create directory for multipart upload if it doesn't exist
-    let upload_dir = format!("{}/{}", bucket, upload_id);
+    let upload_dir = format!("{}/{}", upload.bucket, upload.upload_id);
     fs::create_dir_all(&upload_dir)?;
 
-    // Save the part
+    // Save the part, encrypted if the upload is
+    let (bytes, trailers) = chunked::decode_body(&req, object::read_payload(payload).await?)?;
+    let checksum = checksum::requested(&req, &bytes, &trailers, upload.checksum_algorithm)?;
+    let (stored, encryption) = match &upload.sse {
+        Some(sse) => {
+            let (sealed, info) = crypto::encrypt(&bytes, sse).map_err(encryption::key_error)?;
+            (sealed, Some(info))
+        }
+        None => (bytes.to_vec(), None),
+    };
     let part_path = format!("{}/part-{}", upload_dir, part_number);
     let mut file = File::create(&part_path)?;
-    let bytes = payload.to_bytes().await?;
-    file.write_all(&bytes)?;
+    file.write_all(&stored)?;
 
-    // Generate ETag (simplified version)
+    // ETag of a part is the MD5 of its content
     let etag = format!("{:x}", md5::compute(&bytes));
-    UPLOAD_STORE.add_part(upload_id, part_number, etag.clone());
+    let part = PartInfo { etag: etag.clone(), encryption, checksum: checksum.clone() };
+    if !UPLOAD_STORE.add_part(upload_id, part_number, part) {
+        return Err(actix_web::error::ErrorNotFound("Upload not found"));
+    }
 
-    Ok(HttpResponse::Ok()
-        .insert_header(("ETag", etag))
-        .finish())
+    let mut response = HttpResponse::Ok();
+    response.insert_header(("ETag", etag));
+    for header in upload.sse.iter().flat_map(encryption::requested_headers) {
+        response.insert_header(header);
+    }
+    if let Some(checksum) = checksum {
+        response.insert_header((checksum.algorithm.header(), checksum.value));
+    }
+    Ok(response.finish())
 }
 
-#[post("/{bucket}/{key}/complete")]
 pub async fn complete_multipart_upload(
     path: web::Path<(String, String)>,
     query: web::Query<HashMap<String, String>>,
 ) -> Result<impl Responder, Error> {
-    let (bucket, _key) = path.into_inner();
+    let (bucket, key) = path.into_inner();
     let upload_id = query.get("uploadId").ok_or_else(|| {
         actix_web::error::ErrorBadRequest("Missing uploadId parameter")
     })?;
 
-    println!("[DEBUG] complete_multipart_upload: upload_id = {}", upload_id);
-
-    let upload_info = match UPLOAD_STORE.get_upload(upload_id) {
-        Some(info) => info,
-        None => {
-            println!("[DEBUG] complete_multipart_upload: upload_info not found for upload_id = {}", upload_id);
-            return Err(actix_web::error::ErrorNotFound("Upload not found"));
-        }
-    };
+    let upload_info = find_upload(&bucket, &key, upload_id)?;
+    if bucket::policy_denies(&bucket, "s3:PutObject", &tagging::request_condition_values(&upload_info.tags)) {
+        return Err(actix_web::error::ErrorForbidden("AccessDenied"));
+    }
 
     // Check if all parts are present
     let expected_parts: Vec<u32> = (1..=upload_info.parts.len() as u32).collect();
     let mut part_numbers: Vec<u32> = upload_info.parts.keys().cloned().collect();
     part_numbers.sort();
-    println!("[DEBUG] complete_multipart_upload: part_numbers = {:?}, expected_parts = {:?}", part_numbers, expected_parts);
-    if part_numbers != expected_parts {
-        println!("[DEBUG] complete_multipart_upload: Not all parts are present");
+    if part_numbers.is_empty() || part_numbers != expected_parts {
         return Err(actix_web::error::ErrorBadRequest("Not all parts are present"));
     }
 
-    // Combine all parts
-    let upload_dir = format!("{}/{}", bucket, upload_id);
-    println!("[DEBUG] complete_multipart_upload: upload_dir = {}", upload_dir);
-    let final_path = format!("{}/{}", bucket, _key);
-    let mut final_file = match File::create(&final_path) {
-        Ok(f) => f,
-        Err(e) => {
-            println!("[DEBUG] complete_multipart_upload: Failed to create final file: {}", e);
-            return Err(actix_web::error::ErrorInternalServerError("Failed to create final file"));
-        }
-    };
-
+    // Combine all parts, remembering their boundaries and digests
+    let upload_dir = format!("{}/{}", upload_info.bucket, upload_info.upload_id);
+    let mut content = Vec::new();
+    let mut part_sizes = Vec::with_capacity(part_numbers.len());
+    let mut part_digests = Vec::with_capacity(part_numbers.len() * 16);
     for part_number in &part_numbers {
         let part_path = format!("{}/part-{}", upload_dir, part_number);
-        println!("[DEBUG] complete_multipart_upload: reading part_path = {}", part_path);
-        let mut part_file = match File::open(&part_path) {
-            Ok(f) => f,
-            Err(e) => {
-                println!("[DEBUG] complete_multipart_upload: Failed to open part file {}: {}", part_path, e);
-                return Err(actix_web::error::ErrorInternalServerError("Failed to open part file"));
-            }
-        };
-        if let Err(e) = std::io::copy(&mut part_file, &mut final_file) {
-            println!("[DEBUG] complete_multipart_upload: Failed to copy part file {}: {}", part_path, e);
-            return Err(actix_web::error::ErrorInternalServerError("Failed to copy part file"));
+        let mut part = Vec::new();
+        if let Err(e) = File::open(&part_path).and_then(|mut f| f.read_to_end(&mut part)) {
+            log::error!(path = part_path.as_str(), error:% = e; "Failed to read multipart upload part");
+            return Err(actix_web::error::ErrorInternalServerError("Failed to read part file"));
+        }
+        if let Some(info) = &upload_info.parts[part_number].encryption {
+            let customer_key = upload_info.sse.as_ref().and_then(Sse::customer_key);
+            part = crypto::decrypt(info, customer_key, &part).map_err(encryption::key_error)?;
         }
+        let digest = hex::decode(&upload_info.parts[part_number].etag)
+            .map_err(|_| actix_web::error::ErrorInternalServerError("Invalid part ETag"))?;
+        part_digests.extend_from_slice(&digest);
+        part_sizes.push(part.len() as u64);
+        content.extend_from_slice(&part);
     }
 
+    // S3 multipart ETag: MD5 of the concatenated part digests, suffixed with the part count
+    let etag = format!("{:x}-{}", md5::compute(&part_digests), part_numbers.len());
+    let checksum = match upload_info.checksum_algorithm {
+        Some(algorithm) => {
+            let part_checksums = part_numbers.iter()
+                .map(|n| upload_info.parts[n].checksum.clone()
+                    .ok_or_else(|| actix_web::error::ErrorBadRequest("InvalidPart: part is missing its checksum")))
+                .collect::<Result<Vec<_>, Error>>()?;
+            Some(Checksum::composite(algorithm, &part_checksums)?)
+        }
+        None => None,
+    };
+
+    let version = versioning::create_version_from(&content, upload_info.sse.as_ref(), versioning::VersionInfo {
+        bucket: bucket.clone(),
+        key: key.clone(),
+        etag: etag.clone(),
+        metadata: upload_info.metadata,
+        part_sizes,
+        tags: upload_info.tags,
+        retention: upload_info.lock.retention,
+        legal_hold: upload_info.lock.legal_hold,
+        checksum,
+        ..Default::default()
+    })?;
+
     // Clean up temporary files
+    UPLOAD_STORE.remove_upload(upload_id);
     if let Err(e) = fs::remove_dir_all(&upload_dir) {
-        println!("[DEBUG] complete_multipart_upload: Failed to remove upload_dir {}: {}", upload_dir, e);
+        log::warn!(path = upload_dir.as_str(), error:% = e; "Failed to remove multipart upload directory");
     }
 
     let response = format!(
-        r#"<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+        r#"<?xml version="1.0" encoding="UTF-8"?>
         <CompleteMultipartUploadResult>
             <Location>http://localhost:8080/{}/{}</Location>
             <Bucket>{}</Bucket>
             <Key>{}</Key>
-            <ETag>\"{}\"</ETag>
+            <ETag>"{}"</ETag>{}
         </CompleteMultipartUploadResult>"#,
-        bucket, _key, bucket, _key, upload_id
+        bucket, key, bucket, key, etag,
+        version.checksum.as_ref()
+            .map(|checksum| format!("\n            <{0}>{1}</{0}>", checksum.algorithm.element(), checksum.value))
+            .unwrap_or_default()
     );
-    println!("[DEBUG] complete_multipart_upload response: {}", response);
 
-    Ok(HttpResponse::Ok()
-        .content_type("application/xml")
-        .body(response))
+    let mut http_response = HttpResponse::Ok();
+    http_response.content_type("application/xml");
+    http_response.insert_header(("ETag", etag));
+    for header in checksum::response_headers(version.checksum.as_ref()) {
+        http_response.insert_header(header);
+    }
+    let customer_key = upload_info.sse.as_ref().and_then(Sse::customer_key);
+    for header in encryption::response_headers(&version, customer_key) {
+        http_response.insert_header(header);
+    }
+    if versioning::VERSION_STORE.get_status(&bucket).is_versioned() {
+        http_response.insert_header(("x-amz-version-id", version.version_id));
+    }
+    Ok(http_response.body(response))
 }
 
-#[delete("/{bucket}/{key}")]
 pub async fn abort_multipart_upload(
     path: web::Path<(String, String)>,
     query: web::Query<HashMap<String, String>>,
 ) -> Result<impl Responder, Error> {
-    let (bucket, _key) = path.into_inner();
+    let (bucket, key) = path.into_inner();
     let upload_id = query.get("uploadId").ok_or_else(|| {
         actix_web::error::ErrorBadRequest("Missing uploadId parameter")
     })?;
 
-    // Remove temporary files
-    let upload_dir = format!("{}/{}", bucket, upload_id);
-    if Path::new(&upload_dir).exists() {
-        fs::remove_dir_all(&upload_dir)?;
-    }
+    let upload = find_upload(&bucket, &key, upload_id)?;
+    abort_upload(&upload.bucket, &upload.upload_id)?;
 
     Ok(HttpResponse::NoContent().finish())
 }
@@ -248,6 +388,7 @@ pub async fn abort_multipart_upload(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::controller::router;
     use actix_web::{test, App};
     use std::fs;
     use std::path::Path;
@@ -284,7 +425,7 @@ mod tests {
         let bucket = "testbucket_mp";
         let key = "testfile.txt";
         setup_bucket(bucket);
-        let app = test::init_service(App::new().service(initiate_multipart_upload)).await;
+        let app = test::init_service(App::new().configure(router::configure)).await;
 
         let req = test::TestRequest::post()
             .uri(&format!("/{}/{}?uploads", bucket, key))
@@ -309,9 +450,7 @@ mod tests {
         let bucket = "testbucket_mp2";
         let key = "testfile2.txt";
         setup_bucket(bucket);
-        let app = test::init_service(App::new()
-            .service(initiate_multipart_upload)
-            .service(upload_part))
+        let app = test::init_service(App::new().configure(router::configure))
             .await;
 
         // First initiate upload
@@ -353,10 +492,7 @@ mod tests {
         let bucket = "testbucket_mp4";
         let key = "testfile4.txt";
         setup_bucket(bucket);
-        let app = test::init_service(App::new()
-            .service(initiate_multipart_upload)
-            .service(upload_part)
-            .service(abort_multipart_upload))
+        let app = test::init_service(App::new().configure(router::configure))
             .await;
 
         // Initiate upload
@@ -406,12 +542,124 @@ mod tests {
         cleanup_upload(bucket, &upload_id);
     }
 
+    #[actix_rt::test]
+    async fn test_complete_multipart_upload_creates_version() {
+        let bucket = "testbucket_mp3";
+        let key = "testfile3.txt";
+        setup_bucket(bucket);
+        versioning::VERSION_STORE.set_status(bucket, versioning::VersioningStatus::Enabled);
+        let app = test::init_service(App::new().configure(router::configure))
+            .await;
+
+        // Initiate upload with metadata
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/{}?uploads", bucket, key))
+            .insert_header(("content-type", "text/plain"))
+            .insert_header(("x-amz-meta-origin", "multipart"))
+            .insert_header(("x-amz-checksum-algorithm", "CRC32"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let body = test::read_body(resp).await;
+        let upload_id = extract_upload_id(std::str::from_utf8(&body).unwrap())
+            .expect("Failed to extract upload ID");
+
+        // Upload two parts
+        for (part_number, data) in [(1, "first part,"), (2, "second part")] {
+            let req = test::TestRequest::put()
+                .uri(&format!("/{}/{}?uploadId={}&partNumber={}", bucket, key, upload_id, part_number))
+                .set_payload(data)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200);
+        }
+
+        // Complete upload
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/{}?uploadId={}", bucket, key, upload_id))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let version_id = resp.headers().get("x-amz-version-id")
+            .expect("Missing version id")
+            .to_str().unwrap().to_string();
+        let etag = resp.headers().get("ETag").unwrap().to_str().unwrap().to_string();
+        assert!(etag.ends_with("-2"));
+
+        let version = versioning::VERSION_STORE.get_version(bucket, key, &version_id)
+            .expect("Version not recorded");
+        assert_eq!(version.etag, etag);
+        assert_eq!(version.part_sizes, vec![11, 11]);
+        assert_eq!(version.metadata.get("x-amz-meta-origin").map(String::as_str), Some("multipart"));
+        let parts = [
+            Checksum::compute(ChecksumAlgorithm::Crc32, b"first part,"),
+            Checksum::compute(ChecksumAlgorithm::Crc32, b"second part"),
+        ];
+        assert_eq!(version.checksum, Some(Checksum::composite(ChecksumAlgorithm::Crc32, &parts).unwrap()));
+
+        // Read back a single part
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/{}?partNumber=2", bucket, key))
+            .insert_header(("x-amz-checksum-mode", "ENABLED"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 206);
+        assert_eq!(resp.headers().get("x-amz-mp-parts-count").unwrap(), "2");
+        assert_eq!(resp.headers().get("content-type").unwrap(), "text/plain");
+        assert_eq!(resp.headers().get("x-amz-checksum-crc32").unwrap().to_str().unwrap(), parts[1].value);
+        let body = test::read_body(resp).await;
+        assert_eq!(body, Bytes::from_static(b"second part"));
+
+        // The upload is gone once completed
+        assert!(UPLOAD_STORE.get_upload(&upload_id).is_none());
+        cleanup_upload(bucket, &upload_id);
+    }
+
+    #[actix_rt::test]
+    async fn test_unknown_upload_ids_touch_nothing() {
+        let bucket = "testbucket_mp7";
+        let key = "testfile7.txt";
+        setup_bucket(bucket);
+        fs::create_dir_all(format!("{}/victim", bucket)).unwrap();
+        let app = test::init_service(App::new().configure(router::configure)).await;
+
+        for upload_id in ["victim", "../testbucket_mp7/victim", "..%2Ftestbucket_mp7_escape"] {
+            let req = test::TestRequest::delete()
+                .uri(&format!("/{}/{}?uploadId={}", bucket, key, upload_id))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 404);
+            let req = test::TestRequest::put()
+                .uri(&format!("/{}/{}?uploadId={}&partNumber=1", bucket, key, upload_id))
+                .set_payload("data")
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 404);
+        }
+        assert!(Path::new(&format!("{}/victim", bucket)).exists());
+        assert!(!Path::new("testbucket_mp7_escape").exists());
+
+        // An upload can only be used through the key it was initiated for
+        let req = test::TestRequest::post().uri(&format!("/{}/{}?uploads", bucket, key)).to_request();
+        let body = test::read_body(test::call_service(&app, req).await).await;
+        let upload_id = extract_upload_id(std::str::from_utf8(&body).unwrap()).unwrap();
+        let req = test::TestRequest::delete()
+            .uri(&format!("/{}/other.txt?uploadId={}", bucket, upload_id))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 404);
+        assert!(UPLOAD_STORE.get_upload(&upload_id).is_some());
+        let req = test::TestRequest::delete()
+            .uri(&format!("/{}/{}?uploadId={}", bucket, key, upload_id))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 204);
+        let _ = fs::remove_dir_all(bucket);
+    }
+
     #[actix_rt::test]
     async fn test_upload_part_missing_upload_id() {
         let bucket = "testbucket_mp5";
         let key = "testfile5.txt";
         setup_bucket(bucket);
-        let app = test::init_service(App::new().service(upload_part)).await;
+        // Without uploadId the router treats this as a plain PUT, so call the handler directly
+        let app = test::init_service(App::new().route("/{bucket}/{key:.*}", web::put().to(upload_part))).await;
 
         let data = Bytes::from_static(b"test part data");
         let req = test::TestRequest::put()
@@ -429,7 +677,7 @@ mod tests {
         let bucket = "testbucket_mp6";
         let key = "testfile6.txt";
         setup_bucket(bucket);
-        let app = test::init_service(App::new().service(upload_part)).await;
+        let app = test::init_service(App::new().configure(router::configure)).await;
 
         let data = Bytes::from_static(b"test part data");
         let req = test::TestRequest::put()
@@ -1,83 +1,355 @@
-use actix_web::{web, HttpResponse, Responder, Error, get, post, delete};
+use actix_web::body::SizedStream;
+use actix_web::http::Method;
+use actix_web::{web, HttpRequest, HttpResponse, Responder, Error};
 use serde::Deserialize;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::collections::HashMap;
 use bytes::Bytes;
-use crate::controller::versioning;
-use std::path::Path;
+use opentelemetry::KeyValue;
+use crate::controller::{bucket, checksum, chunked, encryption, object_lock, snapshot, tagging, versioning, website};
+use crate::config;
+use crate::sigv4;
+use crate::telemetry;
+use crate::xml;
 
 #[derive(Deserialize)]
-struct ObjectPath {
+pub struct ObjectPath {
     bucket: String,
+    #[serde(rename = "key")]
     object: String,
 }
 
-impl ObjectPath {
-    fn into_file(&self) -> Result<File, Error> {
-        use std::path::Path;
-        let path = Path::new(&self.bucket).join(&self.object);
-        if path.exists() {
-            File::open(path).map_err(|e| actix_web::error::ErrorInternalServerError(e))
-        } else {
-            File::create(path).map_err(|e| actix_web::error::ErrorInternalServerError(e))
+// Headers that are stored with an object and returned when it is read
+pub const STORED_HEADERS: [&str; 7] = [
+    "content-type",
+    "content-encoding",
+    "content-disposition",
+    "content-language",
+    "cache-control",
+    "expires",
+    website::REDIRECT_LOCATION_HEADER,
+];
+
+// A response to HEAD: the body is declared as `size` bytes, which actix sends
+// as Content-Length without ever reading it
+pub fn head_response(mut response: actix_web::HttpResponseBuilder, size: u64) -> HttpResponse {
+    response.body(SizedStream::new(size, futures_util::stream::empty::<Result<Bytes, Error>>()))
+}
+
+pub fn entity_too_large() -> Error {
+    actix_web::error::ErrorBadRequest(format!(
+        "EntityTooLarge: the maximum allowed size is {} bytes", config::current().max_object_size
+    ))
+}
+
+// Read a request body of at most `max_object_size` bytes
+pub async fn read_payload(payload: web::Payload) -> Result<Bytes, Error> {
+    let limit = usize::try_from(config::current().max_object_size).unwrap_or(usize::MAX);
+    telemetry::in_span_async("read_body", [], payload.to_bytes_limited(limit)).await.map_err(|_| entity_too_large())?
+}
+
+// Collect the standard headers and `x-amz-meta-*` user metadata from a request.
+// `aws-chunked` only describes the transfer and is not stored as an encoding.
+pub fn extract_metadata(req: &HttpRequest) -> HashMap<String, String> {
+    req.headers()
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            STORED_HEADERS.contains(&name) || name.starts_with("x-amz-meta-")
+        })
+        .filter_map(|(name, value)| {
+            let value = value.to_str().ok()?;
+            if name == "content-encoding" {
+                let encodings: Vec<&str> = value.split(',')
+                    .map(str::trim)
+                    .filter(|encoding| *encoding != "aws-chunked")
+                    .collect();
+                return (!encodings.is_empty()).then(|| (name.as_str().to_string(), encodings.join(",")));
+            }
+            Some((name.as_str().to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+// Parse `x-amz-copy-source` (`[/]bucket/key[?versionId=id]`, URL-encoded)
+fn parse_copy_source(source: &str) -> Result<(String, String, Option<String>), Error> {
+    let (path, query) = source.split_once('?').unwrap_or((source, ""));
+    let path = percent_encoding::percent_decode_str(path.trim_start_matches('/'))
+        .decode_utf8()
+        .map_err(|_| actix_web::error::ErrorBadRequest("Invalid copy source"))?;
+    let (bucket, key) = path.split_once('/')
+        .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("Invalid copy source"))?;
+    let version_id = query.split('&')
+        .find_map(|param| param.strip_prefix("versionId="))
+        .map(str::to_string);
+    Ok((bucket.to_string(), key.to_string(), version_id))
+}
+
+// CopyObject: create a new version from an existing one. Copying a specific
+// version of an object onto itself promotes that version to latest.
+fn copy_object(req: &HttpRequest, path: &ObjectPath, source: &str) -> Result<HttpResponse, Error> {
+    let (source_bucket, source_key, source_version_id) = parse_copy_source(source)?;
+    let source_version = match &source_version_id {
+        Some(version_id) => {
+            let version = versioning::VERSION_STORE.get_version(&source_bucket, &source_key, version_id)
+                .ok_or_else(|| actix_web::error::ErrorNotFound("Source version not found"))?;
+            if version.is_delete_marker {
+                return Err(actix_web::error::ErrorBadRequest("Source version is a delete marker"));
+            }
+            version
         }
-    }    
+        None => versioning::VERSION_STORE.get_latest_version(&source_bucket, &source_key)
+            .filter(|v| !v.is_delete_marker)
+            .ok_or_else(|| actix_web::error::ErrorNotFound("Source object not found"))?,
+    };
+
+    let read_action = if source_version_id.is_some() { "s3:GetObjectVersion" } else { "s3:GetObject" };
+    if bucket::policy_denies(&source_bucket, read_action, &tagging::condition_values(&source_version)) {
+        return Err(actix_web::error::ErrorForbidden("AccessDenied"));
+    }
+
+    let replace_metadata = req.headers().get("x-amz-metadata-directive")
+        .is_some_and(|directive| directive == "REPLACE");
+    if source_bucket == path.bucket && source_key == path.object
+        && source_version_id.is_none() && !replace_metadata {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Copying an object to itself requires a versionId or a metadata change",
+        ));
+    }
+    let metadata = if replace_metadata { extract_metadata(req) } else { source_version.metadata.clone() };
+    let replace_tags = req.headers().get("x-amz-tagging-directive")
+        .is_some_and(|directive| directive == "REPLACE");
+    let tags = if replace_tags { tagging::tags_from_request(req)? } else { source_version.tags.clone() };
+    if bucket::policy_denies(&path.bucket, "s3:PutObject", &tagging::request_condition_values(&tags)) {
+        return Err(actix_web::error::ErrorForbidden("AccessDenied"));
+    }
+    // Lock settings are not copied; the copy gets the requested ones or the
+    // destination bucket's default
+    let lock = object_lock::settings_from_request(req, &path.bucket)?;
+    let source_key = encryption::customer_key(req, encryption::COPY_SOURCE_KEY_HEADERS)?;
+    encryption::check_customer_key(&source_version, source_key.as_ref())?;
+    let sse = encryption::requested(req, &path.bucket)?;
+    let version = versioning::copy_version(&source_version, versioning::VersionInfo {
+        bucket: path.bucket.clone(),
+        key: path.object.clone(),
+        metadata,
+        tags,
+        retention: lock.retention,
+        legal_hold: lock.legal_hold,
+        ..Default::default()
+    }, sse.as_ref(), source_key.as_ref())?;
+
+    let mut response = HttpResponse::Ok();
+    response.content_type("application/xml");
+    let customer_key = sse.as_ref().and_then(|sse| sse.customer_key());
+    for header in encryption::response_headers(&version, customer_key) {
+        response.insert_header(header);
+    }
+    if versioning::VERSION_STORE.get_status(&path.bucket).is_versioned() {
+        response.insert_header(("x-amz-version-id", version.version_id.clone()));
+    }
+    if versioning::VERSION_STORE.get_status(&source_bucket).is_versioned() {
+        response.insert_header(("x-amz-copy-source-version-id", source_version.version_id.clone()));
+    }
+    Ok(response.body(format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<CopyObjectResult>
+    <LastModified>{}</LastModified>
+    <ETag>{}</ETag>
+</CopyObjectResult>"#,
+        xml::timestamp(version.last_modified),
+        xml::escape(&format!("\"{}\"", version.etag))
+    )))
 }
 
-#[post("/{bucket}/{object}")]
-pub async fn create_object(path: web::Path<ObjectPath>, payload: web::Payload) 
+pub async fn create_object(req: HttpRequest, path: web::Path<ObjectPath>, payload: web::Payload) 
     -> Result<impl Responder, Error>  {
-    let bytes = payload.to_bytes().await?;
-    let mut f = path.into_file()?;
-    f.write_all(&bytes)?;
+    sigv4::check_presigned(&req)?;
+    if let Some(source) = req.headers().get("x-amz-copy-source") {
+        let source = source.to_str()
+            .map_err(|_| actix_web::error::ErrorBadRequest("Invalid copy source"))?;
+        return copy_object(&req, &path, source);
+    }
+    let lock = object_lock::settings_from_request(&req, &path.bucket)?;
+    let tags = tagging::tags_from_request(&req)?;
+    if bucket::policy_denies(&path.bucket, "s3:PutObject", &tagging::request_condition_values(&tags)) {
+        return Err(actix_web::error::ErrorForbidden("AccessDenied"));
+    }
+    let sse = encryption::requested(&req, &path.bucket)?;
+    let body = read_payload(payload).await?;
+    let (bytes, checksum, etag) = telemetry::in_span("digest_body", [KeyValue::new("size", body.len() as i64)], || {
+        let (bytes, trailers) = chunked::decode_body(&req, body)?;
+        let checksum = checksum::requested(&req, &bytes, &trailers, None)?;
+        let etag = format!("{:x}", md5::compute(&bytes));
+        Ok::<_, Error>((bytes, checksum, etag))
+    })?;
     
     // Create a new version
-    let etag = format!("{:x}", md5::compute(&bytes));
-    versioning::create_version(&path.bucket, &path.object, &bytes, &etag)?;
+    let version = versioning::create_version_from(&bytes, sse.as_ref(), versioning::VersionInfo {
+        bucket: path.bucket.clone(),
+        key: path.object.clone(),
+        etag: etag.clone(),
+        metadata: extract_metadata(&req),
+        tags,
+        retention: lock.retention,
+        legal_hold: lock.legal_hold,
+        checksum,
+        ..Default::default()
+    })?;
     
-    Ok(HttpResponse::Created()
-        .insert_header(("ETag", etag))
-        .finish())
+    let mut response = HttpResponse::Created();
+    response.insert_header(("ETag", etag));
+    for header in checksum::response_headers(version.checksum.as_ref()) {
+        response.insert_header(header);
+    }
+    let customer_key = sse.as_ref().and_then(|sse| sse.customer_key());
+    for header in encryption::response_headers(&version, customer_key) {
+        response.insert_header(header);
+    }
+    if versioning::VERSION_STORE.get_status(&path.bucket).is_versioned() {
+        response.insert_header(("x-amz-version-id", version.version_id));
+    }
+    Ok(response.finish())
 }
 
-#[get("/{bucket}/{object}")]
-pub async fn read_object(path: web::Path<ObjectPath>)
-    -> Result<impl Responder, Error>  {
-    let file_path = Path::new(&path.bucket).join(&path.object);
-    if !file_path.exists() {
-        return Err(actix_web::error::ErrorNotFound("Object not found"));
+// Parse a single `Range: bytes=...` header into `start..end`. Malformed and
+// multi-range headers are ignored, as S3 does, and the whole object is served.
+fn parse_range(range: &str, size: u64) -> Result<Option<(u64, u64)>, Error> {
+    let Some((first, last)) = range.trim().strip_prefix("bytes=").and_then(|r| r.split_once('-')) else {
+        return Ok(None);
+    };
+    if last.contains(',') {
+        return Ok(None);
     }
-    let mut f = path.into_file()?;
-    let mut content = Vec::new();
-    f.read_to_end(&mut content)?;
-    
-    // Get the latest version
-    if let Some(version) = versioning::VERSION_STORE.get_latest_version(&path.bucket, &path.object) {
-        if version.is_delete_marker {
-            return Err(actix_web::error::ErrorNotFound("Object is deleted"));
-        }
+    let (start, end) = match (first.parse::<u64>().ok(), last.parse::<u64>().ok()) {
+        (Some(start), Some(last)) if start <= last => (start, (last + 1).min(size)),
+        (Some(start), None) if last.is_empty() => (start, size),
+        (None, Some(suffix)) if first.is_empty() && suffix > 0 => (size.saturating_sub(suffix), size),
+        _ => return Ok(None),
+    };
+    if start >= size {
+        return Err(actix_web::error::ErrorRangeNotSatisfiable("InvalidRange"));
     }
-    
-    Ok(HttpResponse::Ok().body(Bytes::from(content)))
+    Ok(Some((start, end)))
 }
 
-#[get("/{bucket}/{object}")]
-pub async fn update_object(_path: web::Path<ObjectPath>) -> impl Responder {
-    // TODO: Implement logic to update object
-    HttpResponse::NoContent().finish()
+pub async fn read_object(
+    req: HttpRequest,
+    path: web::Path<ObjectPath>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<impl Responder, Error> {
+    sigv4::check_presigned(&req)?;
+    // Get the latest version, or the one recorded in a snapshot
+    let version = match query.get("snapshot") {
+        Some(name) => snapshot::snapshot_version(&path.bucket, name, &path.object)?,
+        None => versioning::VERSION_STORE.get_latest_version(&path.bucket, &path.object)
+            .ok_or_else(|| actix_web::error::ErrorNotFound("Object not found"))?,
+    };
+    if version.is_delete_marker {
+        return Err(actix_web::error::ErrorNotFound("Object is deleted"));
+    }
+    if bucket::policy_denies(&path.bucket, "s3:GetObject", &tagging::condition_values(&version)) {
+        return Err(actix_web::error::ErrorForbidden("AccessDenied"));
+    }
+    let customer_key = encryption::customer_key(&req, encryption::CUSTOMER_KEY_HEADERS)?;
+    encryption::check_customer_key(&version, customer_key.as_ref())?;
+
+    let mut response = HttpResponse::Ok();
+    response.insert_header(("ETag", version.etag.clone()));
+    response.insert_header(("Accept-Ranges", "bytes"));
+    if versioning::VERSION_STORE.get_status(&path.bucket).is_versioned() {
+        response.insert_header(("x-amz-version-id", version.version_id.clone()));
+    }
+    for (name, value) in &version.metadata {
+        response.insert_header((name.as_str(), value.as_str()));
+    }
+    for header in object_lock::response_headers(&version) {
+        response.insert_header(header);
+    }
+    for header in encryption::response_headers(&version, customer_key.as_ref()) {
+        response.insert_header(header);
+    }
+    if let Some(header) = tagging::count_header(&version) {
+        response.insert_header(header);
+    }
+
+    // Serve a single part or byte range when asked for one. Checksums are
+    // returned for whole objects and parts only.
+    let checksum_enabled = checksum::mode_enabled(&req);
+    let range = if let Some(part_number) = query.get("partNumber") {
+        let part_number = part_number.parse::<usize>()
+            .map_err(|_| actix_web::error::ErrorBadRequest("Invalid partNumber parameter"))?;
+        // Objects that were not uploaded in parts consist of a single part
+        let part_sizes = if version.part_sizes.is_empty() {
+            vec![version.size]
+        } else {
+            version.part_sizes.clone()
+        };
+        if part_number == 0 || part_number > part_sizes.len() {
+            return Err(actix_web::error::ErrorRangeNotSatisfiable("Invalid part number"));
+        }
+        let start = part_sizes[..part_number - 1].iter().sum::<u64>();
+        let end = start + part_sizes[part_number - 1];
+        if end > version.size {
+            return Err(actix_web::error::ErrorInternalServerError("Part boundaries exceed object size"));
+        }
+        response.insert_header(("x-amz-mp-parts-count", part_sizes.len().to_string()));
+        if checksum_enabled {
+            for header in checksum::response_headers(checksum::part_checksum(&version, part_number).as_ref()) {
+                response.insert_header(header);
+            }
+        }
+        Some((start, end))
+    } else {
+        match req.headers().get("Range").and_then(|range| range.to_str().ok()) {
+            Some(range) => parse_range(range, version.size)?,
+            None => None,
+        }
+    };
+
+    // HEAD answers from the version's metadata without reading its data
+    let head = req.method() == Method::HEAD;
+    if let Some((start, end)) = range {
+        response.status(actix_web::http::StatusCode::PARTIAL_CONTENT);
+        if end > start {
+            response.insert_header((
+                "Content-Range",
+                format!("bytes {}-{}/{}", start, end - 1, version.size),
+            ));
+        }
+        if head {
+            return Ok(head_response(response, end - start));
+        }
+        let content = versioning::read_version_range(&version, customer_key.as_ref(), start, end)?;
+        return Ok(response.body(Bytes::from(content)));
+    }
+
+    if checksum_enabled {
+        for header in checksum::response_headers(version.checksum.as_ref()) {
+            response.insert_header(header);
+        }
+    }
+    if head {
+        return Ok(head_response(response, version.size));
+    }
+    let content = versioning::read_version_with(&version, customer_key.as_ref())?;
+    Ok(response.body(Bytes::from(content)))
 }
 
-#[delete("/{bucket}/{object}")]
 pub async fn delete_object(path: web::Path<ObjectPath>) -> Result<impl Responder, Error> {
-    // Create a delete marker
-    versioning::create_delete_marker(&path.bucket, &path.object)?;
-    
-    Ok(HttpResponse::NoContent().finish())
+    // Versioned buckets keep the data and only add a delete marker
+    let mut response = HttpResponse::NoContent();
+    if let Some(marker) = versioning::delete_object(&path.bucket, &path.object)? {
+        response.insert_header(("x-amz-version-id", marker.version_id));
+        response.insert_header(("x-amz-delete-marker", "true"));
+    }
+    Ok(response.finish())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::controller::router;
     use actix_web::{test, App};
     use std::fs;
     use std::io::Write;
@@ -107,11 +379,11 @@ mod tests {
         let object = "testobject.txt";
         cleanup_object(bucket, object);
         setup_bucket(bucket);
-        let app = test::init_service(App::new().service(create_object).service(read_object)).await;
+        let app = test::init_service(App::new().configure(router::configure)).await;
 
         // Create object
         let data = Bytes::from_static(b"Hello, world!");
-        let req = test::TestRequest::post()
+        let req = test::TestRequest::put()
             .uri(&format!("/{}/{}", bucket, object))
             .set_payload(data.clone())
             .to_request();
@@ -129,13 +401,222 @@ mod tests {
         cleanup_object(bucket, object);
     }
 
+    #[actix_rt::test]
+    async fn test_encrypted_object_range_read() {
+        let bucket = "testbucket_obj_sse";
+        let object = "secret.bin";
+        cleanup_object(bucket, object);
+        setup_bucket(bucket);
+        let app = test::init_service(App::new().configure(router::configure)).await;
+
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 256) as u8).collect();
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}/{}", bucket, object))
+            .insert_header(("x-amz-server-side-encryption", "AES256"))
+            .set_payload(data.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 201);
+        assert_eq!(resp.headers().get("x-amz-server-side-encryption").unwrap(), "AES256");
+
+        // Stored data is not plaintext
+        let version = versioning::VERSION_STORE.get_latest_version(bucket, object).unwrap();
+        let stored = crate::blob::BLOB_STORE.read(bucket, &version.blob).unwrap();
+        assert_ne!(&stored[..1000], &data[..1000]);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/{}", bucket, object))
+            .insert_header(("Range", "bytes=65530-65545"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 206);
+        assert_eq!(resp.headers().get("Content-Range").unwrap(), "bytes 65530-65545/200000");
+        assert_eq!(test::read_body(resp).await, &data[65530..65546]);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/{}", bucket, object))
+            .insert_header(("Range", "bytes=-10"))
+            .to_request();
+        assert_eq!(test::read_body(test::call_service(&app, req).await).await, &data[199_990..]);
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/{}", bucket, object))
+            .insert_header(("Range", "bytes=300000-"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 416);
+        cleanup_object(bucket, object);
+    }
+
+    fn customer_key_headers(key: [u8; 32]) -> Vec<(&'static str, String)> {
+        use base64::Engine;
+        let base64 = base64::engine::general_purpose::STANDARD;
+        vec![
+            ("x-amz-server-side-encryption-customer-algorithm", "AES256".to_string()),
+            ("x-amz-server-side-encryption-customer-key", base64.encode(key)),
+            ("x-amz-server-side-encryption-customer-key-MD5", base64.encode(md5::compute(key).0)),
+        ]
+    }
+
+    #[actix_rt::test]
+    async fn test_customer_key_required_to_read() {
+        let bucket = "testbucket_obj_sse_c";
+        let object = "customer.txt";
+        cleanup_object(bucket, object);
+        setup_bucket(bucket);
+        let app = test::init_service(App::new().configure(router::configure)).await;
+
+        let mut req = test::TestRequest::put().uri(&format!("/{}/{}", bucket, object));
+        for header in customer_key_headers([1; 32]) {
+            req = req.insert_header(header);
+        }
+        let resp = test::call_service(&app, req.set_payload("only with the key").to_request()).await;
+        assert_eq!(resp.status(), 201);
+
+        let req = test::TestRequest::get().uri(&format!("/{}/{}", bucket, object)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+
+        let mut req = test::TestRequest::get().uri(&format!("/{}/{}", bucket, object));
+        for header in customer_key_headers([2; 32]) {
+            req = req.insert_header(header);
+        }
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), 403);
+
+        let mut req = test::TestRequest::get().uri(&format!("/{}/{}", bucket, object));
+        for header in customer_key_headers([1; 32]) {
+            req = req.insert_header(header);
+        }
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 200);
+        assert!(resp.headers().contains_key("x-amz-server-side-encryption-customer-key-MD5"));
+        assert_eq!(test::read_body(resp).await, "only with the key");
+        cleanup_object(bucket, object);
+    }
+
+    #[actix_rt::test]
+    async fn test_copy_from_self_promotes_version() {
+        let bucket = "testbucket_obj_promote";
+        let object = "promote.txt";
+        cleanup_object(bucket, object);
+        setup_bucket(bucket);
+        versioning::VERSION_STORE.set_status(bucket, versioning::VersioningStatus::Enabled);
+        let app = test::init_service(App::new().configure(router::configure)).await;
+
+        let mut version_ids = Vec::new();
+        for data in ["first", "second"] {
+            let req = test::TestRequest::put()
+                .uri(&format!("/{}/{}", bucket, object))
+                .set_payload(data)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            version_ids.push(resp.headers().get("x-amz-version-id").unwrap().to_str().unwrap().to_string());
+        }
+
+        // Copying onto itself without a version is rejected
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}/{}", bucket, object))
+            .insert_header(("x-amz-copy-source", format!("/{}/{}", bucket, object)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+
+        // Promote the first version
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}/{}", bucket, object))
+            .insert_header(("x-amz-copy-source", format!("{}/{}?versionId={}", bucket, object, version_ids[0])))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("x-amz-copy-source-version-id").unwrap(), version_ids[0].as_str());
+        let new_version_id = resp.headers().get("x-amz-version-id").unwrap().to_str().unwrap().to_string();
+        assert!(!version_ids.contains(&new_version_id));
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/{}", bucket, object))
+            .to_request();
+        let body = test::read_body(test::call_service(&app, req).await).await;
+        assert_eq!(body, Bytes::from_static(b"first"));
+        assert_eq!(versioning::VERSION_STORE.get_versions(bucket, object).len(), 3);
+        cleanup_object(bucket, object);
+    }
+
+    #[actix_rt::test]
+    async fn test_copy_source_obeys_bucket_policy() {
+        let bucket = "testbucket_copy_policy";
+        let target = "testbucket_copy_policy_target";
+        let _ = fs::remove_dir_all(bucket);
+        let _ = fs::remove_dir_all(target);
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let req = test::TestRequest::put().uri(&format!("/{}/private.txt", bucket)).set_payload("secret").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 201);
+        let source = versioning::VERSION_STORE.get_latest_version(bucket, "private.txt").unwrap();
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?policy", bucket))
+            .set_json(serde_json::json!({
+                "policy": r#"{"Statement": [{"Effect": "Deny", "Action": ["s3:GetObject", "s3:GetObjectVersion"]}]}"#,
+                "acl": "",
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        // Objects that can't be read can't be copied out either
+        for source in [format!("/{}/private.txt", bucket), format!("/{}/private.txt?versionId={}", bucket, source.version_id)] {
+            let req = test::TestRequest::put()
+                .uri(&format!("/{}/stolen.txt", target))
+                .insert_header(("x-amz-copy-source", source))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 403);
+        }
+        assert!(versioning::VERSION_STORE.get_latest_version(target, "stolen.txt").is_none());
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?policy", bucket))
+            .set_json(serde_json::json!({ "policy": "{}", "acl": "" }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let _ = fs::remove_dir_all(bucket);
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[actix_rt::test]
+    async fn test_head_reads_metadata_only() {
+        let bucket = "testbucket_obj_head";
+        let _ = fs::remove_dir_all(bucket);
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let req = test::TestRequest::put().uri(&format!("/{}/head.txt", bucket)).set_payload("headless").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 201);
+        let version = versioning::VERSION_STORE.get_latest_version(bucket, "head.txt").unwrap();
+
+        // With the data gone, GET fails but HEAD still answers
+        fs::remove_dir_all(Path::new(bucket).join(".blobs")).unwrap();
+        let req = test::TestRequest::get().uri(&format!("/{}/head.txt", bucket)).to_request();
+        assert!(!test::call_service(&app, req).await.status().is_success());
+        for uri in [
+            format!("/{}/head.txt", bucket),
+            format!("/{}/head.txt?versionId={}", bucket, version.version_id),
+        ] {
+            let req = test::TestRequest::default().method(Method::HEAD).uri(&uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200, "{}", uri);
+            assert_eq!(resp.headers().get("ETag").unwrap().to_str().unwrap(), version.etag);
+            assert_eq!(actix_web::body::MessageBody::size(resp.response().body()), actix_web::body::BodySize::Sized(8));
+        }
+        let req = test::TestRequest::default()
+            .method(Method::HEAD)
+            .uri(&format!("/{}/head.txt", bucket))
+            .insert_header(("Range", "bytes=0-3"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 206);
+        assert_eq!(resp.headers().get("Content-Range").unwrap(), "bytes 0-3/8");
+        let _ = fs::remove_dir_all(bucket);
+    }
+
     #[actix_rt::test]
     async fn test_read_nonexistent_object() {
         let bucket = "testbucket_obj2";
         let object = "nonexistent.txt";
         cleanup_object(bucket, object);
         setup_bucket(bucket);
-        let app = test::init_service(App::new().service(read_object)).await;
+        let app = test::init_service(App::new().configure(router::configure)).await;
         let req = test::TestRequest::get()
             .uri(&format!("/{}/{}", bucket, object))
             .to_request();
@@ -154,7 +635,7 @@ mod tests {
         let object_path = Path::new(bucket).join(object);
         let mut file = fs::File::create(&object_path).unwrap();
         file.write_all(b"delete me").unwrap();
-        let app = test::init_service(App::new().service(delete_object).service(read_object)).await;
+        let app = test::init_service(App::new().configure(router::configure)).await;
         // Delete object
         let req = test::TestRequest::delete()
             .uri(&format!("/{}/{}", bucket, object))
@@ -0,0 +1,508 @@
+use actix_web::{web, HttpRequest, HttpResponse, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use crate::config;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use lazy_static::lazy_static;
+use crate::controller::auth;
+use crate::controller::versioning::{self, VersionInfo, VersioningStatus, VERSION_STORE};
+use crate::xml;
+
+const OBJECT_LOCK_FILE: &str = "bucket_object_lock.json";
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LockMode {
+    Governance,
+    Compliance,
+}
+
+impl LockMode {
+    fn parse(mode: &str) -> Option<LockMode> {
+        match mode {
+            "GOVERNANCE" => Some(LockMode::Governance),
+            "COMPLIANCE" => Some(LockMode::Compliance),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LockMode::Governance => "GOVERNANCE",
+            LockMode::Compliance => "COMPLIANCE",
+        }
+    }
+}
+
+// Retention of a single object version
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Retention {
+    pub mode: LockMode,
+    pub retain_until: u64,
+}
+
+// Default retention applied to new versions in a bucket
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DefaultRetention {
+    pub mode: LockMode,
+    pub days: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ObjectLockConfiguration {
+    pub default_retention: Option<DefaultRetention>,
+}
+
+// Lock settings requested for a new version
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LockSettings {
+    pub retention: Option<Retention>,
+    pub legal_hold: bool,
+}
+
+lazy_static! {
+    // Buckets with Object Lock enabled; once enabled it cannot be turned off
+    static ref OBJECT_LOCK: Mutex<HashMap<String, ObjectLockConfiguration>> = Mutex::new(HashMap::new());
+}
+
+pub fn load_object_lock() -> std::io::Result<()> {
+    if config::metadata_path(OBJECT_LOCK_FILE).exists() {
+        let mut file = File::open(config::metadata_path(OBJECT_LOCK_FILE))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let configs: HashMap<String, ObjectLockConfiguration> = serde_json::from_str(&contents)?;
+        *OBJECT_LOCK.lock().unwrap() = configs;
+    }
+    Ok(())
+}
+
+fn save_object_lock() -> std::io::Result<()> {
+    let configs = OBJECT_LOCK.lock().unwrap();
+    let contents = serde_json::to_string_pretty(&*configs)?;
+    let mut file = File::create(config::metadata_path(OBJECT_LOCK_FILE))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+pub fn is_enabled(bucket: &str) -> bool {
+    OBJECT_LOCK.lock().unwrap().contains_key(bucket)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+// Read `x-amz-object-lock-*` headers for a new version, falling back to the
+// bucket's default retention
+pub fn settings_from_request(req: &HttpRequest, bucket: &str) -> Result<LockSettings, Error> {
+    settings_from(|name| req.headers().get(name).and_then(|v| v.to_str().ok()), bucket)
+}
+
+// Like `settings_from_request`, with `field` looking up the settings by
+// header name, e.g. in a POST upload's form fields
+pub fn settings_from<'a>(field: impl Fn(&str) -> Option<&'a str>, bucket: &str) -> Result<LockSettings, Error> {
+    let mode = field("x-amz-object-lock-mode");
+    let retain_until = field("x-amz-object-lock-retain-until-date");
+    let legal_hold = field("x-amz-object-lock-legal-hold");
+    let config = OBJECT_LOCK.lock().unwrap().get(bucket).cloned();
+
+    let Some(config) = config else {
+        if mode.is_some() || retain_until.is_some() || legal_hold.is_some() {
+            return Err(actix_web::error::ErrorBadRequest("Bucket is missing Object Lock Configuration"));
+        }
+        return Ok(LockSettings::default());
+    };
+
+    let retention = match (mode, retain_until) {
+        (Some(mode), Some(retain_until)) => Some(Retention {
+            mode: LockMode::parse(mode)
+                .ok_or_else(|| actix_web::error::ErrorBadRequest("Invalid x-amz-object-lock-mode"))?,
+            retain_until: xml::parse_timestamp(retain_until)
+                .filter(|until| *until > now())
+                .ok_or_else(|| actix_web::error::ErrorBadRequest("Invalid x-amz-object-lock-retain-until-date"))?,
+        }),
+        (None, None) => config.default_retention.map(|default| Retention {
+            mode: default.mode,
+            retain_until: now() + default.days * SECONDS_PER_DAY,
+        }),
+        _ => return Err(actix_web::error::ErrorBadRequest(
+            "x-amz-object-lock-mode and x-amz-object-lock-retain-until-date must be used together",
+        )),
+    };
+    let legal_hold = match legal_hold {
+        None | Some("OFF") => false,
+        Some("ON") => true,
+        Some(_) => return Err(actix_web::error::ErrorBadRequest("Invalid x-amz-object-lock-legal-hold")),
+    };
+    Ok(LockSettings { retention, legal_hold })
+}
+
+// Fail when a version is protected by a legal hold or an active retention period
+pub fn check_removable(version: &VersionInfo, bypass_governance: bool) -> Result<(), Error> {
+    if version.legal_hold {
+        return Err(actix_web::error::ErrorForbidden("Version is under legal hold"));
+    }
+    match version.retention {
+        Some(retention) if retention.retain_until > now() => match retention.mode {
+            LockMode::Governance if bypass_governance => Ok(()),
+            _ => Err(actix_web::error::ErrorForbidden("Version is locked by its retention period")),
+        },
+        _ => Ok(()),
+    }
+}
+
+// Headers describing the lock state of a version on GET/HEAD
+pub fn response_headers(version: &VersionInfo) -> Vec<(&'static str, String)> {
+    let mut headers = Vec::new();
+    if let Some(retention) = version.retention {
+        headers.push(("x-amz-object-lock-mode", retention.mode.as_str().to_string()));
+        headers.push(("x-amz-object-lock-retain-until-date", xml::timestamp(retention.retain_until)));
+    }
+    if version.legal_hold {
+        headers.push(("x-amz-object-lock-legal-hold", "ON".to_string()));
+    }
+    headers
+}
+
+// Whether the request asks to bypass governance retention; only admins may
+pub fn bypass_governance(req: &HttpRequest) -> Result<bool, Error> {
+    let bypass = req.headers().get("x-amz-bypass-governance-retention")
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"true"));
+    if bypass && !auth::is_admin(req) {
+        return Err(actix_web::error::ErrorForbidden("AccessDenied: bypassing governance retention requires an admin"));
+    }
+    Ok(bypass)
+}
+
+pub async fn put_object_lock_configuration(
+    path: web::Path<String>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    let body = std::str::from_utf8(&body)
+        .map_err(|_| actix_web::error::ErrorBadRequest("MalformedXML"))?;
+    if xml::element_text(body, "ObjectLockEnabled") != Some("Enabled") {
+        return Err(actix_web::error::ErrorBadRequest("MalformedXML"));
+    }
+    if VERSION_STORE.get_status(&bucket) != VersioningStatus::Enabled {
+        return Err(actix_web::error::ErrorConflict("InvalidBucketState: versioning must be enabled"));
+    }
+    let default_retention = match xml::element_text(body, "DefaultRetention") {
+        Some(default) => {
+            let mode = xml::element_text(default, "Mode")
+                .and_then(LockMode::parse)
+                .ok_or_else(|| actix_web::error::ErrorBadRequest("MalformedXML: invalid Mode"))?;
+            let days = match (xml::element_text(default, "Days"), xml::element_text(default, "Years")) {
+                (Some(days), None) => days.parse::<u64>().ok(),
+                (None, Some(years)) => years.parse::<u64>().ok().map(|years| years * 365),
+                _ => None,
+            }
+            .filter(|days| *days > 0)
+            .ok_or_else(|| actix_web::error::ErrorBadRequest("MalformedXML: specify either Days or Years"))?;
+            Some(DefaultRetention { mode, days })
+        }
+        None => None,
+    };
+    OBJECT_LOCK.lock().unwrap().insert(bucket, ObjectLockConfiguration { default_retention });
+    if let Err(e) = save_object_lock() {
+        return Ok(HttpResponse::InternalServerError().body(format!("Failed to save object lock: {}", e)));
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn get_object_lock_configuration(path: web::Path<String>) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    let config = OBJECT_LOCK.lock().unwrap().get(&bucket).cloned()
+        .ok_or_else(|| actix_web::error::ErrorNotFound("ObjectLockConfigurationNotFoundError"))?;
+    let rule = config.default_retention
+        .map(|default| format!(
+            "<Rule><DefaultRetention><Mode>{}</Mode><Days>{}</Days></DefaultRetention></Rule>",
+            default.mode.as_str(),
+            default.days
+        ))
+        .unwrap_or_default();
+    let response = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<ObjectLockConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><ObjectLockEnabled>Enabled</ObjectLockEnabled>{}</ObjectLockConfiguration>"#,
+        rule
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(response))
+}
+
+pub async fn put_object_retention(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    query: web::Query<HashMap<String, String>>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let (bucket, key) = path.into_inner();
+    if !is_enabled(&bucket) {
+        return Err(actix_web::error::ErrorBadRequest("Bucket is missing Object Lock Configuration"));
+    }
+    let version = versioning::target_version(&bucket, &key, &query)?;
+    let body = std::str::from_utf8(&body)
+        .map_err(|_| actix_web::error::ErrorBadRequest("MalformedXML"))?;
+    let retention = match (xml::element_text(body, "Mode"), xml::element_text(body, "RetainUntilDate")) {
+        (Some(mode), Some(until)) => Some(Retention {
+            mode: LockMode::parse(mode)
+                .ok_or_else(|| actix_web::error::ErrorBadRequest("MalformedXML: invalid Mode"))?,
+            retain_until: xml::parse_timestamp(until)
+                .ok_or_else(|| actix_web::error::ErrorBadRequest("MalformedXML: invalid RetainUntilDate"))?,
+        }),
+        (None, None) => None,
+        _ => return Err(actix_web::error::ErrorBadRequest("MalformedXML")),
+    };
+
+    // An active retention may only be made stricter, unless governance is bypassed
+    if let Some(current) = version.retention.filter(|r| r.retain_until > now()) {
+        let stricter = retention.is_some_and(|new| {
+            new.retain_until >= current.retain_until
+                && (new.mode == LockMode::Compliance || current.mode == LockMode::Governance)
+        });
+        let allowed = stricter || (current.mode == LockMode::Governance && bypass_governance(&req)?);
+        if !allowed {
+            return Err(actix_web::error::ErrorForbidden("AccessDenied: retention cannot be weakened"));
+        }
+    }
+
+    VERSION_STORE.update_version(&bucket, &key, &version.version_id, |v| v.retention = retention)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn get_object_retention(
+    path: web::Path<(String, String)>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, Error> {
+    let (bucket, key) = path.into_inner();
+    let version = versioning::target_version(&bucket, &key, &query)?;
+    let retention = version.retention
+        .ok_or_else(|| actix_web::error::ErrorNotFound("NoSuchObjectLockConfiguration"))?;
+    let response = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<Retention xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><Mode>{}</Mode><RetainUntilDate>{}</RetainUntilDate></Retention>"#,
+        retention.mode.as_str(),
+        xml::timestamp(retention.retain_until)
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(response))
+}
+
+pub async fn put_object_legal_hold(
+    path: web::Path<(String, String)>,
+    query: web::Query<HashMap<String, String>>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let (bucket, key) = path.into_inner();
+    if !is_enabled(&bucket) {
+        return Err(actix_web::error::ErrorBadRequest("Bucket is missing Object Lock Configuration"));
+    }
+    let version = versioning::target_version(&bucket, &key, &query)?;
+    let body = std::str::from_utf8(&body)
+        .map_err(|_| actix_web::error::ErrorBadRequest("MalformedXML"))?;
+    let legal_hold = match xml::element_text(body, "Status") {
+        Some("ON") => true,
+        Some("OFF") => false,
+        _ => return Err(actix_web::error::ErrorBadRequest("MalformedXML")),
+    };
+    VERSION_STORE.update_version(&bucket, &key, &version.version_id, |v| v.legal_hold = legal_hold)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn get_object_legal_hold(
+    path: web::Path<(String, String)>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, Error> {
+    let (bucket, key) = path.into_inner();
+    let version = versioning::target_version(&bucket, &key, &query)?;
+    let response = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<LegalHold xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><Status>{}</Status></LegalHold>"#,
+        if version.legal_hold { "ON" } else { "OFF" }
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::router;
+    use actix_web::{test, App};
+    use serial_test::serial;
+    use std::fs;
+
+    fn future_date() -> String {
+        xml::timestamp(now() + SECONDS_PER_DAY)
+    }
+
+    #[actix_rt::test]
+    async fn test_object_lock_requires_versioning() {
+        let bucket = "testbucket_lock_unversioned";
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?object-lock", bucket))
+            .set_payload("<ObjectLockConfiguration><ObjectLockEnabled>Enabled</ObjectLockEnabled></ObjectLockConfiguration>")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 409);
+        assert!(!is_enabled(bucket));
+    }
+
+    #[actix_rt::test]
+    async fn test_retention_and_legal_hold_block_deletion() {
+        let bucket = "testbucket_lock";
+        let _ = fs::remove_dir_all(bucket);
+        VERSION_STORE.set_status(bucket, VersioningStatus::Enabled);
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?object-lock", bucket))
+            .set_payload("<ObjectLockConfiguration><ObjectLockEnabled>Enabled</ObjectLockEnabled>\
+                <Rule><DefaultRetention><Mode>GOVERNANCE</Mode><Days>1</Days></DefaultRetention></Rule>\
+                </ObjectLockConfiguration>")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        // Default retention is applied to new versions
+        let req = test::TestRequest::default().to_http_request();
+        let settings = settings_from_request(&req, bucket).unwrap();
+        assert_eq!(settings.retention.unwrap().mode, LockMode::Governance);
+        let version = versioning::create_version(bucket, "doc", b"v1", "e", HashMap::new(), Vec::new()).unwrap();
+        VERSION_STORE.update_version(bucket, "doc", &version.version_id, |v| v.retention = settings.retention).unwrap();
+
+        // Governance retention can only be bypassed explicitly
+        assert!(versioning::remove_version(bucket, "doc", &version.version_id, false).is_err());
+
+        // Retention can be extended and upgraded to compliance, but not shortened
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}/doc?retention", bucket))
+            .set_payload(format!(
+                "<Retention><Mode>COMPLIANCE</Mode><RetainUntilDate>{}</RetainUntilDate></Retention>",
+                xml::timestamp(now() + 2 * SECONDS_PER_DAY)
+            ))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}/doc?retention", bucket))
+            .insert_header(("x-amz-bypass-governance-retention", "true"))
+            .set_payload(format!(
+                "<Retention><Mode>GOVERNANCE</Mode><RetainUntilDate>{}</RetainUntilDate></Retention>",
+                future_date()
+            ))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+        assert!(versioning::remove_version(bucket, "doc", &version.version_id, true).is_err());
+
+        // Legal hold protects versions without retention
+        let other = versioning::create_version(bucket, "held", b"v1", "e", HashMap::new(), Vec::new()).unwrap();
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}/held?legal-hold", bucket))
+            .set_payload("<LegalHold><Status>ON</Status></LegalHold>")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        assert!(versioning::remove_version(bucket, "held", &other.version_id, true).is_err());
+        VERSION_STORE.update_version(bucket, "held", &other.version_id, |v| v.legal_hold = false).unwrap();
+        assert!(versioning::remove_version(bucket, "held", &other.version_id, false).is_ok());
+
+        // Versioning can no longer be suspended
+        assert!(versioning::set_versioning(bucket, VersioningStatus::Suspended).is_err());
+        OBJECT_LOCK.lock().unwrap().remove(bucket);
+        let _ = fs::remove_dir_all(bucket);
+    }
+
+    #[actix_rt::test]
+    async fn test_copies_do_not_inherit_locks() {
+        let bucket = "testbucket_lock_copy_source";
+        let target = "testbucket_lock_copy_target";
+        let _ = fs::remove_dir_all(bucket);
+        let _ = fs::remove_dir_all(target);
+        VERSION_STORE.set_status(bucket, VersioningStatus::Enabled);
+        let version = versioning::create_version(bucket, "doc", b"v1", "e", HashMap::new(), Vec::new()).unwrap();
+        let retention = Retention { mode: LockMode::Compliance, retain_until: now() + SECONDS_PER_DAY };
+        VERSION_STORE.update_version(bucket, "doc", &version.version_id, |v| {
+            v.retention = Some(retention);
+            v.legal_hold = true;
+        }).unwrap();
+
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}/copy", target))
+            .insert_header(("x-amz-copy-source", format!("/{}/doc", bucket)))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let copy = VERSION_STORE.get_latest_version(target, "copy").unwrap();
+        assert!(copy.retention.is_none());
+        assert!(!copy.legal_hold);
+
+        let req = test::TestRequest::delete().uri(&format!("/{}/copy", target)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 204);
+        assert!(VERSION_STORE.get_latest_version(target, "copy").is_none());
+
+        // Lock settings can't be requested for a bucket without Object Lock
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}/copy", target))
+            .insert_header(("x-amz-copy-source", format!("/{}/doc", bucket)))
+            .insert_header(("x-amz-object-lock-legal-hold", "ON"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+        VERSION_STORE.update_version(bucket, "doc", &version.version_id, |v| {
+            v.retention = None;
+            v.legal_hold = false;
+        }).unwrap();
+        let _ = fs::remove_dir_all(bucket);
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[actix_rt::test]
+    #[serial(config)]
+    async fn test_bypass_governance_requires_an_admin() {
+        let bucket = "testbucket_lock_bypass";
+        let _ = fs::remove_dir_all(bucket);
+        VERSION_STORE.set_status(bucket, VersioningStatus::Enabled);
+        let version = versioning::create_version(bucket, "doc", b"v1", "e", HashMap::new(), Vec::new()).unwrap();
+        let retention = Retention { mode: LockMode::Governance, retain_until: now() + SECONDS_PER_DAY };
+        VERSION_STORE.update_version(bucket, "doc", &version.version_id, |v| v.retention = Some(retention)).unwrap();
+
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?object-lock", bucket))
+            .set_payload("<ObjectLockConfiguration><ObjectLockEnabled>Enabled</ObjectLockEnabled></ObjectLockConfiguration>")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        // Unauthenticated requests cannot bypass governance retention
+        let req = test::TestRequest::delete()
+            .uri(&format!("/{}/doc?versionId={}", bucket, version.version_id))
+            .insert_header(("x-amz-bypass-governance-retention", "true"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}/doc?retention&versionId={}", bucket, version.version_id))
+            .insert_header(("x-amz-bypass-governance-retention", "true"))
+            .set_payload("<Retention></Retention>")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+        assert!(VERSION_STORE.get_version(bucket, "doc", &version.version_id).unwrap().retention.is_some());
+
+        // Admins can
+        let _admin = auth::as_test_admin();
+        let req = test::TestRequest::delete()
+            .uri(&auth::test_admin_uri("DELETE", &format!("/{}/doc?versionId={}", bucket, version.version_id)))
+            .insert_header(("Host", "localhost:8080"))
+            .insert_header(("x-amz-bypass-governance-retention", "true"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 204);
+        assert!(VERSION_STORE.get_version(bucket, "doc", &version.version_id).is_none());
+        OBJECT_LOCK.lock().unwrap().remove(bucket);
+        let _ = fs::remove_dir_all(bucket);
+    }
+}
@@ -0,0 +1,468 @@
+use actix_multipart::Multipart;
+use actix_web::{web, HttpRequest, HttpResponse, Error};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use bytes::Bytes;
+use futures_util::StreamExt;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::controller::{bucket, encryption, object, object_lock, tagging, versioning};
+use crate::config;
+use crate::sigv4;
+use crate::xml;
+
+// Browser-based uploads (POST Object): a multipart/form-data form carrying
+// the key, a signed policy document and the file
+
+// Form fields other than the file may not exceed this size in total
+const MAX_FIELDS_SIZE: usize = 20 * 1024;
+
+// Fields that policy conditions don't need to mention
+const UNCHECKED_FIELDS: [&str; 2] = ["policy", "x-amz-signature"];
+
+// Form fields (lowercased names) up to the file, and the file itself
+struct Form {
+    fields: HashMap<String, String>,
+    filename: String,
+    file: Bytes,
+}
+
+impl Form {
+    fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(String::as_str)
+    }
+}
+
+// Read fields until the `file` field; anything after the file is ignored
+async fn read_form(mut payload: Multipart) -> Result<Form, Error> {
+    let mut fields = HashMap::new();
+    let mut size = 0;
+    while let Some(field) = payload.next().await {
+        let mut field = field?;
+        let name = field.name().unwrap_or_default().to_ascii_lowercase();
+        let filename = field.content_disposition()
+            .and_then(|disposition| disposition.get_filename())
+            .unwrap_or_default()
+            .to_string();
+        let mut value = Vec::new();
+        while let Some(chunk) = field.next().await {
+            value.extend_from_slice(&chunk?);
+            if name != "file" && size + value.len() > MAX_FIELDS_SIZE {
+                return Err(actix_web::error::ErrorBadRequest("MaxPostPreDataLengthExceeded"));
+            }
+            if name == "file" && value.len() as u64 > config::current().max_object_size {
+                return Err(object::entity_too_large());
+            }
+        }
+        if name == "file" {
+            return Ok(Form { fields, filename, file: Bytes::from(value) });
+        }
+        size += value.len();
+        let value = String::from_utf8(value)
+            .map_err(|_| actix_web::error::ErrorBadRequest(format!("InvalidArgument: invalid form field {}", name)))?;
+        fields.insert(name, value);
+    }
+    Err(actix_web::error::ErrorBadRequest("InvalidArgument: POST requires exactly one file upload per request"))
+}
+
+fn invalid_policy(message: &str) -> Error {
+    actix_web::error::ErrorBadRequest(format!("InvalidPolicyDocument: {}", message))
+}
+
+fn policy_failed(message: &str) -> Error {
+    actix_web::error::ErrorForbidden(format!("AccessDenied: Invalid according to Policy: {}", message))
+}
+
+// Check a form against the policy's expiration and conditions. Every form
+// field has to be allowed by some condition.
+fn check_policy(policy: &Value, bucket: &str, form: &Form, now: u64) -> Result<(), Error> {
+    let expiration = policy["expiration"].as_str()
+        .and_then(xml::parse_timestamp)
+        .ok_or_else(|| invalid_policy("missing or invalid expiration"))?;
+    if expiration <= now {
+        return Err(policy_failed("Policy expired"));
+    }
+    let conditions = policy["conditions"].as_array()
+        .ok_or_else(|| invalid_policy("missing conditions"))?;
+    let value = |field: &str| if field == "bucket" { bucket } else { form.field(field).unwrap_or_default() };
+    let mut covered = HashSet::new();
+    for condition in conditions {
+        match condition {
+            // {"field": "value"} is an exact match
+            Value::Object(matches) => {
+                for (field, expected) in matches {
+                    let field = field.to_ascii_lowercase();
+                    let expected = expected.as_str().ok_or_else(|| invalid_policy("condition values must be strings"))?;
+                    if value(&field) != expected {
+                        return Err(policy_failed(&format!("Policy Condition failed: [\"eq\", \"${}\", \"{}\"]", field, expected)));
+                    }
+                    covered.insert(field);
+                }
+            }
+            Value::Array(items) => match items.as_slice() {
+                [operator, min, max] if operator == "content-length-range" => {
+                    let (min, max) = min.as_u64().zip(max.as_u64())
+                        .ok_or_else(|| invalid_policy("invalid content-length-range"))?;
+                    let size = form.file.len() as u64;
+                    if size < min {
+                        return Err(actix_web::error::ErrorBadRequest("EntityTooSmall"));
+                    }
+                    if size > max {
+                        return Err(actix_web::error::ErrorBadRequest("EntityTooLarge"));
+                    }
+                }
+                [operator, field, expected] => {
+                    let field = field.as_str()
+                        .and_then(|field| field.strip_prefix('$'))
+                        .ok_or_else(|| invalid_policy("condition fields must start with $"))?
+                        .to_ascii_lowercase();
+                    let expected = expected.as_str().ok_or_else(|| invalid_policy("condition values must be strings"))?;
+                    let actual = value(&field);
+                    let holds = match operator.as_str() {
+                        Some("eq") => actual == expected,
+                        // Each of several comma-separated content types has to match
+                        Some("starts-with") if field == "content-type" => {
+                            actual.split(',').all(|v| v.trim().starts_with(expected))
+                        }
+                        Some("starts-with") => actual.starts_with(expected),
+                        _ => return Err(invalid_policy("unknown condition operator")),
+                    };
+                    if !holds {
+                        return Err(policy_failed(&format!(
+                            "Policy Condition failed: [{}, \"${}\", \"{}\"]", operator, field, expected
+                        )));
+                    }
+                    covered.insert(field);
+                }
+                _ => return Err(invalid_policy("invalid condition")),
+            },
+            _ => return Err(invalid_policy("invalid condition")),
+        }
+    }
+    for field in form.fields.keys() {
+        if !UNCHECKED_FIELDS.contains(&field.as_str()) && !field.starts_with("x-ignore-") && !covered.contains(field) {
+            return Err(policy_failed(&format!("Extra input fields: {}", field)));
+        }
+    }
+    Ok(())
+}
+
+pub async fn post_object(req: HttpRequest, path: web::Path<String>, payload: Multipart) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    let mut form = read_form(payload).await?;
+    let key = form.field("key")
+        .filter(|key| !key.is_empty())
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("InvalidArgument: key is required"))?
+        .replace("${filename}", &form.filename);
+    form.fields.insert("key".to_string(), key.clone());
+
+    // The policy is signed like a SigV4 string to sign
+    let policy = form.field("policy")
+        .ok_or_else(|| actix_web::error::ErrorForbidden("AccessDenied: POST uploads require a signed policy"))?;
+    if form.field("x-amz-algorithm") != Some(sigv4::ALGORITHM) {
+        return Err(actix_web::error::ErrorBadRequest("InvalidArgument: x-amz-algorithm must be AWS4-HMAC-SHA256"));
+    }
+    sigv4::verify_policy(
+        form.field("x-amz-credential").unwrap_or_default(),
+        form.field("x-amz-date").unwrap_or_default(),
+        policy,
+        form.field("x-amz-signature").unwrap_or_default(),
+    )?;
+    let policy: Value = BASE64.decode(policy).ok()
+        .and_then(|policy| serde_json::from_slice(&policy).ok())
+        .ok_or_else(|| invalid_policy("policy is not base64-encoded JSON"))?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    check_policy(&policy, &bucket, &form, now)?;
+    let tags = form.field("tagging").map(tagging::parse_object_tagging).transpose()?.unwrap_or_default();
+    if bucket::policy_denies(&bucket, "s3:PutObject", &tagging::request_condition_values(&tags)) {
+        return Err(actix_web::error::ErrorForbidden("AccessDenied"));
+    }
+
+    let metadata: HashMap<String, String> = form.fields.iter()
+        .filter(|(name, _)| object::STORED_HEADERS.contains(&name.as_str()) || name.starts_with("x-amz-meta-"))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    // Object Lock settings and encryption come from the form's
+    // `x-amz-object-lock-*` and `x-amz-server-side-encryption*` fields
+    let lock = object_lock::settings_from(|name| form.field(name), &bucket)?;
+    let sse = encryption::requested_from(|name| form.field(&name.to_ascii_lowercase()), &bucket)?;
+    let etag = format!("{:x}", md5::compute(&form.file));
+    let version = versioning::create_version_from(&form.file, sse.as_ref(), versioning::VersionInfo {
+        bucket: bucket.clone(),
+        key: key.clone(),
+        etag: etag.clone(),
+        metadata,
+        tags,
+        retention: lock.retention,
+        legal_hold: lock.legal_hold,
+        ..Default::default()
+    })?;
+
+    // Respond with a redirect or the requested status
+    let mut response = match (form.field("success_action_redirect"), form.field("success_action_status")) {
+        (Some(redirect), _) if !redirect.is_empty() => {
+            let separator = if redirect.contains('?') { '&' } else { '?' };
+            let location = format!(
+                "{}{}bucket={}&key={}&etag={}",
+                redirect,
+                separator,
+                sigv4::uri_encode(&bucket, true),
+                sigv4::uri_encode(&key, true),
+                sigv4::uri_encode(&format!("\"{}\"", etag), true)
+            );
+            let mut response = HttpResponse::SeeOther();
+            response.insert_header(("Location", location));
+            response
+        }
+        (_, Some("200")) => HttpResponse::Ok(),
+        (_, Some("201")) => HttpResponse::Created(),
+        _ => HttpResponse::NoContent(),
+    };
+    response.insert_header(("ETag", etag.clone()));
+    let customer_key = sse.as_ref().and_then(|sse| sse.customer_key());
+    for header in encryption::response_headers(&version, customer_key) {
+        response.insert_header(header);
+    }
+    if versioning::VERSION_STORE.get_status(&bucket).is_versioned() {
+        response.insert_header(("x-amz-version-id", version.version_id));
+    }
+    if form.field("success_action_status") != Some("201") || form.field("success_action_redirect").is_some_and(|r| !r.is_empty()) {
+        return Ok(response.finish());
+    }
+    let connection = req.connection_info();
+    let location = format!(
+        "{}://{}/{}/{}",
+        connection.scheme(),
+        connection.host(),
+        sigv4::uri_encode(&bucket, true),
+        sigv4::uri_encode(&key, false)
+    );
+    response.insert_header(("Location", location.clone()));
+    Ok(response.content_type("application/xml").body(format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<PostResponse><Location>{}</Location><Bucket>{}</Bucket><Key>{}</Key><ETag>"{}"</ETag></PostResponse>"#,
+        xml::escape(&location),
+        xml::escape(&bucket),
+        xml::escape(&key),
+        etag
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::router;
+    use crate::controller::auth;
+    use actix_web::{test, App};
+    use std::fs;
+
+    const BOUNDARY: &str = "r3formboundary";
+    const ACCESS_KEY_ID: &str = "R3POSTOBJECTTESTKEY0";
+    const SECRET: &str = "post-object-secret";
+
+    // A signed form for `conditions`, with `fields` and a file
+    fn form_body(conditions: Value, fields: &[(&str, &str)], filename: &str, file: &[u8]) -> Vec<u8> {
+        auth::add_access_key(auth::AccessKey {
+            access_key_id: ACCESS_KEY_ID.to_string(),
+            secret_access_key: SECRET.to_string(),
+            username: "poster".to_string(),
+        }).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let timestamp = chrono::DateTime::from_timestamp(now as i64, 0).unwrap().format("%Y%m%dT%H%M%SZ").to_string();
+        let credential = format!("{}/{}/us-east-1/s3/aws4_request", ACCESS_KEY_ID, &timestamp[..8]);
+        let mut conditions = conditions.as_array().unwrap().clone();
+        conditions.push(serde_json::json!({ "x-amz-algorithm": sigv4::ALGORITHM }));
+        conditions.push(serde_json::json!({ "x-amz-credential": credential }));
+        conditions.push(serde_json::json!({ "x-amz-date": timestamp }));
+        let policy = BASE64.encode(serde_json::json!({
+            "expiration": xml::timestamp(now + 600),
+            "conditions": conditions,
+        }).to_string());
+        let signature = sigv4::SigningContext::new(&sigv4::Credential::parse(&credential).unwrap(), SECRET, &timestamp)
+            .sign(&policy);
+
+        let mut all_fields: Vec<(&str, &str)> = fields.to_vec();
+        all_fields.extend([
+            ("policy", policy.as_str()),
+            ("x-amz-algorithm", sigv4::ALGORITHM),
+            ("x-amz-credential", credential.as_str()),
+            ("x-amz-date", timestamp.as_str()),
+            ("x-amz-signature", signature.as_str()),
+        ]);
+        let mut body = Vec::new();
+        for (name, value) in all_fields {
+            body.extend_from_slice(format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n", BOUNDARY, name, value
+            ).as_bytes());
+        }
+        body.extend_from_slice(format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n", BOUNDARY, filename
+        ).as_bytes());
+        body.extend_from_slice(file);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+        body
+    }
+
+    fn post(bucket: &str, body: Vec<u8>) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri(&format!("/{}", bucket))
+            .insert_header(("Content-Type", format!("multipart/form-data; boundary={}", BOUNDARY)))
+            .set_payload(body)
+    }
+
+    #[actix_rt::test]
+    async fn test_post_object_with_policy() {
+        let bucket = "testbucket_post_object";
+        let _ = fs::remove_dir_all(bucket);
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let conditions = serde_json::json!([
+            { "bucket": bucket },
+            ["starts-with", "$key", "uploads/"],
+            ["starts-with", "$Content-Type", "image/"],
+            { "success_action_status": "201" },
+            ["content-length-range", 1, 1024],
+        ]);
+        let fields = [
+            ("key", "uploads/${filename}"),
+            ("Content-Type", "image/png"),
+            ("success_action_status", "201"),
+        ];
+        let resp = test::call_service(&app, post(bucket, form_body(conditions.clone(), &fields, "cat.png", b"png data")).to_request()).await;
+        assert_eq!(resp.status(), 201);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("<Key>uploads/cat.png</Key>"));
+        let version = versioning::VERSION_STORE.get_latest_version(bucket, "uploads/cat.png").unwrap();
+        assert_eq!(versioning::read_version(&version).unwrap(), b"png data");
+        assert_eq!(version.metadata.get("content-type").map(String::as_str), Some("image/png"));
+
+        // Outside the key prefix, too large, or with a field the policy doesn't allow
+        let fields = [("key", "other/${filename}"), ("Content-Type", "image/png"), ("success_action_status", "201")];
+        let resp = test::call_service(&app, post(bucket, form_body(conditions.clone(), &fields, "cat.png", b"png")).to_request()).await;
+        assert_eq!(resp.status(), 403);
+        let fields = [("key", "uploads/big"), ("Content-Type", "image/png"), ("success_action_status", "201")];
+        let resp = test::call_service(&app, post(bucket, form_body(conditions.clone(), &fields, "big", &[0; 2048])).to_request()).await;
+        assert_eq!(resp.status(), 400);
+        let fields = [("key", "uploads/x"), ("Content-Type", "image/png"), ("success_action_status", "201"), ("acl", "public-read")];
+        let resp = test::call_service(&app, post(bucket, form_body(conditions, &fields, "x", b"x")).to_request()).await;
+        assert_eq!(resp.status(), 403);
+        let _ = fs::remove_dir_all(bucket);
+    }
+
+    #[actix_rt::test]
+    async fn test_post_object_signature_and_redirect() {
+        let bucket = "testbucket_post_redirect";
+        let _ = fs::remove_dir_all(bucket);
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let conditions = serde_json::json!([
+            { "bucket": bucket },
+            { "key": "doc.txt" },
+            { "success_action_redirect": "https://example.com/done" },
+        ]);
+        let fields = [("key", "doc.txt"), ("success_action_redirect", "https://example.com/done")];
+        let body = form_body(conditions.clone(), &fields, "doc.txt", b"hello");
+        let resp = test::call_service(&app, post(bucket, body).to_request()).await;
+        assert_eq!(resp.status(), 303);
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        assert!(location.starts_with("https://example.com/done?bucket=testbucket_post_redirect&key=doc.txt&etag="));
+
+        // Tampering with the signed policy is detected
+        let body = String::from_utf8(form_body(conditions, &fields, "doc.txt", b"hello")).unwrap();
+        let signature = body.split("name=\"x-amz-signature\"\r\n\r\n").nth(1).unwrap()[..64].to_string();
+        let tampered = body.replace(&signature, &"0".repeat(64));
+        let resp = test::call_service(&app, post(bucket, tampered.into_bytes()).to_request()).await;
+        assert_eq!(resp.status(), 403);
+        let _ = fs::remove_dir_all(bucket);
+    }
+
+    #[actix_rt::test]
+    async fn test_post_object_policy_and_encryption() {
+        let bucket = "testbucket_post_encryption";
+        let _ = fs::remove_dir_all(bucket);
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let conditions = serde_json::json!([
+            { "bucket": bucket },
+            { "key": "secret.txt" },
+            { "x-amz-server-side-encryption": "AES256" },
+        ]);
+        let fields = [("key", "secret.txt"), ("x-amz-server-side-encryption", "AES256")];
+        let resp = test::call_service(&app, post(bucket, form_body(conditions.clone(), &fields, "secret.txt", b"hush")).to_request()).await;
+        assert_eq!(resp.status(), 204);
+        assert_eq!(resp.headers().get("x-amz-server-side-encryption").unwrap(), "AES256");
+        let version = versioning::VERSION_STORE.get_latest_version(bucket, "secret.txt").unwrap();
+        assert!(version.encryption.is_some());
+        assert_eq!(versioning::read_version(&version).unwrap(), b"hush");
+
+        // A bucket policy denying PutObject applies to browser uploads too
+        let resp = test::TestRequest::put()
+            .uri(&format!("/{}?policy", bucket))
+            .set_json(serde_json::json!({
+                "policy": r#"{"Statement": [{"Effect": "Deny", "Action": "s3:PutObject"}]}"#,
+                "acl": "",
+            }))
+            .send_request(&app).await;
+        assert!(resp.status().is_success());
+        let resp = test::call_service(&app, post(bucket, form_body(conditions, &fields, "secret.txt", b"again")).to_request()).await;
+        assert_eq!(resp.status(), 403);
+        let resp = test::TestRequest::put()
+            .uri(&format!("/{}/other.txt", bucket))
+            .set_payload("x")
+            .send_request(&app).await;
+        assert_eq!(resp.status(), 403);
+        let resp = test::TestRequest::put()
+            .uri(&format!("/{}?policy", bucket))
+            .set_json(serde_json::json!({ "policy": "{}", "acl": "" }))
+            .send_request(&app).await;
+        assert!(resp.status().is_success());
+        let _ = fs::remove_dir_all(bucket);
+    }
+
+    #[actix_rt::test]
+    async fn test_post_object_lock_and_tagging_fields() {
+        let bucket = "testbucket_post_lock";
+        let _ = fs::remove_dir_all(bucket);
+        versioning::VERSION_STORE.set_status(bucket, versioning::VersioningStatus::Enabled);
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let tagging = "<Tagging><TagSet><Tag><Key>team</Key><Value>a&amp;b</Value></Tag></TagSet></Tagging>";
+        let conditions = serde_json::json!([
+            { "bucket": bucket },
+            { "key": "held.txt" },
+            { "x-amz-object-lock-legal-hold": "ON" },
+            { "tagging": tagging },
+        ]);
+        let fields = [("key", "held.txt"), ("x-amz-object-lock-legal-hold", "ON"), ("tagging", tagging)];
+
+        // Lock settings need a bucket with Object Lock
+        let resp = test::call_service(&app, post(bucket, form_body(conditions.clone(), &fields, "held.txt", b"keep")).to_request()).await;
+        assert_eq!(resp.status(), 400);
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?object-lock", bucket))
+            .set_payload("<ObjectLockConfiguration><ObjectLockEnabled>Enabled</ObjectLockEnabled></ObjectLockConfiguration>")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        let resp = test::call_service(&app, post(bucket, form_body(conditions.clone(), &fields, "held.txt", b"keep")).to_request()).await;
+        assert_eq!(resp.status(), 204);
+        let version = versioning::VERSION_STORE.get_latest_version(bucket, "held.txt").unwrap();
+        assert!(version.legal_hold);
+        assert_eq!(version.tags.get("team").map(String::as_str), Some("a&b"));
+
+        // The fields have to be allowed by the policy, and the tags have to parse
+        let resp = test::call_service(&app, post(bucket, form_body(serde_json::json!([
+            { "bucket": bucket },
+            { "key": "held.txt" },
+        ]), &fields, "held.txt", b"keep")).to_request()).await;
+        assert_eq!(resp.status(), 403);
+        let fields = [("key", "held.txt"), ("tagging", "team=core")];
+        let resp = test::call_service(&app, post(bucket, form_body(serde_json::json!([
+            { "bucket": bucket },
+            { "key": "held.txt" },
+            { "tagging": "team=core" },
+        ]), &fields, "held.txt", b"keep")).to_request()).await;
+        assert_eq!(resp.status(), 400);
+
+        versioning::VERSION_STORE.update_version(bucket, "held.txt", &version.version_id, |v| v.legal_hold = false).unwrap();
+        let _ = fs::remove_dir_all(bucket);
+    }
+}
@@ -0,0 +1,198 @@
+use actix_web::guard::{Guard, GuardContext};
+use actix_web::http::Method;
+use actix_web::{web, HttpRequest, HttpResponse};
+use crate::controller::{
+    access_log, bucket, checksum, cors, encryption, lifecycle, multipart, object, object_lock, post_object, tagging,
+    versioning, website,
+};
+
+// S3 routes on `/{bucket}` and `/{bucket}/{key}`, dispatched by method and
+// subresource query key (`?versioning`, `?uploadId=...`). actix matches paths
+// only, so each subresource is a guarded route; the first matching route
+// wins, and subresources without a handler get NotImplemented.
+
+// Query keys that select a subresource or operation rather than modify a
+// request. `versionId`, `partNumber` and listing parameters are not among
+// them, except `list-type`, which selects ListObjectsV2.
+pub const SUBRESOURCES: [&str; 32] = [
+    "accelerate", "acl", "analytics", "attributes", "cors", "delete", "encryption",
+    "intelligent-tiering", "inventory", "legal-hold", "lifecycle", "list-type", "location", "logging",
+    "metrics", "notification", "object-lock", "ownershipControls", "policy", "policyStatus",
+    "publicAccessBlock", "replication", "requestPayment", "restore", "retention", "select",
+    "tagging", "torrent", "uploadId", "uploads", "versioning", "versions",
+];
+
+// Bucket names taken by the paths of the admin, metrics and account routes,
+// which are matched before the S3 ones
+pub const RESERVED_BUCKETS: [&str; 5] = ["access-keys", "admin", "login", "metrics", "register"];
+
+pub fn is_reserved_bucket(bucket: &str) -> bool {
+    RESERVED_BUCKETS.contains(&bucket)
+}
+
+pub fn query_has_key(query: &str, name: &str) -> bool {
+    query.split('&').any(|param| param.split('=').next() == Some(name))
+}
+
+fn has_query_key(ctx: &GuardContext, name: &str) -> bool {
+    query_has_key(ctx.head().uri.query().unwrap_or_default(), name)
+}
+
+// Matches requests with the `name` query key
+struct Subresource(&'static str);
+
+impl Guard for Subresource {
+    fn check(&self, ctx: &GuardContext) -> bool {
+        has_query_key(ctx, self.0)
+    }
+}
+
+// Matches requests naming any subresource, for those without a handler
+struct AnySubresource;
+
+impl Guard for AnySubresource {
+    fn check(&self, ctx: &GuardContext) -> bool {
+        SUBRESOURCES.iter().any(|name| has_query_key(ctx, name))
+    }
+}
+
+// Matches requests for a reserved bucket name
+struct ReservedBucket;
+
+impl Guard for ReservedBucket {
+    fn check(&self, ctx: &GuardContext) -> bool {
+        let path = ctx.head().uri.path().trim_start_matches('/');
+        is_reserved_bucket(path.split('/').next().unwrap_or_default())
+    }
+}
+
+async fn reserved_bucket(req: HttpRequest) -> HttpResponse {
+    let bucket = req.match_info().get("bucket").unwrap_or_default();
+    HttpResponse::BadRequest().body(format!("InvalidBucketName: {} is reserved", bucket))
+}
+
+async fn not_implemented(req: HttpRequest) -> HttpResponse {
+    HttpResponse::NotImplemented().body(format!(
+        "NotImplemented: {} {}{} is not supported",
+        req.method(),
+        req.path(),
+        req.uri().query().map(|query| format!("?{}", query)).unwrap_or_default()
+    ))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    let sub = Subresource;
+    cfg.service(web::resource("/{bucket}")
+        .route(web::route().guard(ReservedBucket).to(reserved_bucket))
+        .route(web::method(Method::OPTIONS).to(cors::preflight_bucket))
+        .route(web::get().guard(sub("versioning")).to(versioning::get_bucket_versioning))
+        .route(web::put().guard(sub("versioning")).to(versioning::put_bucket_versioning))
+        .route(web::get().guard(sub("versions")).to(versioning::list_object_versions))
+        .route(web::get().guard(sub("policy")).to(bucket::get_bucket_policy))
+        .route(web::put().guard(sub("policy")).to(bucket::set_bucket_policy))
+        .route(web::get().guard(sub("lifecycle")).to(lifecycle::get_bucket_lifecycle))
+        .route(web::put().guard(sub("lifecycle")).to(lifecycle::put_bucket_lifecycle))
+        .route(web::delete().guard(sub("lifecycle")).to(lifecycle::delete_bucket_lifecycle))
+        .route(web::get().guard(sub("object-lock")).to(object_lock::get_object_lock_configuration))
+        .route(web::put().guard(sub("object-lock")).to(object_lock::put_object_lock_configuration))
+        .route(web::get().guard(sub("tagging")).to(tagging::get_bucket_tagging))
+        .route(web::put().guard(sub("tagging")).to(tagging::put_bucket_tagging))
+        .route(web::delete().guard(sub("tagging")).to(tagging::delete_bucket_tagging))
+        .route(web::get().guard(sub("encryption")).to(encryption::get_bucket_encryption))
+        .route(web::put().guard(sub("encryption")).to(encryption::put_bucket_encryption))
+        .route(web::delete().guard(sub("encryption")).to(encryption::delete_bucket_encryption))
+        .route(web::get().guard(sub("cors")).to(cors::get_bucket_cors))
+        .route(web::put().guard(sub("cors")).to(cors::put_bucket_cors))
+        .route(web::delete().guard(sub("cors")).to(cors::delete_bucket_cors))
+        .route(web::get().guard(sub("website")).to(website::get_bucket_website))
+        .route(web::put().guard(sub("website")).to(website::put_bucket_website))
+        .route(web::delete().guard(sub("website")).to(website::delete_bucket_website))
+        .route(web::get().guard(sub("logging")).to(access_log::get_bucket_logging))
+        .route(web::put().guard(sub("logging")).to(access_log::put_bucket_logging))
+        .route(web::route().guard(AnySubresource).to(not_implemented))
+        .route(web::get().to(bucket::read_bucket))
+        .route(web::head().to(bucket::read_bucket))
+        .route(web::delete().to(bucket::delete_bucket))
+        .route(web::post().to(post_object::post_object))
+        .default_service(web::to(not_implemented)));
+
+    cfg.service(web::resource("/{bucket}/{key:.*}")
+        .route(web::route().guard(ReservedBucket).to(reserved_bucket))
+        .route(web::method(Method::OPTIONS).to(cors::preflight_object))
+        .route(web::get().guard(sub("tagging")).to(tagging::get_object_tagging))
+        .route(web::put().guard(sub("tagging")).to(tagging::put_object_tagging))
+        .route(web::delete().guard(sub("tagging")).to(tagging::delete_object_tagging))
+        .route(web::get().guard(sub("retention")).to(object_lock::get_object_retention))
+        .route(web::put().guard(sub("retention")).to(object_lock::put_object_retention))
+        .route(web::get().guard(sub("legal-hold")).to(object_lock::get_object_legal_hold))
+        .route(web::put().guard(sub("legal-hold")).to(object_lock::put_object_legal_hold))
+        .route(web::get().guard(sub("attributes")).to(checksum::get_object_attributes))
+        .route(web::post().guard(sub("uploads")).to(multipart::initiate_multipart_upload))
+        .route(web::put().guard(sub("uploadId")).to(multipart::upload_part))
+        .route(web::post().guard(sub("uploadId")).to(multipart::complete_multipart_upload))
+        .route(web::delete().guard(sub("uploadId")).to(multipart::abort_multipart_upload))
+        .route(web::route().guard(AnySubresource).to(not_implemented))
+        .route(web::get().guard(sub("versionId")).to(versioning::get_object_version))
+        .route(web::head().guard(sub("versionId")).to(versioning::get_object_version))
+        .route(web::delete().guard(sub("versionId")).to(versioning::delete_object_version))
+        .route(web::get().to(object::read_object))
+        .route(web::head().to(object::read_object))
+        .route(web::put().to(object::create_object))
+        .route(web::delete().to(object::delete_object))
+        .default_service(web::to(not_implemented)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    #[actix_rt::test]
+    async fn test_subresource_dispatch() {
+        let bucket = "testbucket_router";
+        let _ = std::fs::remove_dir_all(bucket);
+        let app = test::init_service(App::new().configure(configure)).await;
+
+        // Bucket subresources reach their own handlers rather than the listing
+        let req = test::TestRequest::get().uri(&format!("/{}?versioning", bucket)).to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert!(String::from_utf8(body.to_vec()).unwrap().contains("<VersioningConfiguration"));
+        let req = test::TestRequest::get().uri(&format!("/{}?versions", bucket)).to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert!(String::from_utf8(body.to_vec()).unwrap().contains("<ListVersionsResult"));
+        let req = test::TestRequest::get().uri(&format!("/{}?cors", bucket)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 404);
+
+        // Keys may contain slashes; subresources on them are dispatched too
+        let req = test::TestRequest::put().uri(&format!("/{}/a/b.txt", bucket)).set_payload("nested").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::get().uri(&format!("/{}/a/b.txt", bucket)).to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "nested");
+        let req = test::TestRequest::get().uri(&format!("/{}/a/b.txt?tagging", bucket)).to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert!(String::from_utf8(body.to_vec()).unwrap().contains("<Tagging"));
+
+        // Unsupported subresources and methods
+        for (method, uri) in [
+            (Method::GET, format!("/{}?acl", bucket)),
+            (Method::GET, format!("/{}?list-type=2", bucket)),
+            (Method::PUT, format!("/{}/a/b.txt?acl", bucket)),
+            (Method::POST, format!("/{}/a/b.txt?restore", bucket)),
+            (Method::PATCH, format!("/{}/a/b.txt", bucket)),
+        ] {
+            let req = test::TestRequest::default().method(method).uri(&uri).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 501, "{}", uri);
+        }
+        let _ = std::fs::remove_dir_all(bucket);
+    }
+
+    #[actix_rt::test]
+    async fn test_reserved_bucket_names() {
+        let app = test::init_service(App::new().configure(configure)).await;
+        for uri in ["/admin/notes.txt", "/metrics", "/register/form.html"] {
+            let req = test::TestRequest::put().uri(uri).set_payload("x").to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 400, "{}", uri);
+        }
+        assert!(!std::path::Path::new("admin").exists());
+    }
+}
@@ -0,0 +1,393 @@
+use actix_web::{web, HttpRequest, HttpResponse, Responder, Error, get, post, delete};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use lazy_static::lazy_static;
+use crate::blob::BLOB_STORE;
+use crate::config;
+use crate::controller::admin::parse_timestamp;
+use crate::controller::auth;
+use crate::controller::versioning::{self, VersionInfo, VERSION_STORE};
+
+const SNAPSHOTS_FILE: &str = "bucket_snapshots.json";
+
+// A named, point-in-time view of a bucket: the version of every key that
+// existed at `timestamp`. Referenced versions are pinned and cannot be
+// deleted while the snapshot exists, and the snapshot holds its own
+// reference to their data, so it stays readable even when a write replaces
+// a pinned null version.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
+    pub bucket: String,
+    pub timestamp: u64,
+    pub versions: BTreeMap<String, VersionInfo>, // key -> version
+}
+
+impl Snapshot {
+    fn version_ids(&self) -> BTreeMap<&str, &str> {
+        self.versions.iter()
+            .map(|(key, version)| (key.as_str(), version.version_id.as_str()))
+            .collect()
+    }
+
+    fn retain_blobs(&self) {
+        for version in self.versions.values().filter(|v| !v.blob.is_empty()) {
+            BLOB_STORE.retain(&self.bucket, &version.blob);
+        }
+    }
+
+    fn release_blobs(&self) {
+        for version in self.versions.values().filter(|v| !v.blob.is_empty()) {
+            BLOB_STORE.release(&self.bucket, &version.blob);
+        }
+    }
+}
+
+pub struct SnapshotStore {
+    snapshots: Mutex<HashMap<String, BTreeMap<String, Snapshot>>>, // bucket -> name -> snapshot
+}
+
+impl SnapshotStore {
+    pub fn new() -> Self {
+        SnapshotStore {
+            snapshots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, bucket: &str, name: &str) -> Option<Snapshot> {
+        self.snapshots.lock().unwrap()
+            .get(bucket)
+            .and_then(|snapshots| snapshots.get(name))
+            .cloned()
+    }
+
+    pub fn list(&self, bucket: &str) -> Vec<Snapshot> {
+        self.snapshots.lock().unwrap()
+            .get(bucket)
+            .map(|snapshots| snapshots.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // Record a snapshot, failing if the name is already taken
+    pub fn insert(&self, snapshot: Snapshot) -> std::io::Result<bool> {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        let bucket_snapshots = snapshots.entry(snapshot.bucket.clone()).or_default();
+        if bucket_snapshots.contains_key(&snapshot.name) {
+            return Ok(false);
+        }
+        snapshot.retain_blobs();
+        bucket_snapshots.insert(snapshot.name.clone(), snapshot);
+        save_snapshots(&snapshots)?;
+        Ok(true)
+    }
+
+    pub fn remove(&self, bucket: &str, name: &str) -> std::io::Result<Option<Snapshot>> {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        let removed = snapshots.get_mut(bucket).and_then(|snapshots| snapshots.remove(name));
+        if let Some(snapshot) = &removed {
+            snapshot.release_blobs();
+            save_snapshots(&snapshots)?;
+        }
+        Ok(removed)
+    }
+
+    // Name of a snapshot that references the given version, if any
+    pub fn pinned_by(&self, version: &VersionInfo) -> Option<String> {
+        self.snapshots.lock().unwrap()
+            .get(&version.bucket)?
+            .values()
+            .find(|snapshot| snapshot.versions.get(&version.key).is_some_and(|v| {
+                v.version_id == version.version_id && v.last_modified == version.last_modified
+            }))
+            .map(|snapshot| snapshot.name.clone())
+    }
+}
+
+lazy_static! {
+    pub static ref SNAPSHOT_STORE: SnapshotStore = SnapshotStore::new();
+}
+
+// Read the snapshots of every bucket, taking a blob reference for each
+// version they hold
+pub fn load_snapshots() -> std::io::Result<()> {
+    if config::metadata_path(SNAPSHOTS_FILE).exists() {
+        let mut file = File::open(config::metadata_path(SNAPSHOTS_FILE))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let snapshots: HashMap<String, BTreeMap<String, Snapshot>> = serde_json::from_str(&contents)?;
+        for snapshot in snapshots.values().flat_map(|snapshots| snapshots.values()) {
+            snapshot.retain_blobs();
+        }
+        *SNAPSHOT_STORE.snapshots.lock().unwrap() = snapshots;
+    }
+    Ok(())
+}
+
+fn save_snapshots(snapshots: &HashMap<String, BTreeMap<String, Snapshot>>) -> std::io::Result<()> {
+    let contents = serde_json::to_string(snapshots)?;
+    let mut file = File::create(config::metadata_path(SNAPSHOTS_FILE))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateSnapshotRequest {
+    pub bucket: String,
+    pub name: String,
+    // RFC 3339 timestamp; defaults to now
+    pub timestamp: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SnapshotObject {
+    key: String,
+    version_id: String,
+    size: u64,
+    etag: String,
+    last_modified: u64,
+}
+
+#[derive(Serialize)]
+struct SnapshotListing {
+    name: String,
+    bucket: String,
+    timestamp: u64,
+    objects: Vec<SnapshotObject>,
+}
+
+// Capture the state of a bucket at a point in time
+pub fn create_snapshot(bucket: &str, name: &str, timestamp: u64) -> Result<Snapshot, Error> {
+    if !VERSION_STORE.get_status(bucket).is_versioned() {
+        return Err(actix_web::error::ErrorBadRequest("Bucket versioning is not enabled"));
+    }
+    let versions = versioning::versions_at(bucket, "", timestamp).into_iter()
+        .filter_map(|(_, at_time)| at_time)
+        .filter(|v| !v.is_delete_marker)
+        .map(|v| (v.key.clone(), v))
+        .collect();
+    let snapshot = Snapshot {
+        name: name.to_string(),
+        bucket: bucket.to_string(),
+        timestamp,
+        versions,
+    };
+    if !SNAPSHOT_STORE.insert(snapshot.clone()).map_err(actix_web::error::ErrorInternalServerError)? {
+        return Err(actix_web::error::ErrorConflict("Snapshot already exists"));
+    }
+    Ok(snapshot)
+}
+
+// Version of `key` as recorded in a snapshot
+pub fn snapshot_version(bucket: &str, name: &str, key: &str) -> Result<VersionInfo, Error> {
+    let snapshot = SNAPSHOT_STORE.get(bucket, name)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Snapshot not found"))?;
+    snapshot.versions.get(key)
+        .cloned()
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Object not found in snapshot"))
+}
+
+#[post("/admin/snapshots")]
+pub async fn post_snapshot(req: HttpRequest, request: web::Json<CreateSnapshotRequest>) -> Result<impl Responder, Error> {
+    auth::require_admin(&req)?;
+    let timestamp = match &request.timestamp {
+        Some(timestamp) => parse_timestamp(timestamp)?,
+        None => SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+    };
+    let snapshot = create_snapshot(&request.bucket, &request.name, timestamp)?;
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "name": snapshot.name,
+        "bucket": snapshot.bucket,
+        "timestamp": snapshot.timestamp,
+        "versions": snapshot.version_ids(),
+    })))
+}
+
+#[get("/admin/snapshots/{bucket}")]
+pub async fn list_snapshots(req: HttpRequest, path: web::Path<String>) -> Result<impl Responder, Error> {
+    auth::require_admin(&req)?;
+    let bucket = path.into_inner();
+    let snapshots: Vec<serde_json::Value> = SNAPSHOT_STORE.list(&bucket).into_iter()
+        .map(|s| serde_json::json!({
+            "name": s.name,
+            "timestamp": s.timestamp,
+            "objects": s.versions.len(),
+        }))
+        .collect();
+    Ok(HttpResponse::Ok().json(snapshots))
+}
+
+// List the keys of a bucket as they existed when the snapshot was taken
+#[get("/admin/snapshots/{bucket}/{name}")]
+pub async fn get_snapshot(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<impl Responder, Error> {
+    auth::require_admin(&req)?;
+    let (bucket, name) = path.into_inner();
+    let snapshot = SNAPSHOT_STORE.get(&bucket, &name)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Snapshot not found"))?;
+    let prefix = query.get("prefix").map(String::as_str).unwrap_or("");
+    let objects = snapshot.versions.values()
+        .filter(|v| v.key.starts_with(prefix))
+        .cloned()
+        .map(|v| SnapshotObject {
+            key: v.key,
+            version_id: v.version_id,
+            size: v.size,
+            etag: v.etag,
+            last_modified: v.last_modified,
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(SnapshotListing {
+        name: snapshot.name,
+        bucket: snapshot.bucket,
+        timestamp: snapshot.timestamp,
+        objects,
+    }))
+}
+
+// Roll the bucket back to the snapshot
+#[post("/admin/snapshots/{bucket}/{name}/restore")]
+pub async fn restore_snapshot(req: HttpRequest, path: web::Path<(String, String)>) -> Result<impl Responder, Error> {
+    auth::require_admin(&req)?;
+    let (bucket, name) = path.into_inner();
+    let snapshot = SNAPSHOT_STORE.get(&bucket, &name)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Snapshot not found"))?;
+    let summary = versioning::restore_versions(&bucket, &snapshot.versions)?;
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+// Drop a snapshot, unpinning its versions
+#[delete("/admin/snapshots/{bucket}/{name}")]
+pub async fn delete_snapshot(req: HttpRequest, path: web::Path<(String, String)>) -> Result<impl Responder, Error> {
+    auth::require_admin(&req)?;
+    let (bucket, name) = path.into_inner();
+    SNAPSHOT_STORE.remove(&bucket, &name)
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Snapshot not found"))?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::versioning::VersioningStatus;
+    use actix_web::{test, App};
+    use serial_test::serial;
+    use std::fs;
+
+    #[actix_rt::test]
+    #[serial(config)]
+    async fn test_snapshot_pins_and_restores_versions() {
+        let bucket = "testbucket_snapshot";
+        let _ = fs::remove_dir_all(bucket);
+        VERSION_STORE.set_status(bucket, VersioningStatus::Enabled);
+        let a1 = versioning::create_version(bucket, "a", b"a1", "e", HashMap::new(), Vec::new()).unwrap();
+        versioning::create_version(bucket, "b", b"b1", "e", HashMap::new(), Vec::new()).unwrap();
+        versioning::delete_object(bucket, "b").unwrap();
+
+        let app = test::init_service(App::new()
+            .service(post_snapshot)
+            .service(list_snapshots)
+            .service(get_snapshot)
+            .service(restore_snapshot)
+            .service(delete_snapshot))
+            .await;
+        let create_request = || CreateSnapshotRequest {
+            bucket: bucket.to_string(),
+            name: "before".to_string(),
+            timestamp: None,
+        };
+
+        // Only admins can manage snapshots
+        let req = test::TestRequest::post().uri("/admin/snapshots").set_json(create_request()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+        assert!(SNAPSHOT_STORE.get(bucket, "before").is_none());
+
+        let _admin = auth::as_test_admin();
+        let req = test::TestRequest::post()
+            .uri(&auth::test_admin_uri("POST", "/admin/snapshots"))
+            .insert_header(("Host", "localhost:8080"))
+            .set_json(create_request())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 201);
+
+        for req in [
+            test::TestRequest::get().uri(&format!("/admin/snapshots/{}", bucket)),
+            test::TestRequest::get().uri(&format!("/admin/snapshots/{}/before", bucket)),
+            test::TestRequest::post().uri(&format!("/admin/snapshots/{}/before/restore", bucket)),
+            test::TestRequest::delete().uri(&format!("/admin/snapshots/{}/before", bucket)),
+        ] {
+            assert_eq!(test::call_service(&app, req.to_request()).await.status(), 403);
+        }
+        assert!(SNAPSHOT_STORE.get(bucket, "before").is_some());
+
+        // Only keys that existed are listed
+        let req = test::TestRequest::get()
+            .uri(&auth::test_admin_uri("GET", &format!("/admin/snapshots/{}/before", bucket)))
+            .insert_header(("Host", "localhost:8080"))
+            .to_request();
+        let body: serde_json::Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(body["objects"].as_array().unwrap().len(), 1);
+        assert_eq!(body["objects"][0]["version_id"], a1.version_id.as_str());
+
+        // Pinned versions cannot be deleted
+        assert!(versioning::remove_version(bucket, "a", &a1.version_id, false).is_err());
+
+        // Change the bucket, then roll it back
+        versioning::create_version(bucket, "a", b"a2", "e", HashMap::new(), Vec::new()).unwrap();
+        versioning::create_version(bucket, "c", b"c1", "e", HashMap::new(), Vec::new()).unwrap();
+        assert_eq!(snapshot_version(bucket, "before", "a").unwrap().version_id, a1.version_id);
+        let req = test::TestRequest::post()
+            .uri(&auth::test_admin_uri("POST", &format!("/admin/snapshots/{}/before/restore", bucket)))
+            .insert_header(("Host", "localhost:8080"))
+            .to_request();
+        let body: serde_json::Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(body["restored"], serde_json::json!(["a"]));
+        assert_eq!(body["deleted"], serde_json::json!(["c"]));
+        let latest = VERSION_STORE.get_latest_version(bucket, "a").unwrap();
+        assert_eq!(versioning::read_version(&latest).unwrap(), b"a1");
+
+        // Deleting the snapshot unpins its versions
+        let req = test::TestRequest::delete()
+            .uri(&auth::test_admin_uri("DELETE", &format!("/admin/snapshots/{}/before", bucket)))
+            .insert_header(("Host", "localhost:8080"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 204);
+        assert!(versioning::remove_version(bucket, "a", &a1.version_id, false).is_ok());
+        let _ = fs::remove_dir_all(bucket);
+    }
+
+    #[actix_rt::test]
+    async fn test_snapshot_of_suspended_bucket_allows_writes() {
+        let bucket = "testbucket_snapshot_suspended";
+        let _ = fs::remove_dir_all(bucket);
+        VERSION_STORE.set_status(bucket, VersioningStatus::Suspended);
+        let old = versioning::create_version(bucket, "k", b"one", "e1", HashMap::new(), Vec::new()).unwrap();
+        create_snapshot(bucket, "pinned", u64::MAX).unwrap();
+
+        // The pinned null version is replaced, but the snapshot keeps its data
+        versioning::create_version(bucket, "k", b"two", "e2", HashMap::new(), Vec::new()).unwrap();
+        let latest = VERSION_STORE.get_latest_version(bucket, "k").unwrap();
+        assert_eq!(versioning::read_version(&latest).unwrap(), b"two");
+        let pinned = snapshot_version(bucket, "pinned", "k").unwrap();
+        assert_eq!(versioning::read_version(&pinned).unwrap(), b"one");
+        assert_eq!(crate::blob::BLOB_STORE.ref_count(bucket, &old.blob), 1);
+
+        // Restoring brings the old content back under a new null version
+        let snapshot = SNAPSHOT_STORE.get(bucket, "pinned").unwrap();
+        let summary = versioning::restore_versions(bucket, &snapshot.versions).unwrap();
+        assert_eq!(summary.restored, vec!["k".to_string()]);
+        let latest = VERSION_STORE.get_latest_version(bucket, "k").unwrap();
+        assert_eq!(versioning::read_version(&latest).unwrap(), b"one");
+
+        SNAPSHOT_STORE.remove(bucket, "pinned").unwrap();
+        let _ = fs::remove_dir_all(bucket);
+    }
+}
@@ -0,0 +1,312 @@
+use actix_web::{web, HttpRequest, HttpResponse, Error};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{Read, Write};
+use crate::config;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+use crate::controller::bucket;
+use crate::controller::versioning::{self, VersionInfo, VERSION_STORE};
+use crate::xml;
+
+const TAGGING_FILE: &str = "bucket_tagging.json";
+const MAX_OBJECT_TAGS: usize = 10;
+const MAX_BUCKET_TAGS: usize = 50;
+const MAX_KEY_LENGTH: usize = 128;
+const MAX_VALUE_LENGTH: usize = 256;
+
+pub type TagSet = BTreeMap<String, String>;
+
+lazy_static! {
+    static ref BUCKET_TAGS: Mutex<HashMap<String, TagSet>> = Mutex::new(HashMap::new());
+}
+
+pub fn load_tagging() -> std::io::Result<()> {
+    if config::metadata_path(TAGGING_FILE).exists() {
+        let mut file = File::open(config::metadata_path(TAGGING_FILE))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let tags: HashMap<String, TagSet> = serde_json::from_str(&contents)?;
+        *BUCKET_TAGS.lock().unwrap() = tags;
+    }
+    Ok(())
+}
+
+fn save_tagging() -> std::io::Result<()> {
+    let tags = BUCKET_TAGS.lock().unwrap();
+    let contents = serde_json::to_string_pretty(&*tags)?;
+    let mut file = File::create(config::metadata_path(TAGGING_FILE))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+// Add a tag, enforcing S3's limits on key/value length and duplicate keys
+fn insert_tag(tags: &mut TagSet, key: String, value: String, limit: usize) -> Result<(), Error> {
+    if key.is_empty() || key.chars().count() > MAX_KEY_LENGTH {
+        return Err(actix_web::error::ErrorBadRequest("InvalidTag: invalid tag key"));
+    }
+    if value.chars().count() > MAX_VALUE_LENGTH {
+        return Err(actix_web::error::ErrorBadRequest("InvalidTag: invalid tag value"));
+    }
+    if tags.insert(key, value).is_some() {
+        return Err(actix_web::error::ErrorBadRequest("InvalidTag: duplicate tag key"));
+    }
+    if tags.len() > limit {
+        return Err(actix_web::error::ErrorBadRequest("BadRequest: too many tags"));
+    }
+    Ok(())
+}
+
+// Parse the `x-amz-tagging` header, a URL-encoded query string (`k1=v1&k2=v2`)
+pub fn tags_from_request(req: &HttpRequest) -> Result<TagSet, Error> {
+    let mut tags = TagSet::new();
+    let Some(header) = req.headers().get("x-amz-tagging") else { return Ok(tags) };
+    let header = header.to_str()
+        .map_err(|_| actix_web::error::ErrorBadRequest("InvalidTag: invalid x-amz-tagging header"))?;
+    let decode = |s: &str| {
+        percent_encoding::percent_decode_str(&s.replace('+', " "))
+            .decode_utf8()
+            .map(|s| s.into_owned())
+            .map_err(|_| actix_web::error::ErrorBadRequest("InvalidTag: invalid x-amz-tagging header"))
+    };
+    for pair in header.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        insert_tag(&mut tags, decode(key)?, decode(value)?, MAX_OBJECT_TAGS)?;
+    }
+    Ok(tags)
+}
+
+// Parse a `<Tagging><TagSet><Tag>...</Tag></TagSet></Tagging>` document
+fn parse_tagging(body: &[u8], limit: usize) -> Result<TagSet, Error> {
+    let body = std::str::from_utf8(body)
+        .map_err(|_| actix_web::error::ErrorBadRequest("MalformedXML"))?;
+    let tag_set = xml::element_text(body, "TagSet")
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("MalformedXML: missing TagSet"))?;
+    let mut tags = TagSet::new();
+    for tag in xml::elements(tag_set, "Tag") {
+        let key = xml::element_text(tag, "Key")
+            .ok_or_else(|| actix_web::error::ErrorBadRequest("MalformedXML: Tag is missing Key"))?;
+        let value = xml::element_text(tag, "Value").unwrap_or("");
+        insert_tag(&mut tags, xml::unescape(key), xml::unescape(value), limit)?;
+    }
+    Ok(tags)
+}
+
+// Parse a `Tagging` document for an object, e.g. a POST upload's `tagging` field
+pub fn parse_object_tagging(body: &str) -> Result<TagSet, Error> {
+    parse_tagging(body.as_bytes(), MAX_OBJECT_TAGS)
+}
+
+fn render_tagging(tags: &TagSet) -> String {
+    let tags: String = tags.iter()
+        .map(|(key, value)| format!(
+            "<Tag><Key>{}</Key><Value>{}</Value></Tag>",
+            xml::escape(key),
+            xml::escape(value)
+        ))
+        .collect();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<Tagging xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><TagSet>{}</TagSet></Tagging>"#,
+        tags
+    )
+}
+
+// Policy condition keys describing a version's tags (`s3:ExistingObjectTag/<key>`)
+pub fn condition_values(version: &VersionInfo) -> HashMap<String, String> {
+    version.tags.iter()
+        .map(|(key, value)| (format!("s3:ExistingObjectTag/{}", key), value.clone()))
+        .collect()
+}
+
+// Policy condition keys describing the tags a write sets (`s3:RequestObjectTag/<key>`)
+pub fn request_condition_values(tags: &TagSet) -> HashMap<String, String> {
+    tags.iter()
+        .map(|(key, value)| (format!("s3:RequestObjectTag/{}", key), value.clone()))
+        .collect()
+}
+
+// `x-amz-tagging-count` for GET/HEAD responses, omitted when a version has no tags
+pub fn count_header(version: &VersionInfo) -> Option<(&'static str, String)> {
+    (!version.tags.is_empty()).then(|| ("x-amz-tagging-count", version.tags.len().to_string()))
+}
+
+fn version_response(bucket: &str, version: &VersionInfo) -> actix_web::HttpResponseBuilder {
+    let mut response = HttpResponse::Ok();
+    if VERSION_STORE.get_status(bucket).is_versioned() {
+        response.insert_header(("x-amz-version-id", version.version_id.clone()));
+    }
+    response
+}
+
+pub async fn put_object_tagging(
+    path: web::Path<(String, String)>,
+    query: web::Query<HashMap<String, String>>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let (bucket, key) = path.into_inner();
+    let version = versioning::target_version(&bucket, &key, &query)?;
+    let tags = parse_tagging(&body, MAX_OBJECT_TAGS)?;
+    let mut context = condition_values(&version);
+    context.extend(request_condition_values(&tags));
+    if bucket::policy_denies(&bucket, "s3:PutObjectTagging", &context) {
+        return Err(actix_web::error::ErrorForbidden("AccessDenied"));
+    }
+    VERSION_STORE.update_version(&bucket, &key, &version.version_id, |v| v.tags = tags)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(version_response(&bucket, &version).finish())
+}
+
+pub async fn get_object_tagging(
+    path: web::Path<(String, String)>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, Error> {
+    let (bucket, key) = path.into_inner();
+    let version = versioning::target_version(&bucket, &key, &query)?;
+    if bucket::policy_denies(&bucket, "s3:GetObjectTagging", &condition_values(&version)) {
+        return Err(actix_web::error::ErrorForbidden("AccessDenied"));
+    }
+    Ok(version_response(&bucket, &version)
+        .content_type("application/xml")
+        .body(render_tagging(&version.tags)))
+}
+
+pub async fn delete_object_tagging(
+    path: web::Path<(String, String)>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, Error> {
+    let (bucket, key) = path.into_inner();
+    let version = versioning::target_version(&bucket, &key, &query)?;
+    if bucket::policy_denies(&bucket, "s3:DeleteObjectTagging", &condition_values(&version)) {
+        return Err(actix_web::error::ErrorForbidden("AccessDenied"));
+    }
+    VERSION_STORE.update_version(&bucket, &key, &version.version_id, |v| v.tags.clear())
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    let mut response = HttpResponse::NoContent();
+    if VERSION_STORE.get_status(&bucket).is_versioned() {
+        response.insert_header(("x-amz-version-id", version.version_id));
+    }
+    Ok(response.finish())
+}
+
+pub async fn put_bucket_tagging(path: web::Path<String>, body: web::Bytes) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    let tags = parse_tagging(&body, MAX_BUCKET_TAGS)?;
+    BUCKET_TAGS.lock().unwrap().insert(bucket, tags);
+    save_tagging().map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn get_bucket_tagging(path: web::Path<String>) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    let tags = BUCKET_TAGS.lock().unwrap().get(&bucket).cloned()
+        .ok_or_else(|| actix_web::error::ErrorNotFound("NoSuchTagSet"))?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(render_tagging(&tags)))
+}
+
+pub async fn delete_bucket_tagging(path: web::Path<String>) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    BUCKET_TAGS.lock().unwrap().remove(&bucket);
+    save_tagging().map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::router;
+    use actix_web::{test, App};
+    use std::fs;
+
+    #[actix_rt::test]
+    async fn test_tagging_header_limits() {
+        let req = test::TestRequest::default()
+            .insert_header(("x-amz-tagging", "project=r3&team=storage%20core"))
+            .to_http_request();
+        let tags = tags_from_request(&req).unwrap();
+        assert_eq!(tags.get("team").map(String::as_str), Some("storage core"));
+
+        let too_many = (0..=MAX_OBJECT_TAGS).map(|i| format!("k{}=v", i)).collect::<Vec<_>>().join("&");
+        let req = test::TestRequest::default()
+            .insert_header(("x-amz-tagging", too_many))
+            .to_http_request();
+        assert!(tags_from_request(&req).is_err());
+        let req = test::TestRequest::default()
+            .insert_header(("x-amz-tagging", "a=1&a=2"))
+            .to_http_request();
+        assert!(tags_from_request(&req).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_put_get_delete_object_tagging() {
+        let bucket = "testbucket_tagging";
+        let _ = fs::remove_dir_all(bucket);
+        let version = versioning::create_version(bucket, "doc", b"data", "e", HashMap::new(), Vec::new()).unwrap();
+
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}/doc?tagging", bucket))
+            .set_payload("<Tagging><TagSet><Tag><Key>stage</Key><Value>a&amp;b</Value></Tag></TagSet></Tagging>")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let tagged = VERSION_STORE.get_version(bucket, "doc", &version.version_id).unwrap();
+        assert_eq!(count_header(&tagged), Some(("x-amz-tagging-count", "1".to_string())));
+        assert_eq!(condition_values(&tagged).get("s3:ExistingObjectTag/stage").map(String::as_str), Some("a&b"));
+
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let req = test::TestRequest::get().uri(&format!("/{}/doc?tagging", bucket)).to_request();
+        let body = test::read_body(test::call_service(&app, req).await).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("<Key>stage</Key>"));
+
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let req = test::TestRequest::delete().uri(&format!("/{}/doc?tagging", bucket)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 204);
+        assert!(VERSION_STORE.get_latest_version(bucket, "doc").unwrap().tags.is_empty());
+        let _ = fs::remove_dir_all(bucket);
+    }
+
+    #[actix_rt::test]
+    async fn test_object_tagging_obeys_bucket_policy() {
+        let bucket = "testbucket_tagging_policy";
+        let _ = fs::remove_dir_all(bucket);
+        let mut tags = TagSet::new();
+        tags.insert("class".to_string(), "sealed".to_string());
+        let sealed = versioning::create_version(bucket, "sealed", b"data", "e", HashMap::new(), Vec::new()).unwrap();
+        VERSION_STORE.update_version(bucket, "sealed", &sealed.version_id, |v| v.tags = tags).unwrap();
+        versioning::create_version(bucket, "open", b"data", "e", HashMap::new(), Vec::new()).unwrap();
+
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let policy = r#"{"Statement": [{"Effect": "Deny", "Action": ["s3:PutObjectTagging", "s3:GetObjectTagging", "s3:DeleteObjectTagging"],
+            "Condition": {"StringEquals": {"s3:ExistingObjectTag/class": "sealed"}}}]}"#;
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?policy", bucket))
+            .set_json(serde_json::json!({ "policy": policy, "acl": "" }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let put = |key: &str| test::TestRequest::put()
+            .uri(&format!("/{}/{}?tagging", bucket, key))
+            .set_payload("<Tagging><TagSet><Tag><Key>class</Key><Value>open</Value></Tag></TagSet></Tagging>")
+            .to_request();
+        assert_eq!(test::call_service(&app, put("sealed")).await.status(), 403);
+        assert_eq!(test::call_service(&app, put("open")).await.status(), 200);
+        for req in [
+            test::TestRequest::get().uri(&format!("/{}/sealed?tagging", bucket)),
+            test::TestRequest::delete().uri(&format!("/{}/sealed?tagging", bucket)),
+        ] {
+            assert_eq!(test::call_service(&app, req.to_request()).await.status(), 403);
+        }
+        assert_eq!(VERSION_STORE.get_latest_version(bucket, "sealed").unwrap().tags["class"], "sealed");
+        let req = test::TestRequest::get().uri(&format!("/{}/open?tagging", bucket)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?policy", bucket))
+            .set_json(serde_json::json!({ "policy": "{}", "acl": "" }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let _ = fs::remove_dir_all(bucket);
+    }
+}
@@ -1,16 +1,27 @@
-use actix_web::{web, HttpResponse, Responder, Error, get, put, delete};
+use actix_web::http::Method;
+use actix_web::{web, HttpRequest, HttpResponse, Responder, Error};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::{self, File};
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::PathBuf;
+use crate::config;
 use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 use bytes::Bytes;
 use lazy_static::lazy_static;
+use crate::blob::BLOB_STORE;
+use crate::crypto::{self, CustomerKey, EncryptionInfo, EncryptionMode, Sse};
+use crate::controller::{bucket, encryption, object, tagging};
+use crate::controller::checksum::{self, Checksum};
+use crate::controller::object_lock::{self, Retention};
+use crate::controller::snapshot::SNAPSHOT_STORE;
+use crate::sigv4;
+use crate::telemetry;
+use crate::xml;
 
 // Structure to store version information
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct VersionInfo {
     pub version_id: String,
     pub bucket: String,
@@ -20,29 +31,135 @@ pub struct VersionInfo {
     pub size: u64,
     pub last_modified: u64,
     pub etag: String,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    #[serde(default)]
+    pub part_sizes: Vec<u64>,
+    #[serde(default)]
+    pub blob: String, // content hash in the blob store, empty for delete markers
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default)]
+    pub retention: Option<Retention>,
+    #[serde(default)]
+    pub legal_hold: bool,
+    #[serde(default)]
+    pub encryption: Option<EncryptionInfo>,
+    #[serde(default)]
+    pub checksum: Option<Checksum>,
 }
 
-// In-memory storage for version information
+// Version ID used by unversioned and versioning-suspended buckets
+pub const NULL_VERSION_ID: &str = "null";
+
+const VERSIONING_FILE: &str = "bucket_versioning.json";
+
+// Directory holding each bucket's version index, `{bucket}.json`
+const VERSIONS_DIR: &str = "bucket_versions";
+
+// Versioning state of a bucket. A bucket starts out unversioned and, once
+// versioning has been enabled, can only move between Enabled and Suspended.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VersioningStatus {
+    #[default]
+    Unversioned,
+    Enabled,
+    Suspended,
+}
+
+impl VersioningStatus {
+    pub fn is_versioned(&self) -> bool {
+        *self != VersioningStatus::Unversioned
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BucketStats {
+    pub objects: u64,
+    pub versions: u64,
+    pub bytes: u64,
+}
+
+// Version information, kept in memory and written through to a per-bucket
+// index on every change
 pub struct VersionStore {
     versions: Mutex<HashMap<String, HashMap<String, Vec<VersionInfo>>>>,
+    statuses: Mutex<HashMap<String, VersioningStatus>>,
 }
 
 impl VersionStore {
     pub fn new() -> Self {
         VersionStore {
             versions: Mutex::new(HashMap::new()),
+            statuses: Mutex::new(HashMap::new()),
         }
     }
 
-    pub fn add_version(&self, bucket: &str, key: &str, version_info: VersionInfo) {
+    pub fn get_status(&self, bucket: &str) -> VersioningStatus {
+        self.statuses.lock().unwrap()
+            .get(bucket)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn set_status(&self, bucket: &str, status: VersioningStatus) {
+        self.statuses.lock().unwrap().insert(bucket.to_string(), status);
+    }
+
+    // Apply `update` to a stored version, returning the updated version
+    pub fn update_version(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: &str,
+        update: impl FnOnce(&mut VersionInfo),
+    ) -> std::io::Result<Option<VersionInfo>> {
+        let mut versions = self.versions.lock().unwrap();
+        let Some(version) = versions.get_mut(bucket)
+            .and_then(|keys| keys.get_mut(key))
+            .and_then(|versions| versions.iter_mut().find(|v| v.version_id == version_id))
+        else {
+            return Ok(None);
+        };
+        update(version);
+        let updated = version.clone();
+        save_versions(bucket, &versions[bucket])?;
+        Ok(Some(updated))
+    }
+
+    // Remove a single version, promoting the previous one to latest
+    pub fn remove_version(&self, bucket: &str, key: &str, version_id: &str) -> std::io::Result<Option<VersionInfo>> {
+        let mut versions = self.versions.lock().unwrap();
+        let Some(bucket_map) = versions.get_mut(bucket) else {
+            return Ok(None);
+        };
+        let Some(version_list) = bucket_map.get_mut(key) else {
+            return Ok(None);
+        };
+        let Some(index) = version_list.iter().position(|v| v.version_id == version_id) else {
+            return Ok(None);
+        };
+        let removed = version_list.remove(index);
+        if let Some(last) = version_list.last_mut() {
+            last.is_latest = true;
+        }
+        if version_list.is_empty() {
+            bucket_map.remove(key);
+        }
+        save_versions(bucket, bucket_map)?;
+        Ok(Some(removed))
+    }
+
+    pub fn add_version(&self, bucket: &str, key: &str, version_info: VersionInfo) -> std::io::Result<()> {
         let mut versions = self.versions.lock().unwrap();
-        let bucket_map = versions.entry(bucket.to_string()).or_insert_with(HashMap::new);
-        let version_list = bucket_map.entry(key.to_string()).or_insert_with(Vec::new);
+        let bucket_map = versions.entry(bucket.to_string()).or_default();
+        let version_list = bucket_map.entry(key.to_string()).or_default();
         // Mark all other versions as not latest
         for v in version_list.iter_mut() {
             v.is_latest = false;
         }
         version_list.push(version_info);
+        save_versions(bucket, bucket_map)
     }
 
     pub fn get_versions(&self, bucket: &str, key: &str) -> Vec<VersionInfo> {
@@ -53,6 +170,19 @@ impl VersionStore {
             .unwrap_or_default()
     }
 
+    // All versions in a bucket, keys ascending and newest version first
+    pub fn list_bucket_versions(&self, bucket: &str) -> Vec<VersionInfo> {
+        let versions = self.versions.lock().unwrap();
+        let Some(bucket_map) = versions.get(bucket) else {
+            return Vec::new();
+        };
+        let mut keys: Vec<&String> = bucket_map.keys().collect();
+        keys.sort();
+        keys.into_iter()
+            .flat_map(|key| bucket_map[key].iter().rev().cloned())
+            .collect()
+    }
+
     pub fn get_version(&self, bucket: &str, key: &str, version_id: &str) -> Option<VersionInfo> {
         self.versions.lock().unwrap()
             .get(bucket)
@@ -60,6 +190,23 @@ impl VersionStore {
             .and_then(|versions| versions.iter().find(|v| v.version_id == version_id).cloned())
     }
 
+    // Per bucket: objects whose latest version is not a delete marker, all
+    // versions, and the bytes those versions hold
+    pub fn bucket_stats(&self) -> Vec<(String, BucketStats)> {
+        self.versions.lock().unwrap()
+            .iter()
+            .map(|(bucket, keys)| {
+                let mut stats = BucketStats::default();
+                for versions in keys.values() {
+                    stats.objects += versions.last().is_some_and(|v| !v.is_delete_marker) as u64;
+                    stats.versions += versions.len() as u64;
+                    stats.bytes += versions.iter().map(|v| v.size).sum::<u64>();
+                }
+                (bucket.clone(), stats)
+            })
+            .collect()
+    }
+
     pub fn get_latest_version(&self, bucket: &str, key: &str) -> Option<VersionInfo> {
         self.versions
             .lock().unwrap()
@@ -74,6 +221,72 @@ lazy_static! {
     pub static ref VERSION_STORE: VersionStore = VersionStore::new();
 }
 
+pub fn load_versioning() -> std::io::Result<()> {
+    if config::metadata_path(VERSIONING_FILE).exists() {
+        let mut file = File::open(config::metadata_path(VERSIONING_FILE))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let statuses: HashMap<String, VersioningStatus> = serde_json::from_str(&contents)?;
+        *VERSION_STORE.statuses.lock().unwrap() = statuses;
+    }
+    Ok(())
+}
+
+fn versions_path(bucket: &str) -> PathBuf {
+    config::metadata_path(VERSIONS_DIR).join(format!("{}.json", bucket))
+}
+
+// Read the version index of every bucket, taking a blob reference for each
+// version that holds data
+pub fn load_versions() -> std::io::Result<()> {
+    let dir = config::metadata_path(VERSIONS_DIR);
+    if !dir.exists() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            if let Some(bucket) = path.file_stem().and_then(|stem| stem.to_str()) {
+                load_bucket_versions(bucket)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn load_bucket_versions(bucket: &str) -> std::io::Result<()> {
+    let mut file = File::open(versions_path(bucket))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let keys: HashMap<String, Vec<VersionInfo>> = serde_json::from_str(&contents)?;
+    for version in keys.values().flatten() {
+        if !version.blob.is_empty() {
+            BLOB_STORE.retain(bucket, &version.blob);
+        }
+    }
+    VERSION_STORE.versions.lock().unwrap().insert(bucket.to_string(), keys);
+    Ok(())
+}
+
+// Write a bucket's version index; called with the store locked so writes
+// land in the order the changes were made
+fn save_versions(bucket: &str, keys: &HashMap<String, Vec<VersionInfo>>) -> std::io::Result<()> {
+    let path = versions_path(bucket);
+    fs::create_dir_all(path.parent().unwrap())?;
+    let contents = serde_json::to_string(keys)?;
+    let mut file = File::create(path)?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+fn save_versioning() -> std::io::Result<()> {
+    let statuses = VERSION_STORE.statuses.lock().unwrap();
+    let contents = serde_json::to_string_pretty(&*statuses)?;
+    let mut file = File::create(config::metadata_path(VERSIONING_FILE))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
 // Helper function to generate version ID
 fn generate_version_id() -> String {
     format!("{:x}", SystemTime::now()
@@ -90,138 +303,218 @@ fn get_current_timestamp() -> u64 {
         .as_secs()
 }
 
-// Endpoints
-#[derive(Debug, Serialize, Deserialize)]
-pub struct VersioningConfiguration {
-    status: String,
+// Change the versioning status of a bucket and persist it
+pub fn set_versioning(bucket: &str, status: VersioningStatus) -> Result<(), Error> {
+    if status != VersioningStatus::Enabled && object_lock::is_enabled(bucket) {
+        return Err(actix_web::error::ErrorConflict(
+            "InvalidBucketState: versioning cannot be suspended while Object Lock is enabled",
+        ));
+    }
+    VERSION_STORE.set_status(bucket, status);
+    save_versioning().map_err(|e| {
+        actix_web::error::ErrorInternalServerError(format!("Failed to save versioning: {}", e))
+    })
 }
 
-#[put("/{bucket}")]
+// Endpoints
 pub async fn put_bucket_versioning(
     path: web::Path<String>,
-    _config: web::Json<VersioningConfiguration>,
+    body: web::Bytes,
 ) -> Result<HttpResponse, Error> {
     let bucket = path.into_inner();
-    let mut store = VERSION_STORE.versions.lock().unwrap();
-    store.insert(bucket, HashMap::new());
+    let body = std::str::from_utf8(&body)
+        .map_err(|_| actix_web::error::ErrorBadRequest("MalformedXML"))?;
+    let status = match xml::element_text(body, "Status") {
+        Some("Enabled") => VersioningStatus::Enabled,
+        Some("Suspended") => VersioningStatus::Suspended,
+        _ => return Err(actix_web::error::ErrorBadRequest("MalformedXML")),
+    };
+    set_versioning(&bucket, status)?;
     Ok(HttpResponse::Ok().finish())
 }
 
-#[get("/{bucket}")]
 pub async fn get_bucket_versioning(
     path: web::Path<String>,
 ) -> Result<HttpResponse, Error> {
     let bucket = path.into_inner();
-    let store = VERSION_STORE.versions.lock().unwrap();
-    let has_versions = store.contains_key(&bucket);
+    // Buckets that were never versioned report no status at all
+    let status = match VERSION_STORE.get_status(&bucket) {
+        VersioningStatus::Unversioned => String::new(),
+        VersioningStatus::Enabled => "<Status>Enabled</Status>".to_string(),
+        VersioningStatus::Suspended => "<Status>Suspended</Status>".to_string(),
+    };
     let response = format!(
-        r#"<?xml version=\"1.0\" encoding=\"UTF-8\"?>
-        <VersioningConfiguration>
-            <Status>{}</Status>
-        </VersioningConfiguration>"#,
-        if has_versions { "Enabled" } else { "Suspended" }
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+        <VersioningConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/">{}</VersioningConfiguration>"#,
+        status
     );
     Ok(HttpResponse::Ok()
         .content_type("application/xml")
         .body(response))
 }
 
-#[get("/{bucket}/versions")]
+const MAX_KEYS: usize = 1000;
+
 pub async fn list_object_versions(
     path: web::Path<String>,
     query: web::Query<HashMap<String, String>>,
 ) -> Result<HttpResponse, Error> {
     let bucket = path.into_inner();
-    let versions = VERSION_STORE.get_versions(&bucket, query.get("key").unwrap_or(&String::new()));
-    let response = format!(
-        r#"<?xml version=\"1.0\" encoding=\"UTF-8\"?>
-        <ListVersionsResult>
-            <Name>{}</Name>
-            <Prefix>{}</Prefix>
-            <KeyMarker></KeyMarker>
-            <VersionIdMarker></VersionIdMarker>
-            <MaxKeys>1000</MaxKeys>
-            <IsTruncated>false</IsTruncated>
-            {}
-        </ListVersionsResult>"#,
-        bucket,
-        query.get("prefix").unwrap_or(&String::new()),
-        versions.iter().map(|v| format!(
-            r#"<Version>
-                <Key>{}</Key>
-                <VersionId>{}</VersionId>
-                <IsLatest>{}</IsLatest>
-                <LastModified>{}</LastModified>
-                <ETag>\"{}\"</ETag>
-                <Size>{}</Size>
-                <StorageClass>STANDARD</StorageClass>
-            </Version>"#,
-            v.key,
-            v.version_id,
-            v.is_latest,
-            v.last_modified,
-            v.etag,
-            v.size
-        )).collect::<Vec<String>>().join("\n")
-    );
-    Ok(HttpResponse::Ok()
-        .content_type("application/xml")
-        .body(response))
-}
+    let empty = String::new();
+    let prefix = query.get("prefix").unwrap_or(&empty);
+    let delimiter = query.get("delimiter").filter(|d| !d.is_empty());
+    let key_marker = query.get("key-marker").unwrap_or(&empty);
+    let version_id_marker = query.get("version-id-marker").filter(|v| !v.is_empty());
+    let max_keys = match query.get("max-keys") {
+        Some(max) => max.parse::<usize>()
+            .map_err(|_| actix_web::error::ErrorBadRequest("Invalid max-keys parameter"))?
+            .min(MAX_KEYS),
+        None => MAX_KEYS,
+    };
 
-#[get("/{bucket}/{key}?versions")]
-pub async fn list_object_versions_old(
-    path: web::Path<(String, String)>,
-) -> Result<impl Responder, Error> {
-    let (bucket, key) = path.into_inner();
-    let versions = VERSION_STORE.get_versions(&bucket, &key);
+    let mut versions = VERSION_STORE.list_bucket_versions(&bucket).into_iter()
+        .filter(|v| v.key.starts_with(prefix.as_str()))
+        .peekable();
 
-    let response = format!(
+    // Skip everything up to and including the markers
+    if !key_marker.is_empty() {
+        let mut past_version_marker = version_id_marker.is_none();
+        while let Some(v) = versions.peek() {
+            let skip = if v.key == *key_marker {
+                !past_version_marker || version_id_marker.is_none()
+            } else {
+                v.key < *key_marker
+                    // a key marker that is a common prefix covers every key below it
+                    || (delimiter.is_some_and(|d| key_marker.ends_with(d.as_str()))
+                        && v.key.starts_with(key_marker.as_str()))
+            };
+            if !skip {
+                break;
+            }
+            if v.key == *key_marker && Some(&v.version_id) == version_id_marker {
+                past_version_marker = true;
+            }
+            versions.next();
+        }
+    }
+
+    let mut entries = Vec::new();
+    let mut common_prefixes: Vec<String> = Vec::new();
+    let mut next_markers = None;
+    let mut is_truncated = false;
+    let mut count = 0;
+    for v in versions {
+        let common_prefix = delimiter.and_then(|d| {
+            v.key[prefix.len()..].find(d.as_str())
+                .map(|pos| v.key[..prefix.len() + pos + d.len()].to_string())
+        });
+        if common_prefix.is_some() && common_prefixes.last() == common_prefix.as_ref() {
+            continue;
+        }
+        if count == max_keys {
+            is_truncated = true;
+            break;
+        }
+        count += 1;
+        match common_prefix {
+            Some(common_prefix) => {
+                next_markers = Some((common_prefix.clone(), None));
+                common_prefixes.push(common_prefix);
+            }
+            None => {
+                next_markers = Some((v.key.clone(), Some(v.version_id.clone())));
+                entries.push(v);
+            }
+        }
+    }
+
+    let mut response = format!(
         r#"<?xml version="1.0" encoding="UTF-8"?>
-        <ListVersionsResult>
-            <Name>{}</Name>
-            <Prefix>{}</Prefix>
-            <KeyMarker></KeyMarker>
-            <VersionIdMarker></VersionIdMarker>
-            <MaxKeys>1000</MaxKeys>
-            <IsTruncated>false</IsTruncated>
-            {}
-        </ListVersionsResult>"#,
-        bucket,
-        key,
-        versions.iter().map(|v| format!(
-            r#"<Version>
-                <Key>{}</Key>
-                <VersionId>{}</VersionId>
-                <IsLatest>{}</IsLatest>
-                <LastModified>{}</LastModified>
-                <ETag>"{}"</ETag>
-                <Size>{}</Size>
-                <StorageClass>STANDARD</StorageClass>
-                <Owner>
-                    <ID>owner</ID>
-                    <DisplayName>owner</DisplayName>
-                </Owner>
-            </Version>"#,
-            v.key,
-            v.version_id,
-            v.is_latest,
-            v.last_modified,
-            v.etag,
-            v.size
-        )).collect::<Vec<String>>().join("\n")
+<ListVersionsResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+    <Name>{}</Name>
+    <Prefix>{}</Prefix>
+    <KeyMarker>{}</KeyMarker>
+    <VersionIdMarker>{}</VersionIdMarker>
+    <MaxKeys>{}</MaxKeys>
+    <IsTruncated>{}</IsTruncated>"#,
+        xml::escape(&bucket),
+        xml::escape(prefix),
+        xml::escape(key_marker),
+        xml::escape(version_id_marker.unwrap_or(&empty)),
+        max_keys,
+        is_truncated
     );
+    if let Some(delimiter) = delimiter {
+        response.push_str(&format!("\n    <Delimiter>{}</Delimiter>", xml::escape(delimiter)));
+    }
+    if is_truncated {
+        if let Some((key, version_id)) = &next_markers {
+            response.push_str(&format!("\n    <NextKeyMarker>{}</NextKeyMarker>", xml::escape(key)));
+            if let Some(version_id) = version_id {
+                response.push_str(&format!(
+                    "\n    <NextVersionIdMarker>{}</NextVersionIdMarker>",
+                    xml::escape(version_id)
+                ));
+            }
+        }
+    }
+    for v in &entries {
+        if v.is_delete_marker {
+            response.push_str(&format!(
+                r#"
+    <DeleteMarker>
+        <Key>{}</Key>
+        <VersionId>{}</VersionId>
+        <IsLatest>{}</IsLatest>
+        <LastModified>{}</LastModified>
+        <Owner><ID>owner</ID><DisplayName>owner</DisplayName></Owner>
+    </DeleteMarker>"#,
+                xml::escape(&v.key),
+                xml::escape(&v.version_id),
+                v.is_latest,
+                xml::timestamp(v.last_modified)
+            ));
+        } else {
+            response.push_str(&format!(
+                r#"
+    <Version>
+        <Key>{}</Key>
+        <VersionId>{}</VersionId>
+        <IsLatest>{}</IsLatest>
+        <LastModified>{}</LastModified>
+        <ETag>{}</ETag>
+        <Size>{}</Size>
+        <StorageClass>STANDARD</StorageClass>
+        <Owner><ID>owner</ID><DisplayName>owner</DisplayName></Owner>
+    </Version>"#,
+                xml::escape(&v.key),
+                xml::escape(&v.version_id),
+                v.is_latest,
+                xml::timestamp(v.last_modified),
+                xml::escape(&format!("\"{}\"", v.etag)),
+                v.size
+            ));
+        }
+    }
+    for common_prefix in &common_prefixes {
+        response.push_str(&format!(
+            "\n    <CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+            xml::escape(common_prefix)
+        ));
+    }
+    response.push_str("\n</ListVersionsResult>");
 
     Ok(HttpResponse::Ok()
         .content_type("application/xml")
         .body(response))
 }
 
-#[get("/{bucket}/{key}?versionId={version_id}")]
 pub async fn get_object_version(
+    req: HttpRequest,
     path: web::Path<(String, String)>,
     query: web::Query<HashMap<String, String>>,
 ) -> Result<impl Responder, Error> {
+    sigv4::check_presigned(&req)?;
     let (bucket, key) = path.into_inner();
     let version_id = query.get("versionId").ok_or_else(|| {
         actix_web::error::ErrorBadRequest("Missing versionId parameter")
@@ -229,24 +522,38 @@ pub async fn get_object_version(
 
     let version = VERSION_STORE.get_version(&bucket, &key, version_id)
         .ok_or_else(|| actix_web::error::ErrorNotFound("Version not found"))?;
-
-    if version.is_delete_marker {
-        return Err(actix_web::error::ErrorNotFound("Version is a delete marker"));
+    if bucket::policy_denies(&bucket, "s3:GetObjectVersion", &tagging::condition_values(&version)) {
+        return Err(actix_web::error::ErrorForbidden("AccessDenied"));
     }
 
-    let version_path = format!("{}/.versions/{}/{}", bucket, version_id, key);
-    let mut file = File::open(&version_path)?;
-    let mut content = Vec::new();
-    file.read_to_end(&mut content)?;
+    let customer_key = encryption::customer_key(&req, encryption::CUSTOMER_KEY_HEADERS)?;
+    encryption::check_customer_key(&version, customer_key.as_ref())?;
 
-    Ok(HttpResponse::Ok()
-        .insert_header(("ETag", version.etag))
-        .insert_header(("Last-Modified", version.last_modified.to_string()))
-        .body(Bytes::from(content)))
+    let mut response = HttpResponse::Ok();
+    if let Some(header) = tagging::count_header(&version) {
+        response.insert_header(header);
+    }
+    for header in encryption::response_headers(&version, customer_key.as_ref()) {
+        response.insert_header(header);
+    }
+    if checksum::mode_enabled(&req) {
+        for header in checksum::response_headers(version.checksum.as_ref()) {
+            response.insert_header(header);
+        }
+    }
+    response
+        .insert_header(("ETag", version.etag.clone()))
+        .insert_header(("Last-Modified", version.last_modified.to_string()));
+    // HEAD answers from the version's metadata without reading its data
+    if req.method() == Method::HEAD {
+        return Ok(object::head_response(response, version.size));
+    }
+    let content = read_version_with(&version, customer_key.as_ref())?;
+    Ok(response.body(Bytes::from(content)))
 }
 
-#[delete("/{bucket}/{key}?versionId={version_id}")]
 pub async fn delete_object_version(
+    req: HttpRequest,
     path: web::Path<(String, String)>,
     query: web::Query<HashMap<String, String>>,
 ) -> Result<impl Responder, Error> {
@@ -255,108 +562,548 @@ pub async fn delete_object_version(
         actix_web::error::ErrorBadRequest("Missing versionId parameter")
     })?;
 
-    let version_path = format!("{}/.versions/{}/{}", bucket, version_id, key);
-    if Path::new(&version_path).exists() {
-        fs::remove_file(&version_path)?;
-        // Remove version from in-memory store
-        let mut versions = VERSION_STORE.versions.lock().unwrap();
-        if let Some(bucket_map) = versions.get_mut(&bucket) {
-            if let Some(version_list) = bucket_map.get_mut(&key) {
-                version_list.retain(|v| v.version_id != *version_id);
+    let bypass_governance = object_lock::bypass_governance(&req)?;
+    let removed = remove_version(&bucket, &key, version_id, bypass_governance)?;
+
+    let mut response = HttpResponse::NoContent();
+    response.insert_header(("x-amz-version-id", version_id.as_str()));
+    if removed.is_some_and(|v| v.is_delete_marker) {
+        response.insert_header(("x-amz-delete-marker", "true"));
+    }
+    Ok(response.finish())
+}
+
+// Remove a version and release its data. Versions under Object Lock or
+// pinned by a snapshot are refused.
+pub fn remove_version(
+    bucket: &str,
+    key: &str,
+    version_id: &str,
+    bypass_governance: bool,
+) -> Result<Option<VersionInfo>, Error> {
+    discard_version(bucket, key, version_id, bypass_governance, true)
+}
+
+fn discard_version(
+    bucket: &str,
+    key: &str,
+    version_id: &str,
+    bypass_governance: bool,
+    honor_pins: bool,
+) -> Result<Option<VersionInfo>, Error> {
+    telemetry::in_span("metadata.remove_version", [], || {
+        if let Some(version) = VERSION_STORE.get_version(bucket, key, version_id) {
+            object_lock::check_removable(&version, bypass_governance)?;
+            if let Some(snapshot) = SNAPSHOT_STORE.pinned_by(&version).filter(|_| honor_pins) {
+                return Err(actix_web::error::ErrorForbidden(format!(
+                    "Version is pinned by snapshot {}", snapshot
+                )));
             }
         }
+        let removed = VERSION_STORE.remove_version(bucket, key, version_id)
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+        if let Some(version) = &removed {
+            if !version.blob.is_empty() {
+                BLOB_STORE.release(bucket, &version.blob);
+            }
+        }
+        Ok(removed)
+    })
+}
+
+// Version ID for a new write, replacing any existing null version when the
+// bucket is not versioning-enabled. Snapshots hold their own reference to
+// the data of a null version they pinned, so replacing it is allowed.
+fn next_version_id(bucket: &str, key: &str) -> Result<String, Error> {
+    if VERSION_STORE.get_status(bucket) == VersioningStatus::Enabled {
+        return Ok(generate_version_id());
+    }
+    discard_version(bucket, key, NULL_VERSION_ID, false, false)?;
+    Ok(NULL_VERSION_ID.to_string())
+}
+
+// Read the data of a version from the blob store
+#[cfg(test)]
+pub fn read_version(version: &VersionInfo) -> Result<Vec<u8>, Error> {
+    read_version_with(version, None)
+}
+
+// Read the data of a version, which may have been encrypted with `customer_key`
+pub fn read_version_with(version: &VersionInfo, customer_key: Option<&CustomerKey>) -> Result<Vec<u8>, Error> {
+    if version.is_delete_marker {
+        return Err(actix_web::error::ErrorNotFound("Version is a delete marker"));
+    }
+    let content = BLOB_STORE.read(&version.bucket, &version.blob)?;
+    match &version.encryption {
+        Some(info) => telemetry::in_span("sse.decrypt", [], || crypto::decrypt(info, customer_key, &content))
+            .map_err(encryption::key_error),
+        None => Ok(content),
     }
+}
 
-    Ok(HttpResponse::NoContent().finish())
+// Read bytes `start..end` of a version, decrypting only the chunks that cover them
+pub fn read_version_range(
+    version: &VersionInfo,
+    customer_key: Option<&CustomerKey>,
+    start: u64,
+    end: u64,
+) -> Result<Vec<u8>, Error> {
+    if version.is_delete_marker {
+        return Err(actix_web::error::ErrorNotFound("Version is a delete marker"));
+    }
+    match &version.encryption {
+        Some(info) => {
+            let (offset, len) = crypto::ciphertext_span(start, end, version.size);
+            let sealed = BLOB_STORE.read_range(&version.bucket, &version.blob, offset, len)?;
+            telemetry::in_span("sse.decrypt", [], || {
+                crypto::decrypt_range(info, customer_key, version.size, start, end, &sealed)
+            }).map_err(encryption::key_error)
+        }
+        None => Ok(BLOB_STORE.read_range(&version.bucket, &version.blob, start, end - start)?),
+    }
 }
 
-// Helper function to create a new version
-pub fn create_version(bucket: &str, key: &str, content: &[u8], etag: &str) -> Result<(), Error> {
-    let version_id = generate_version_id();
-    let version_path = format!("{}/.versions/{}/{}", bucket, version_id, key);
-    
-    // Check if version directory exists
-    if Path::new(&version_path).exists() {
-        return Err(actix_web::error::ErrorBadRequest("Version already exists"));
+// Put `content` in the blob store, encrypting it first when requested
+fn store_content(bucket: &str, content: &[u8], sse: Option<&Sse>) -> Result<(String, Option<EncryptionInfo>), Error> {
+    match sse {
+        Some(sse) => {
+            let (sealed, info) = telemetry::in_span("sse.encrypt", [], || crypto::encrypt(content, sse))
+                .map_err(encryption::key_error)?;
+            Ok((BLOB_STORE.put(bucket, &sealed)?, Some(info)))
+        }
+        None => Ok((BLOB_STORE.put(bucket, content)?, None)),
     }
-    
-    // Create version directory
-    fs::create_dir_all(Path::new(&version_path).parent().unwrap())?;
-    
-    // Write version content
-    let mut file = File::create(&version_path)?;
-    file.write_all(content)?;
+}
 
-    // Create version info
-    let version_info = VersionInfo {
-        version_id: version_id.clone(),
+// The version addressed by a subresource request: `versionId` if given,
+// otherwise the latest. Delete markers have no subresources.
+pub fn target_version(bucket: &str, key: &str, query: &HashMap<String, String>) -> Result<VersionInfo, Error> {
+    let version = match query.get("versionId") {
+        Some(version_id) => VERSION_STORE.get_version(bucket, key, version_id),
+        None => VERSION_STORE.get_latest_version(bucket, key),
+    };
+    version
+        .filter(|v| !v.is_delete_marker)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("NoSuchKey"))
+}
+
+// Helper function to create a new version. `part_sizes` records the part
+// boundaries of multipart objects so `partNumber` reads can be served.
+#[cfg(test)]
+pub fn create_version(
+    bucket: &str,
+    key: &str,
+    content: &[u8],
+    etag: &str,
+    metadata: HashMap<String, String>,
+    part_sizes: Vec<u64>,
+) -> Result<VersionInfo, Error> {
+    create_version_from(content, None, VersionInfo {
         bucket: bucket.to_string(),
         key: key.to_string(),
-        is_latest: true,
-        is_delete_marker: false,
-        size: content.len() as u64,
-        last_modified: get_current_timestamp(),
         etag: etag.to_string(),
+        metadata,
+        part_sizes,
+        ..Default::default()
+    })
+}
+
+// Create a new version holding `content`, encrypted with `sse` if given,
+// taking every other attribute (bucket, key, ETag, metadata, lock settings,
+// ...) from `template`
+pub fn create_version_from(content: &[u8], sse: Option<&Sse>, template: VersionInfo) -> Result<VersionInfo, Error> {
+    let (blob, encryption) = store_content(&template.bucket, content, sse)?;
+    add_new_version(VersionInfo {
+        size: content.len() as u64,
+        blob,
+        encryption,
+        ..template
+    })
+}
+
+// Whether data encrypted as `source` already satisfies the encryption requested for a copy
+fn encrypted_as(source: &VersionInfo, sse: Option<&Sse>) -> bool {
+    match (sse, &source.encryption) {
+        (None, _) => true,
+        (Some(Sse::S3), Some(info)) => info.mode == EncryptionMode::S3,
+        (Some(Sse::Kms(key_id)), Some(info)) => info.mode == EncryptionMode::Kms && info.key_id.as_ref() == Some(key_id),
+        (Some(Sse::Customer(key)), Some(info)) => crypto::key_matches(info, key),
+        (Some(_), None) => false,
+    }
+}
+
+// Create a new latest version from an existing one. `target` gives the
+// bucket, key, metadata, tags and lock settings of the copy; the data, size,
+// ETag and checksum come from `source`, and lock settings are never copied.
+// The data is shared with the source when both live in the same bucket and
+// `sse` does not ask for different encryption. `source_key` is the customer
+// key of an SSE-C source. Encrypted data is never stored decrypted by a copy;
+// without `sse` it is re-encrypted with the master key.
+pub fn copy_version(
+    source: &VersionInfo,
+    target: VersionInfo,
+    sse: Option<&Sse>,
+    source_key: Option<&CustomerKey>,
+) -> Result<VersionInfo, Error> {
+    if source.is_delete_marker {
+        return Err(actix_web::error::ErrorBadRequest("Cannot copy a delete marker"));
+    }
+    // Take the new reference before the source can be replaced by this write
+    let (blob, encryption) = if source.bucket == target.bucket && encrypted_as(source, sse) {
+        BLOB_STORE.retain(&target.bucket, &source.blob);
+        (source.blob.clone(), source.encryption.clone())
+    } else {
+        let sse = sse.or(source.encryption.as_ref().map(|_| &Sse::S3));
+        store_content(&target.bucket, &read_version_with(source, source_key)?, sse)?
     };
+    add_new_version(VersionInfo {
+        size: source.size,
+        etag: source.etag.clone(),
+        part_sizes: source.part_sizes.clone(),
+        checksum: source.checksum.clone(),
+        blob,
+        encryption,
+        ..target
+    })
+}
+
+// Store `version` as the new latest version of its key, assigning its
+// version ID and modification time
+fn add_new_version(mut version: VersionInfo) -> Result<VersionInfo, Error> {
+    telemetry::in_span("metadata.add_version", [], || {
+        version.version_id = next_version_id(&version.bucket, &version.key)?;
+        version.is_latest = true;
+        version.last_modified = get_current_timestamp();
+        VERSION_STORE.add_version(&version.bucket, &version.key, version.clone())
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+        Ok(version)
+    })
+}
+
+// Outcome of restoring keys to an earlier state
+#[derive(Debug, Default, Serialize)]
+pub struct RestoreSummary {
+    pub restored: Vec<String>,
+    pub deleted: Vec<String>,
+    pub unchanged: usize,
+}
+
+// For every key under `prefix`, its latest version together with the
+// version that was current at `timestamp` (if the key existed then)
+pub fn versions_at(bucket: &str, prefix: &str, timestamp: u64) -> Vec<(VersionInfo, Option<VersionInfo>)> {
+    // Versions come newest first per key, so the first match is the one current at `timestamp`
+    let mut by_key: Vec<(VersionInfo, Option<VersionInfo>)> = Vec::new();
+    for version in VERSION_STORE.list_bucket_versions(bucket) {
+        if !version.key.starts_with(prefix) {
+            continue;
+        }
+        if by_key.last().map(|(latest, _)| &latest.key) != Some(&version.key) {
+            by_key.push((version.clone(), None));
+        }
+        let (_, at_time) = by_key.last_mut().unwrap();
+        if at_time.is_none() && version.last_modified <= timestamp {
+            *at_time = Some(version);
+        }
+    }
+    by_key
+}
 
-    VERSION_STORE.add_version(bucket, key, version_info);
+// Make `target` the current version of its key again, or delete the key
+// when it should not exist
+fn restore_key(latest: &VersionInfo, target: Option<&VersionInfo>, summary: &mut RestoreSummary) -> Result<(), Error> {
+    match target.filter(|v| !v.is_delete_marker) {
+        Some(version) if version.version_id == latest.version_id && version.blob == latest.blob => {
+            summary.unchanged += 1
+        }
+        Some(version) => {
+            copy_version(version, VersionInfo {
+                bucket: latest.bucket.clone(),
+                key: latest.key.clone(),
+                metadata: version.metadata.clone(),
+                tags: version.tags.clone(),
+                ..Default::default()
+            }, None, None)?;
+            summary.restored.push(latest.key.clone());
+        }
+        None if latest.is_delete_marker => summary.unchanged += 1,
+        None => {
+            create_delete_marker(&latest.bucket, &latest.key)?;
+            summary.deleted.push(latest.key.clone());
+        }
+    }
     Ok(())
 }
 
+// Bring every key under `prefix` back to the state it had at `timestamp`:
+// the version that was current then is promoted to latest, and keys that
+// did not exist (or were deleted) at that time get a delete marker.
+pub fn restore_prefix(bucket: &str, prefix: &str, timestamp: u64) -> Result<RestoreSummary, Error> {
+    if !VERSION_STORE.get_status(bucket).is_versioned() {
+        return Err(actix_web::error::ErrorBadRequest("Bucket versioning is not enabled"));
+    }
+    let mut summary = RestoreSummary::default();
+    for (latest, at_time) in versions_at(bucket, prefix, timestamp) {
+        restore_key(&latest, at_time.as_ref(), &mut summary)?;
+    }
+    Ok(summary)
+}
+
+// Bring the whole bucket back to an exact set of versions (key -> version);
+// keys missing from `versions` are deleted
+pub fn restore_versions(bucket: &str, versions: &BTreeMap<String, VersionInfo>) -> Result<RestoreSummary, Error> {
+    if !VERSION_STORE.get_status(bucket).is_versioned() {
+        return Err(actix_web::error::ErrorBadRequest("Bucket versioning is not enabled"));
+    }
+    let mut summary = RestoreSummary::default();
+    for (latest, _) in versions_at(bucket, "", 0) {
+        restore_key(&latest, versions.get(&latest.key), &mut summary)?;
+    }
+    Ok(summary)
+}
+
+// Helper function to delete an object: unversioned buckets remove it
+// outright, versioned buckets get a delete marker
+pub fn delete_object(bucket: &str, key: &str) -> Result<Option<VersionInfo>, Error> {
+    if VERSION_STORE.get_status(bucket).is_versioned() {
+        return create_delete_marker(bucket, key).map(Some);
+    }
+    remove_version(bucket, key, NULL_VERSION_ID, false)?;
+    Ok(None)
+}
+
 // Helper function to create a delete marker
-pub fn create_delete_marker(bucket: &str, key: &str) -> Result<(), Error> {
-    let version_id = generate_version_id();
-    
-    let version_info = VersionInfo {
-        version_id: version_id.clone(),
+pub fn create_delete_marker(bucket: &str, key: &str) -> Result<VersionInfo, Error> {
+    add_new_version(VersionInfo {
         bucket: bucket.to_string(),
         key: key.to_string(),
-        is_latest: true,
         is_delete_marker: true,
-        size: 0,
-        last_modified: get_current_timestamp(),
-        etag: "".to_string(),
-    };
+        ..Default::default()
+    })
+}
 
-    VERSION_STORE.add_version(bucket, key, version_info);
-    Ok(())
+// Add a version with a chosen ID and modification time; `None` content
+// adds a delete marker
+#[cfg(test)]
+pub fn add_version_at(bucket: &str, key: &str, version_id: &str, content: Option<&[u8]>, last_modified: u64) {
+    let blob = content.map(|c| BLOB_STORE.put(bucket, c).unwrap()).unwrap_or_default();
+    VERSION_STORE.add_version(bucket, key, VersionInfo {
+        version_id: version_id.to_string(),
+        bucket: bucket.to_string(),
+        key: key.to_string(),
+        is_latest: true,
+        is_delete_marker: content.is_none(),
+        size: content.map_or(0, |c| c.len() as u64),
+        last_modified,
+        blob,
+        ..Default::default()
+    }).unwrap();
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::controller::router;
     use actix_web::test;
+    use std::fs;
+    use std::path::Path;
+
+    fn cleanup_bucket(bucket: &str) {
+        if Path::new(bucket).exists() {
+            let _ = fs::remove_dir_all(bucket);
+        }
+    }
 
     #[actix_web::test]
     async fn test_put_bucket_versioning() {
         let app = test::init_service(
-            actix_web::App::new()
-                .service(put_bucket_versioning)
+            actix_web::App::new().configure(router::configure)
         ).await;
 
         let req = test::TestRequest::put()
-            .uri("/test-bucket")
-            .set_json(&VersioningConfiguration {
-                status: "Enabled".to_string(),
-            })
+            .uri("/test-bucket?versioning")
+            .set_payload(r#"<VersioningConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+                <Status>Enabled</Status>
+            </VersioningConfiguration>"#)
             .to_request();
 
         let resp = test::call_service(&app, req).await;
         assert!(resp.status().is_success());
+        assert_eq!(VERSION_STORE.get_status("test-bucket"), VersioningStatus::Enabled);
+
+        let req = test::TestRequest::put()
+            .uri("/test-bucket?versioning")
+            .set_payload("<VersioningConfiguration><Status>Off</Status></VersioningConfiguration>")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
     }
 
     #[actix_web::test]
     async fn test_get_bucket_versioning() {
         let app = test::init_service(
-            actix_web::App::new()
-                .service(get_bucket_versioning)
+            actix_web::App::new().configure(router::configure)
         ).await;
 
         let req = test::TestRequest::get()
-            .uri("/test-bucket")
+            .uri("/test-bucket-unversioned?versioning")
             .to_request();
 
         let resp = test::call_service(&app, req).await;
         assert!(resp.status().is_success());
+        let body = test::read_body(resp).await;
+        assert!(!std::str::from_utf8(&body).unwrap().contains("<Status>"));
+
+        VERSION_STORE.set_status("test-bucket-suspended", VersioningStatus::Suspended);
+        let req = test::TestRequest::get()
+            .uri("/test-bucket-suspended?versioning")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let body = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("<Status>Suspended</Status>"));
+    }
+
+    fn version_entries(body: &str) -> Vec<(String, String)> {
+        body.split('<')
+            .filter(|tag| tag.starts_with("Version>") || tag.starts_with("DeleteMarker>"))
+            .map(|tag| tag.split('>').next().unwrap().to_string())
+            .zip(xml::elements(body, "Key").into_iter().map(str::to_string))
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn test_list_object_versions_paging() {
+        let bucket = "testbucket_ver_list";
+        cleanup_bucket(bucket);
+        VERSION_STORE.set_status(bucket, VersioningStatus::Enabled);
+        for key in ["b&c", "a", "dir/one", "dir/two"] {
+            create_version(bucket, key, b"data", "etag", HashMap::new(), Vec::new()).unwrap();
+        }
+        let newest = create_version(bucket, "a", b"data2", "etag2", HashMap::new(), Vec::new()).unwrap();
+        create_delete_marker(bucket, "b&c").unwrap();
+
+        let app = test::init_service(actix_web::App::new().configure(router::configure)).await;
+
+        // Key ascending, newest first, delete markers interleaved, keys escaped
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}?versions&delimiter=/", bucket))
+            .to_request();
+        let body = test::read_body(test::call_service(&app, req).await).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(version_entries(body), vec![
+            ("Version".to_string(), "a".to_string()),
+            ("Version".to_string(), "a".to_string()),
+            ("DeleteMarker".to_string(), "b&amp;c".to_string()),
+            ("Version".to_string(), "b&amp;c".to_string()),
+        ]);
+        assert!(body.contains("<CommonPrefixes><Prefix>dir/</Prefix></CommonPrefixes>"));
+        assert!(body.contains("<IsTruncated>false</IsTruncated>"));
+
+        // Page through with max-keys=1
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}?versions&max-keys=1", bucket))
+            .to_request();
+        let body = test::read_body(test::call_service(&app, req).await).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("<IsTruncated>true</IsTruncated>"));
+        assert_eq!(xml::element_text(body, "NextKeyMarker"), Some("a"));
+        assert_eq!(xml::element_text(body, "NextVersionIdMarker"), Some(newest.version_id.as_str()));
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}?versions&max-keys=2&key-marker=a&version-id-marker={}", bucket, newest.version_id))
+            .to_request();
+        let body = test::read_body(test::call_service(&app, req).await).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(version_entries(body), vec![
+            ("Version".to_string(), "a".to_string()),
+            ("DeleteMarker".to_string(), "b&amp;c".to_string()),
+        ]);
+
+        // Prefix filtering
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}?versions&prefix=dir/", bucket))
+            .to_request();
+        let body = test::read_body(test::call_service(&app, req).await).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(version_entries(body).len(), 2);
+        cleanup_bucket(bucket);
+    }
+
+    #[actix_web::test]
+    async fn test_identical_versions_share_a_blob() {
+        let bucket = "testbucket_ver_dedup";
+        cleanup_bucket(bucket);
+        VERSION_STORE.set_status(bucket, VersioningStatus::Enabled);
+        let first = create_version(bucket, "a", b"same", "e", HashMap::new(), Vec::new()).unwrap();
+        let second = create_version(bucket, "b", b"same", "e", HashMap::new(), Vec::new()).unwrap();
+        assert_eq!(first.blob, second.blob);
+        assert_eq!(BLOB_STORE.ref_count(bucket, &first.blob), 2);
+
+        remove_version(bucket, "a", &first.version_id, false).unwrap();
+        remove_version(bucket, "b", &second.version_id, false).unwrap();
+        assert_eq!(BLOB_STORE.ref_count(bucket, &first.blob), 0);
+        cleanup_bucket(bucket);
+    }
+
+    #[actix_web::test]
+    async fn test_unversioned_bucket_overwrites_in_place() {
+        let bucket = "testbucket_ver_unversioned";
+        cleanup_bucket(bucket);
+        create_version(bucket, "key", b"one", "e1", HashMap::new(), Vec::new()).unwrap();
+        create_version(bucket, "key", b"two", "e2", HashMap::new(), Vec::new()).unwrap();
+        let versions = VERSION_STORE.get_versions(bucket, "key");
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].version_id, NULL_VERSION_ID);
+        assert_eq!(versions[0].etag, "e2");
+
+        assert!(delete_object(bucket, "key").unwrap().is_none());
+        assert!(VERSION_STORE.get_versions(bucket, "key").is_empty());
+        cleanup_bucket(bucket);
+    }
+
+    #[actix_web::test]
+    async fn test_versions_survive_a_restart() {
+        let bucket = "testbucket_ver_reload";
+        cleanup_bucket(bucket);
+        VERSION_STORE.set_status(bucket, VersioningStatus::Enabled);
+        let old = create_version(bucket, "key", b"one", "e1", HashMap::new(), Vec::new()).unwrap();
+        create_version(bucket, "key", b"two", "e2", HashMap::new(), Vec::new()).unwrap();
+
+        // Forget everything held in memory, then read the index back
+        VERSION_STORE.versions.lock().unwrap().remove(bucket);
+        BLOB_STORE.forget_bucket(bucket);
+        load_bucket_versions(bucket).unwrap();
+
+        let latest = VERSION_STORE.get_latest_version(bucket, "key").unwrap();
+        assert_eq!(read_version(&latest).unwrap(), b"two");
+        let old = VERSION_STORE.get_version(bucket, "key", &old.version_id).unwrap();
+        assert_eq!(read_version(&old).unwrap(), b"one");
+        assert_eq!(BLOB_STORE.ref_count(bucket, &old.blob), 1);
+        cleanup_bucket(bucket);
     }
-} 
\ No newline at end of file
+
+    #[actix_web::test]
+    async fn test_enabled_then_suspended_versioning() {
+        let bucket = "testbucket_ver_suspended";
+        cleanup_bucket(bucket);
+        VERSION_STORE.set_status(bucket, VersioningStatus::Enabled);
+        create_version(bucket, "key", b"one", "e1", HashMap::new(), Vec::new()).unwrap();
+        create_version(bucket, "key", b"two", "e2", HashMap::new(), Vec::new()).unwrap();
+        assert_eq!(VERSION_STORE.get_versions(bucket, "key").len(), 2);
+
+        // Suspended writes keep history but replace the single null version
+        VERSION_STORE.set_status(bucket, VersioningStatus::Suspended);
+        create_version(bucket, "key", b"three", "e3", HashMap::new(), Vec::new()).unwrap();
+        let marker = delete_object(bucket, "key").unwrap().expect("Expected a delete marker");
+        assert_eq!(marker.version_id, NULL_VERSION_ID);
+        let versions = VERSION_STORE.get_versions(bucket, "key");
+        assert_eq!(versions.len(), 3);
+        assert!(versions[2].is_delete_marker && versions[2].is_latest);
+
+        // Removing the delete marker brings back the previous version
+        remove_version(bucket, "key", NULL_VERSION_ID, false).unwrap();
+        let latest = VERSION_STORE.get_latest_version(bucket, "key").unwrap();
+        assert_eq!(latest.etag, "e2");
+        assert_eq!(read_version(&latest).unwrap(), b"two");
+        cleanup_bucket(bucket);
+    }
+}
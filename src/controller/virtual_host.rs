@@ -0,0 +1,119 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Uri;
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage};
+use std::sync::RwLock;
+use lazy_static::lazy_static;
+use crate::config;
+use crate::controller::router;
+
+// Virtual-hosted-style addressing: `bucket.s3.example.com/key` is rewritten
+// to the path-style `/bucket/key` the handlers are routed on
+
+// The path a client sent before it was rewritten, which is what it signed
+#[derive(Clone)]
+pub struct OriginalPath(pub String);
+
+lazy_static! {
+    // Domain suffixes that buckets are addressed under, longest first
+    static ref DOMAINS: RwLock<Vec<String>> = RwLock::new(normalize(config::Config::default().domains));
+}
+
+fn normalize(domains: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut domains: Vec<String> = domains.into_iter()
+        .map(|domain| domain.trim().trim_matches('.').to_ascii_lowercase())
+        .filter(|domain| !domain.is_empty())
+        .collect();
+    domains.sort_by_key(|domain| std::cmp::Reverse(domain.len()));
+    domains
+}
+
+fn set_domains(domains: impl IntoIterator<Item = String>) {
+    *DOMAINS.write().unwrap() = normalize(domains);
+}
+
+// Use the configured domain suffixes
+pub fn load_domains() {
+    set_domains(config::current().domains.clone());
+}
+
+// The bucket named by a `{bucket}.{domain}` host, if any
+pub fn bucket_from_host(host: &str) -> Option<String> {
+    let host = host.rsplit_once(':').map_or(host, |(name, _)| name).to_ascii_lowercase();
+    DOMAINS.read().unwrap().iter().find_map(|domain| {
+        host.strip_suffix(domain.as_str())
+            .and_then(|rest| rest.strip_suffix('.'))
+            .filter(|bucket| !bucket.is_empty())
+            .map(str::to_string)
+    })
+}
+
+// Middleware rewriting virtual-hosted requests to path-style; requests to
+// a bare domain or an unknown host are left as they are
+pub async fn rewrite_host(mut req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let host = req.connection_info().host().to_string();
+    if let Some(bucket) = bucket_from_host(&host) {
+        // `admin.{domain}/...` must not reach the admin routes
+        if router::is_reserved_bucket(&bucket) {
+            return Err(actix_web::error::ErrorBadRequest(format!("InvalidBucketName: {} is reserved", bucket)));
+        }
+        let original = req.uri().path().to_string();
+        let path = match original.trim_start_matches('/') {
+            "" => format!("/{}", bucket),
+            key => format!("/{}/{}", bucket, key),
+        };
+        let path_and_query = match req.uri().query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = Some(path_and_query.parse()
+            .map_err(|_| actix_web::error::ErrorBadRequest("Invalid path"))?);
+        let uri = Uri::from_parts(parts).map_err(|_| actix_web::error::ErrorBadRequest("Invalid path"))?;
+        req.match_info_mut().get_mut().update(&uri);
+        req.head_mut().uri = uri;
+        req.extensions_mut().insert(OriginalPath(original));
+    }
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpRequest, HttpResponse, get};
+
+    #[get("/{bucket}/{key:.*}")]
+    async fn echo(req: HttpRequest, path: web::Path<(String, String)>) -> HttpResponse {
+        let original = req.extensions().get::<OriginalPath>().map(|path| path.0.clone()).unwrap_or_default();
+        HttpResponse::Ok().body(format!("{} {} {} {}", path.0, path.1, req.query_string(), original))
+    }
+
+    #[actix_rt::test]
+    async fn test_virtual_hosted_requests() {
+        set_domains(["s3.example.com".to_string(), "example.com".to_string()]);
+        assert_eq!(bucket_from_host("photos.s3.example.com:8080").as_deref(), Some("photos"));
+        assert_eq!(bucket_from_host("my.photos.example.com").as_deref(), Some("my.photos"));
+        assert_eq!(bucket_from_host("example.com"), None);
+        assert_eq!(bucket_from_host("127.0.0.1:8080"), None);
+
+        let app = test::init_service(App::new()
+            .wrap(actix_web::middleware::from_fn(rewrite_host))
+            .service(echo)).await;
+        let req = test::TestRequest::get()
+            .uri("/docs/readme.md?versionId=1")
+            .insert_header(("Host", "photos.s3.example.com"))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body, "photos docs/readme.md versionId=1 /docs/readme.md");
+
+        // Path-style requests still work
+        let req = test::TestRequest::get()
+            .uri("/photos/cat.png")
+            .insert_header(("Host", "example.com"))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body, "photos cat.png  ");
+        set_domains(config::Config::default().domains);
+    }
+}
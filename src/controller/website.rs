@@ -0,0 +1,505 @@
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse, Error};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use crate::config;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use crate::controller::{bucket, cors, encryption, object, tagging, versioning};
+use crate::controller::versioning::VersionInfo;
+use crate::crypto::EncryptionMode;
+use crate::xml;
+
+const WEBSITE_FILE: &str = "bucket_website.json";
+// Per-object redirect, stored with the object's metadata
+pub const REDIRECT_LOCATION_HEADER: &str = "x-amz-website-redirect-location";
+// Host label that separates the bucket name in website endpoint host names,
+// as in `docs.s3-website.localhost`
+const WEBSITE_HOST_LABEL: &str = "s3-website";
+
+// Where a redirect goes; unset parts keep the request's protocol, host or key
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Redirect {
+    #[serde(default)]
+    protocol: Option<String>,
+    #[serde(default)]
+    host_name: Option<String>,
+    #[serde(default)]
+    replace_key_prefix_with: Option<String>,
+    #[serde(default)]
+    replace_key_with: Option<String>,
+    #[serde(default)]
+    http_redirect_code: Option<u16>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RoutingRule {
+    #[serde(default)]
+    key_prefix_equals: Option<String>,
+    #[serde(default)]
+    http_error_code_returned_equals: Option<u16>,
+    redirect: Redirect,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct WebsiteConfig {
+    #[serde(default)]
+    index_suffix: Option<String>,
+    #[serde(default)]
+    error_document: Option<String>,
+    #[serde(default)]
+    redirect_all: Option<Redirect>,
+    #[serde(default)]
+    routing_rules: Vec<RoutingRule>,
+}
+
+lazy_static! {
+    static ref BUCKET_WEBSITE: Mutex<HashMap<String, WebsiteConfig>> = Mutex::new(HashMap::new());
+}
+
+pub fn load_website() -> std::io::Result<()> {
+    if config::metadata_path(WEBSITE_FILE).exists() {
+        let mut file = File::open(config::metadata_path(WEBSITE_FILE))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let configs: HashMap<String, WebsiteConfig> = serde_json::from_str(&contents)?;
+        *BUCKET_WEBSITE.lock().unwrap() = configs;
+    }
+    Ok(())
+}
+
+fn save_website() -> std::io::Result<()> {
+    let configs = BUCKET_WEBSITE.lock().unwrap();
+    let contents = serde_json::to_string_pretty(&*configs)?;
+    let mut file = File::create(config::metadata_path(WEBSITE_FILE))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+fn malformed(message: &str) -> Error {
+    actix_web::error::ErrorBadRequest(format!("MalformedXML: {}", message))
+}
+
+fn parse_redirect(body: &str) -> Result<Redirect, Error> {
+    let text = |tag: &str| xml::element_text(body, tag).map(xml::unescape);
+    let redirect = Redirect {
+        protocol: text("Protocol"),
+        host_name: text("HostName"),
+        replace_key_prefix_with: text("ReplaceKeyPrefixWith"),
+        replace_key_with: text("ReplaceKeyWith"),
+        http_redirect_code: text("HttpRedirectCode")
+            .map(|code| code.parse().map_err(|_| malformed("invalid HttpRedirectCode")))
+            .transpose()?,
+    };
+    if redirect.protocol.as_deref().is_some_and(|protocol| protocol != "http" && protocol != "https") {
+        return Err(malformed("Protocol must be http or https"));
+    }
+    if redirect.replace_key_prefix_with.is_some() && redirect.replace_key_with.is_some() {
+        return Err(malformed("ReplaceKeyPrefixWith and ReplaceKeyWith cannot both be set"));
+    }
+    if redirect.http_redirect_code.is_some_and(|code| !(300..400).contains(&code)) {
+        return Err(malformed("HttpRedirectCode must be a 3XX code"));
+    }
+    Ok(redirect)
+}
+
+fn parse_website(body: &[u8]) -> Result<WebsiteConfig, Error> {
+    let body = std::str::from_utf8(body)
+        .map_err(|_| actix_web::error::ErrorBadRequest("MalformedXML"))?;
+    if let Some(redirect_all) = xml::element_text(body, "RedirectAllRequestsTo") {
+        let redirect = parse_redirect(redirect_all)?;
+        if redirect.host_name.is_none() {
+            return Err(malformed("RedirectAllRequestsTo requires a HostName"));
+        }
+        return Ok(WebsiteConfig { redirect_all: Some(redirect), ..Default::default() });
+    }
+    let index_suffix = xml::element_text(body, "IndexDocument")
+        .and_then(|index| xml::element_text(index, "Suffix"))
+        .map(xml::unescape)
+        .filter(|suffix| !suffix.is_empty() && !suffix.contains('/'))
+        .ok_or_else(|| malformed("IndexDocument requires a Suffix without slashes"))?;
+    let error_document = xml::element_text(body, "ErrorDocument")
+        .and_then(|error| xml::element_text(error, "Key"))
+        .map(xml::unescape);
+    let mut routing_rules = Vec::new();
+    for rule in xml::elements(body, "RoutingRule") {
+        let condition = xml::element_text(rule, "Condition").unwrap_or_default();
+        let redirect = xml::element_text(rule, "Redirect")
+            .ok_or_else(|| malformed("RoutingRule requires a Redirect"))?;
+        routing_rules.push(RoutingRule {
+            key_prefix_equals: xml::element_text(condition, "KeyPrefixEquals").map(xml::unescape),
+            http_error_code_returned_equals: xml::element_text(condition, "HttpErrorCodeReturnedEquals")
+                .map(|code| code.parse().map_err(|_| malformed("invalid HttpErrorCodeReturnedEquals")))
+                .transpose()?,
+            redirect: parse_redirect(redirect)?,
+        });
+    }
+    Ok(WebsiteConfig { index_suffix: Some(index_suffix), error_document, redirect_all: None, routing_rules })
+}
+
+fn render_redirect(redirect: &Redirect) -> String {
+    let element = |tag: &str, value: Option<String>| {
+        value.map(|value| format!("<{}>{}</{}>", tag, xml::escape(&value), tag)).unwrap_or_default()
+    };
+    [
+        element("Protocol", redirect.protocol.clone()),
+        element("HostName", redirect.host_name.clone()),
+        element("ReplaceKeyPrefixWith", redirect.replace_key_prefix_with.clone()),
+        element("ReplaceKeyWith", redirect.replace_key_with.clone()),
+        element("HttpRedirectCode", redirect.http_redirect_code.map(|code| code.to_string())),
+    ].concat()
+}
+
+fn render_website(config: &WebsiteConfig) -> String {
+    if let Some(redirect) = &config.redirect_all {
+        return format!("<RedirectAllRequestsTo>{}</RedirectAllRequestsTo>", render_redirect(redirect));
+    }
+    let mut body = format!(
+        "<IndexDocument><Suffix>{}</Suffix></IndexDocument>",
+        xml::escape(config.index_suffix.as_deref().unwrap_or_default())
+    );
+    if let Some(key) = &config.error_document {
+        body.push_str(&format!("<ErrorDocument><Key>{}</Key></ErrorDocument>", xml::escape(key)));
+    }
+    if !config.routing_rules.is_empty() {
+        body.push_str("<RoutingRules>");
+        for rule in &config.routing_rules {
+            body.push_str("<RoutingRule>");
+            if rule.key_prefix_equals.is_some() || rule.http_error_code_returned_equals.is_some() {
+                body.push_str("<Condition>");
+                if let Some(prefix) = &rule.key_prefix_equals {
+                    body.push_str(&format!("<KeyPrefixEquals>{}</KeyPrefixEquals>", xml::escape(prefix)));
+                }
+                if let Some(code) = rule.http_error_code_returned_equals {
+                    body.push_str(&format!("<HttpErrorCodeReturnedEquals>{}</HttpErrorCodeReturnedEquals>", code));
+                }
+                body.push_str("</Condition>");
+            }
+            body.push_str(&format!("<Redirect>{}</Redirect></RoutingRule>", render_redirect(&rule.redirect)));
+        }
+        body.push_str("</RoutingRules>");
+    }
+    body
+}
+
+pub async fn put_bucket_website(path: web::Path<String>, body: web::Bytes) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    let config = parse_website(&body)?;
+    BUCKET_WEBSITE.lock().unwrap().insert(bucket, config);
+    save_website().map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn get_bucket_website(path: web::Path<String>) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    let config = BUCKET_WEBSITE.lock().unwrap().get(&bucket).cloned()
+        .ok_or_else(|| actix_web::error::ErrorNotFound("NoSuchWebsiteConfiguration"))?;
+    let response = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<WebsiteConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/">{}</WebsiteConfiguration>"#,
+        render_website(&config)
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(response))
+}
+
+pub async fn delete_bucket_website(path: web::Path<String>) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    BUCKET_WEBSITE.lock().unwrap().remove(&bucket);
+    save_website().map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// The bucket a website request is for: the labels before `s3-website`, or
+// the whole host name for buckets named after their domain
+pub fn bucket_from_host(host: &str) -> String {
+    let host = host.rsplit_once(':').map_or(host, |(name, _)| name);
+    let labels: Vec<&str> = host.split('.').collect();
+    match labels.iter().position(|label| label.starts_with(WEBSITE_HOST_LABEL)) {
+        Some(end) => labels[..end].join("."),
+        None => host.to_string(),
+    }
+}
+
+fn redirect_response(req: &HttpRequest, redirect: &Redirect, key: String, default_code: u16) -> HttpResponse {
+    let connection = req.connection_info();
+    let location = format!(
+        "{}://{}/{}",
+        redirect.protocol.as_deref().unwrap_or(connection.scheme()),
+        redirect.host_name.as_deref().unwrap_or(connection.host()),
+        key
+    );
+    let code = StatusCode::from_u16(redirect.http_redirect_code.unwrap_or(default_code))
+        .unwrap_or(StatusCode::MOVED_PERMANENTLY);
+    HttpResponse::build(code).insert_header(("Location", location)).finish()
+}
+
+// Apply the first routing rule matching `key` (and the error, if any)
+fn route(req: &HttpRequest, config: &WebsiteConfig, key: &str, error: Option<u16>) -> Option<HttpResponse> {
+    let rule = config.routing_rules.iter().find(|rule| {
+        rule.key_prefix_equals.as_deref().is_none_or(|prefix| key.starts_with(prefix))
+            && rule.http_error_code_returned_equals == error
+    })?;
+    let redirect = &rule.redirect;
+    let key = match (&redirect.replace_key_with, &redirect.replace_key_prefix_with) {
+        (Some(replacement), _) => replacement.clone(),
+        (None, Some(replacement)) => {
+            let prefix = rule.key_prefix_equals.as_deref().unwrap_or_default();
+            format!("{}{}", replacement, &key[prefix.len()..])
+        }
+        (None, None) => key.to_string(),
+    };
+    Some(redirect_response(req, redirect, key, 301))
+}
+
+// The latest readable version of `key`, if it exists. Visitors are
+// anonymous, so only objects the bucket policy makes public are served, and
+// since they can't send SSE-C keys customer-encrypted objects are treated as
+// missing.
+fn find_object(bucket: &str, key: &str) -> Option<VersionInfo> {
+    versioning::VERSION_STORE.get_latest_version(bucket, key)
+        .filter(|version| !version.is_delete_marker)
+        .filter(|version| version.encryption.as_ref().is_none_or(|info| info.mode != EncryptionMode::Customer))
+        .filter(|version| {
+            let context = tagging::condition_values(version);
+            bucket::policy_allows_public(bucket, "s3:GetObject", &context)
+                && !bucket::policy_denies(bucket, "s3:GetObject", &context)
+        })
+}
+
+fn object_response(req: &HttpRequest, version: &VersionInfo, status: StatusCode) -> Result<HttpResponse, Error> {
+    if let Some(location) = version.metadata.get(REDIRECT_LOCATION_HEADER) {
+        return Ok(HttpResponse::MovedPermanently().insert_header(("Location", location.as_str())).finish());
+    }
+    let mut response = HttpResponse::build(status);
+    response.insert_header(("ETag", version.etag.clone()));
+    for (name, value) in &version.metadata {
+        response.insert_header((name.as_str(), value.as_str()));
+    }
+    for header in encryption::response_headers(version, None) {
+        response.insert_header(header);
+    }
+    if req.method() == Method::HEAD {
+        return Ok(object::head_response(response, version.size));
+    }
+    let content = versioning::read_version_with(version, None)?;
+    Ok(response.body(Bytes::from(content)))
+}
+
+// The error document, or a plain page when the bucket has none
+fn error_response(req: &HttpRequest, bucket: &str, config: &WebsiteConfig, status: StatusCode, message: &str) -> Result<HttpResponse, Error> {
+    if let Some(version) = config.error_document.as_deref().and_then(|key| find_object(bucket, key)) {
+        return object_response(req, &version, status);
+    }
+    Ok(HttpResponse::build(status).content_type("text/html").body(format!(
+        "<html><head><title>{} {}</title></head><body><h1>{} {}</h1><ul><li>Message: {}</li></ul></body></html>",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default(),
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default(),
+        xml::escape(message)
+    )))
+}
+
+// Serve a request on the website endpoint: the bucket comes from the Host
+// header, directory-style paths get the index document, and missing keys
+// get the error document
+pub async fn serve(req: HttpRequest) -> Result<HttpResponse, Error> {
+    let bucket = bucket_from_host(req.connection_info().host());
+    let Some(config) = BUCKET_WEBSITE.lock().unwrap().get(&bucket).cloned() else {
+        let config = WebsiteConfig::default();
+        return error_response(&req, &bucket, &config, StatusCode::NOT_FOUND, "NoSuchWebsiteConfiguration");
+    };
+    if req.method() == Method::OPTIONS {
+        return cors::preflight(&req, &bucket);
+    }
+    let path = req.path().trim_start_matches('/');
+    if let Some(redirect) = &config.redirect_all {
+        return Ok(redirect_response(&req, redirect, path.to_string(), 301));
+    }
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return error_response(&req, &bucket, &config, StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed");
+    }
+    let path = percent_encoding::percent_decode_str(path)
+        .decode_utf8()
+        .map_err(|_| actix_web::error::ErrorBadRequest("Invalid path"))?
+        .into_owned();
+    if let Some(response) = route(&req, &config, &path, None) {
+        return Ok(response);
+    }
+
+    let suffix = config.index_suffix.as_deref().unwrap_or_default();
+    let key = if path.is_empty() || path.ends_with('/') { format!("{}{}", path, suffix) } else { path.clone() };
+    if let Some(version) = find_object(&bucket, &key) {
+        return object_response(&req, &version, StatusCode::OK);
+    }
+    // `docs` is served as the directory `docs/` when it has an index document
+    if key == path && find_object(&bucket, &format!("{}/{}", path, suffix)).is_some() {
+        return Ok(HttpResponse::Found().insert_header(("Location", format!("/{}/", path))).finish());
+    }
+    if let Some(response) = route(&req, &config, &path, Some(404)) {
+        return Ok(response);
+    }
+    error_response(&req, &bucket, &config, StatusCode::NOT_FOUND, "NoSuchKey")
+}
+
+// `PUT /{bucket}?policy` body letting anyone read the bucket's objects, as
+// website tests need
+#[cfg(test)]
+pub fn public_read_policy() -> serde_json::Value {
+    serde_json::json!({
+        "policy": r#"{"Statement": [{"Effect": "Allow", "Principal": "*", "Action": "s3:GetObject"}]}"#,
+        "acl": "",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::router;
+    use crate::crypto;
+    use actix_web::{test, App};
+
+    fn put_object(bucket: &str, key: &str, content: &[u8], metadata: &[(&str, &str)]) {
+        versioning::create_version_from(content, None, VersionInfo {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            etag: format!("{:x}", md5::compute(content)),
+            metadata: metadata.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            ..Default::default()
+        }).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_website_index_and_error_documents() {
+        let bucket = "testbucket_website";
+        let _ = std::fs::remove_dir_all(bucket);
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?website", bucket))
+            .set_payload("<WebsiteConfiguration><IndexDocument><Suffix>index.html</Suffix></IndexDocument>\
+                <ErrorDocument><Key>404.html</Key></ErrorDocument></WebsiteConfiguration>")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let req = test::TestRequest::get().uri(&format!("/{}?website", bucket)).to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert!(String::from_utf8(body.to_vec()).unwrap().contains("<Suffix>index.html</Suffix>"));
+
+        put_object(bucket, "index.html", b"home", &[("content-type", "text/html")]);
+        put_object(bucket, "guide/index.html", b"guide", &[("content-type", "text/html")]);
+        put_object(bucket, "404.html", b"not here", &[("content-type", "text/html")]);
+        put_object(bucket, "old.html", b"", &[(REDIRECT_LOCATION_HEADER, "/guide/")]);
+
+        let site = test::init_service(App::new().default_service(web::to(serve))).await;
+        let get = |path: &str| test::TestRequest::get()
+            .uri(path)
+            .insert_header(("Host", format!("{}.s3-website.localhost:8081", bucket)))
+            .to_request();
+
+        // Nothing is served until the bucket policy makes the objects public
+        let resp = test::call_service(&site, get("/")).await;
+        assert_eq!(resp.status(), 404);
+        assert_ne!(test::read_body(resp).await, Bytes::from_static(b"not here"));
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?policy", bucket))
+            .set_json(serde_json::json!({
+                "policy": r#"{"Statement": [{"Effect": "Allow", "Principal": {"AWS": "someone"}, "Action": "s3:GetObject"}]}"#,
+                "acl": "",
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        assert_eq!(test::call_service(&site, get("/")).await.status(), 404);
+        let req = test::TestRequest::put().uri(&format!("/{}?policy", bucket)).set_json(public_read_policy()).to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let resp = test::call_service(&site, get("/")).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(test::read_body(resp).await, Bytes::from_static(b"home"));
+        let resp = test::call_service(&site, get("/guide/")).await;
+        assert_eq!(test::read_body(resp).await, Bytes::from_static(b"guide"));
+        let resp = test::call_service(&site, get("/guide")).await;
+        assert_eq!(resp.status(), 302);
+        assert_eq!(resp.headers().get("Location").unwrap(), "/guide/");
+        let resp = test::call_service(&site, get("/old.html")).await;
+        assert_eq!(resp.status(), 301);
+        assert_eq!(resp.headers().get("Location").unwrap(), "/guide/");
+        let resp = test::call_service(&site, get("/missing.html")).await;
+        assert_eq!(resp.status(), 404);
+        assert_eq!(test::read_body(resp).await, Bytes::from_static(b"not here"));
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?policy", bucket))
+            .set_json(serde_json::json!({ "policy": "{}", "acl": "" }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let _ = std::fs::remove_dir_all(bucket);
+    }
+
+    #[actix_rt::test]
+    async fn test_website_redirects() {
+        let body = b"<WebsiteConfiguration><IndexDocument><Suffix>index.html</Suffix></IndexDocument>\
+            <RoutingRules><RoutingRule><Condition><KeyPrefixEquals>docs/</KeyPrefixEquals></Condition>\
+            <Redirect><ReplaceKeyPrefixWith>documents/</ReplaceKeyPrefixWith></Redirect></RoutingRule>\
+            <RoutingRule><Condition><HttpErrorCodeReturnedEquals>404</HttpErrorCodeReturnedEquals></Condition>\
+            <Redirect><HostName>fallback.example.com</HostName><HttpRedirectCode>302</HttpRedirectCode></Redirect>\
+            </RoutingRule></RoutingRules></WebsiteConfiguration>";
+        let config = parse_website(body).unwrap();
+        BUCKET_WEBSITE.lock().unwrap().insert("testbucket_website_routing".to_string(), config);
+        let redirect_all = parse_website(b"<WebsiteConfiguration><RedirectAllRequestsTo>\
+            <HostName>www.example.com</HostName><Protocol>https</Protocol></RedirectAllRequestsTo></WebsiteConfiguration>").unwrap();
+        BUCKET_WEBSITE.lock().unwrap().insert("testbucket_website_moved".to_string(), redirect_all);
+
+        let site = test::init_service(App::new().default_service(web::to(serve))).await;
+        let get = |host: &str, path: &str| test::TestRequest::get().uri(path).insert_header(("Host", host)).to_request();
+        let resp = test::call_service(&site, get("testbucket_website_routing.s3-website.localhost", "/docs/a.html")).await;
+        assert_eq!(resp.status(), 301);
+        assert_eq!(resp.headers().get("Location").unwrap(), "http://testbucket_website_routing.s3-website.localhost/documents/a.html");
+        let resp = test::call_service(&site, get("testbucket_website_routing.s3-website.localhost", "/gone.html")).await;
+        assert_eq!(resp.status(), 302);
+        assert_eq!(resp.headers().get("Location").unwrap(), "http://fallback.example.com/gone.html");
+        let resp = test::call_service(&site, get("testbucket_website_moved.s3-website.localhost", "/a/b")).await;
+        assert_eq!(resp.status(), 301);
+        assert_eq!(resp.headers().get("Location").unwrap(), "https://www.example.com/a/b");
+
+        assert!(parse_website(b"<WebsiteConfiguration><IndexDocument><Suffix>a/b</Suffix></IndexDocument></WebsiteConfiguration>").is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_website_customer_encrypted_objects() {
+        let bucket = "testbucket_website_sse_c";
+        let _ = std::fs::remove_dir_all(bucket);
+        let config = parse_website(b"<WebsiteConfiguration><IndexDocument><Suffix>index.html</Suffix></IndexDocument>\
+            <ErrorDocument><Key>404.html</Key></ErrorDocument></WebsiteConfiguration>").unwrap();
+        BUCKET_WEBSITE.lock().unwrap().insert(bucket.to_string(), config);
+        let key = crypto::CustomerKey::new([7; 32], String::new());
+        versioning::create_version_from(b"private", Some(&crypto::Sse::Customer(key)), VersionInfo {
+            bucket: bucket.to_string(),
+            key: "index.html".to_string(),
+            ..Default::default()
+        }).unwrap();
+        put_object(bucket, "404.html", b"not here", &[]);
+        let app = test::init_service(App::new().configure(router::configure)).await;
+        let req = test::TestRequest::put().uri(&format!("/{}?policy", bucket)).set_json(public_read_policy()).to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        // Served as missing rather than failing to decrypt
+        let site = test::init_service(App::new().default_service(web::to(serve))).await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header(("Host", format!("{}.s3-website.localhost", bucket)))
+            .to_request();
+        let resp = test::call_service(&site, req).await;
+        assert_eq!(resp.status(), 404);
+        assert_eq!(test::read_body(resp).await, Bytes::from_static(b"not here"));
+        let req = test::TestRequest::put()
+            .uri(&format!("/{}?policy", bucket))
+            .set_json(serde_json::json!({ "policy": "{}", "acl": "" }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        BUCKET_WEBSITE.lock().unwrap().remove(bucket);
+        let _ = std::fs::remove_dir_all(bucket);
+    }
+}
@@ -0,0 +1,329 @@
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use crate::config;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+use crate::kms;
+
+// Encryption of object data at rest. Every object gets its own AES-256-GCM
+// data key, which is stored next to the version wrapped by the master key
+// (SSE-S3), by a KMS master key (SSE-KMS) or by a key supplied with each
+// request (SSE-C). Customer keys are never stored; only a salted fingerprint
+// is kept to recognize them. Data is sealed in fixed-size chunks, each with
+// its own tag, so a byte range can be served by decrypting only the chunks
+// that cover it.
+
+const MASTER_KEY_FILE: &str = "master.key";
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+const TAG_SIZE: u64 = 16;
+const NONCE_SIZE: usize = 12;
+
+// A customer-provided key (SSE-C). Lives only as long as the request.
+#[derive(Clone, PartialEq, Eq)]
+pub struct CustomerKey {
+    key: [u8; 32],
+    pub key_md5: String, // base64 MD5 of the key, echoed back to clients
+}
+
+impl CustomerKey {
+    pub fn new(key: [u8; 32], key_md5: String) -> Self {
+        CustomerKey { key, key_md5 }
+    }
+}
+
+// Encryption requested for a new version
+#[derive(Clone)]
+pub enum Sse {
+    S3,
+    Kms(String), // KMS master key ID
+    Customer(CustomerKey),
+}
+
+impl Sse {
+    // Value of `x-amz-server-side-encryption` or of
+    // `x-amz-server-side-encryption-customer-algorithm`
+    pub fn algorithm(&self) -> &'static str {
+        match self {
+            Sse::S3 | Sse::Customer(_) => "AES256",
+            Sse::Kms(_) => "aws:kms",
+        }
+    }
+
+    pub fn customer_key(&self) -> Option<&CustomerKey> {
+        match self {
+            Sse::Customer(key) => Some(key),
+            Sse::S3 | Sse::Kms(_) => None,
+        }
+    }
+}
+
+// Which kind of key protects a version's data key
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EncryptionMode {
+    S3,
+    Kms,
+    Customer,
+}
+
+// How a version's data is encrypted
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptionInfo {
+    pub mode: EncryptionMode,
+    pub wrapped_key: String, // hex of nonce || data key sealed by the wrapping key, or the KMS ciphertext
+    #[serde(default)]
+    pub key_fingerprint: Option<String>, // hex of salt || SHA-256(salt || customer key)
+    #[serde(default)]
+    pub key_id: Option<String>, // KMS master key the data key was generated under
+}
+
+lazy_static! {
+    static ref MASTER_KEY: Mutex<Option<[u8; 32]>> = Mutex::new(None);
+}
+
+// Load the master key from its key file, generating one on first start
+pub fn load_master_key() -> io::Result<()> {
+    master_key().map(|_| ())
+}
+
+fn master_key() -> io::Result<[u8; 32]> {
+    let mut master = MASTER_KEY.lock().unwrap();
+    if let Some(key) = *master {
+        return Ok(key);
+    }
+    let key = if config::metadata_path(MASTER_KEY_FILE).exists() {
+        let mut key = [0u8; 32];
+        hex::decode_to_slice(fs::read_to_string(config::metadata_path(MASTER_KEY_FILE))?.trim(), &mut key)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid master key file"))?;
+        key
+    } else {
+        let key: [u8; 32] = Aes256Gcm::generate_key(OsRng).into();
+        let mut file = File::create(config::metadata_path(MASTER_KEY_FILE))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(hex::encode(key).as_bytes())?;
+        key
+    };
+    *master = Some(key);
+    Ok(key)
+}
+
+fn crypto_error<E>(_: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Failed to decrypt object data")
+}
+
+// Chunk nonces are derived from the chunk index; the final chunk is flagged
+// so a truncated ciphertext cannot pass for a shorter object
+fn chunk_nonce(index: u64, last: bool) -> Nonce<aes_gcm::aead::consts::U12> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[0] = last as u8;
+    nonce[4..].copy_from_slice(&index.to_be_bytes());
+    nonce.into()
+}
+
+fn chunk_count(size: u64) -> u64 {
+    size.div_ceil(CHUNK_SIZE).max(1)
+}
+
+// Index of the chunk holding plaintext offset `offset`; offsets at or past
+// the end fall in the last chunk
+fn chunk_index(offset: u64, size: u64) -> u64 {
+    (offset / CHUNK_SIZE).min(chunk_count(size) - 1)
+}
+
+fn fingerprint(salt: &[u8], key: &CustomerKey) -> String {
+    let digest = Sha256::new().chain_update(salt).chain_update(key.key).finalize();
+    hex::encode([salt, digest.as_slice()].concat())
+}
+
+// Whether `key` is the customer key a version was encrypted with
+pub fn key_matches(info: &EncryptionInfo, key: &CustomerKey) -> bool {
+    let Some(stored) = info.key_fingerprint.as_deref() else { return false };
+    let Ok(salt) = hex::decode(stored.get(..32).unwrap_or_default()) else { return false };
+    fingerprint(&salt, key) == stored
+}
+
+// The key that wraps data keys for the given encryption
+fn wrapping_key(mode: EncryptionMode, customer_key: Option<&CustomerKey>) -> io::Result<Aes256Gcm> {
+    let key = match (mode, customer_key) {
+        (EncryptionMode::S3, _) => master_key()?,
+        (EncryptionMode::Customer, Some(customer_key)) => customer_key.key,
+        (EncryptionMode::Customer, None) => {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Customer key required"));
+        }
+        (EncryptionMode::Kms, _) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "KMS data keys are wrapped by the key manager"));
+        }
+    };
+    Ok(Aes256Gcm::new(&key.into()))
+}
+
+fn wrap_key(data_key: &Key<Aes256Gcm>, wrapping: &Aes256Gcm) -> io::Result<String> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let sealed = wrapping.encrypt(&nonce, data_key.as_slice()).map_err(crypto_error)?;
+    Ok(hex::encode([nonce.as_slice(), &sealed].concat()))
+}
+
+fn unwrap_key(info: &EncryptionInfo, customer_key: Option<&CustomerKey>) -> io::Result<Aes256Gcm> {
+    if info.mode == EncryptionMode::Kms {
+        let key_id = info.key_id.as_deref().ok_or_else(|| crypto_error(()))?;
+        let data_key = kms::key_manager().decrypt(key_id, &info.wrapped_key)?;
+        return Ok(Aes256Gcm::new(&data_key.into()));
+    }
+    let wrapped = hex::decode(&info.wrapped_key).map_err(crypto_error)?;
+    if wrapped.len() <= NONCE_SIZE {
+        return Err(crypto_error(()));
+    }
+    let (nonce, sealed) = wrapped.split_at(NONCE_SIZE);
+    let wrapping = wrapping_key(info.mode, customer_key)?;
+    let data_key = wrapping.decrypt(Nonce::from_slice(nonce), sealed).map_err(crypto_error)?;
+    Aes256Gcm::new_from_slice(&data_key).map_err(crypto_error)
+}
+
+// Encrypt `plaintext` under a fresh data key
+pub fn encrypt(plaintext: &[u8], sse: &Sse) -> io::Result<(Vec<u8>, EncryptionInfo)> {
+    let (data_key, info) = match sse {
+        Sse::S3 => {
+            let data_key = Aes256Gcm::generate_key(OsRng);
+            let info = EncryptionInfo {
+                mode: EncryptionMode::S3,
+                wrapped_key: wrap_key(&data_key, &wrapping_key(EncryptionMode::S3, None)?)?,
+                key_fingerprint: None,
+                key_id: None,
+            };
+            (data_key, info)
+        }
+        Sse::Kms(key_id) => {
+            let generated = kms::key_manager().generate_data_key(key_id)?;
+            let info = EncryptionInfo {
+                mode: EncryptionMode::Kms,
+                wrapped_key: generated.ciphertext,
+                key_fingerprint: None,
+                key_id: Some(generated.key_id),
+            };
+            (generated.plaintext.into(), info)
+        }
+        Sse::Customer(key) => {
+            let data_key = Aes256Gcm::generate_key(OsRng);
+            let mut salt = [0u8; 16];
+            OsRng.fill_bytes(&mut salt);
+            let info = EncryptionInfo {
+                mode: EncryptionMode::Customer,
+                wrapped_key: wrap_key(&data_key, &wrapping_key(EncryptionMode::Customer, Some(key))?)?,
+                key_fingerprint: Some(fingerprint(&salt, key)),
+                key_id: None,
+            };
+            (data_key, info)
+        }
+    };
+    let cipher = Aes256Gcm::new(&data_key);
+    let chunks = chunk_count(plaintext.len() as u64);
+    let mut ciphertext = Vec::with_capacity(plaintext.len() + (chunks * TAG_SIZE) as usize);
+    for index in 0..chunks {
+        let start = (index * CHUNK_SIZE) as usize;
+        let end = (start + CHUNK_SIZE as usize).min(plaintext.len());
+        let sealed = cipher.encrypt(&chunk_nonce(index, index + 1 == chunks), &plaintext[start..end])
+            .map_err(crypto_error)?;
+        ciphertext.extend_from_slice(&sealed);
+    }
+    Ok((ciphertext, info))
+}
+
+// Offset and length of the ciphertext holding plaintext bytes `start..end`
+// of an object of `size` bytes
+pub fn ciphertext_span(start: u64, end: u64, size: u64) -> (u64, u64) {
+    let first = chunk_index(start, size);
+    let last = chunk_index(end.max(start + 1) - 1, size);
+    let offset = first * (CHUNK_SIZE + TAG_SIZE);
+    let sealed_end = ((last + 1) * CHUNK_SIZE).min(size) + (last + 1) * TAG_SIZE;
+    (offset, sealed_end - offset)
+}
+
+// Decrypt plaintext bytes `start..end` from the span returned by
+// `ciphertext_span`. SSE-C data needs the customer key.
+pub fn decrypt_range(
+    info: &EncryptionInfo,
+    customer_key: Option<&CustomerKey>,
+    size: u64,
+    start: u64,
+    end: u64,
+    sealed: &[u8],
+) -> io::Result<Vec<u8>> {
+    let cipher = unwrap_key(info, customer_key)?;
+    let chunks = chunk_count(size);
+    let first = chunk_index(start, size);
+    let mut plaintext = Vec::with_capacity(sealed.len());
+    for (i, chunk) in sealed.chunks((CHUNK_SIZE + TAG_SIZE) as usize).enumerate() {
+        let index = first + i as u64;
+        let opened = cipher.decrypt(&chunk_nonce(index, index + 1 == chunks), chunk)
+            .map_err(crypto_error)?;
+        plaintext.extend_from_slice(&opened);
+    }
+    let skip = (start - first * CHUNK_SIZE) as usize;
+    let len = (end - start) as usize;
+    if skip + len > plaintext.len() {
+        return Err(crypto_error(()));
+    }
+    Ok(plaintext[skip..skip + len].to_vec())
+}
+
+// Decrypt a whole object
+pub fn decrypt(info: &EncryptionInfo, customer_key: Option<&CustomerKey>, sealed: &[u8]) -> io::Result<Vec<u8>> {
+    let chunks = (sealed.len() as u64).div_ceil(CHUNK_SIZE + TAG_SIZE).max(1);
+    let size = (sealed.len() as u64).checked_sub(chunks * TAG_SIZE)
+        .ok_or_else(|| crypto_error(()))?;
+    decrypt_range(info, customer_key, size, 0, size, sealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_ranges() {
+        let plaintext: Vec<u8> = (0..3 * CHUNK_SIZE as usize + 100).map(|i| (i % 251) as u8).collect();
+        let size = plaintext.len() as u64;
+        let (sealed, info) = encrypt(&plaintext, &Sse::S3).unwrap();
+        assert_ne!(&sealed[..64], &plaintext[..64]);
+        assert_eq!(decrypt(&info, None, &sealed).unwrap(), plaintext);
+
+        for (start, end) in [(0, 10), (CHUNK_SIZE - 5, CHUNK_SIZE + 5), (size - 1, size), (70_000, 190_000)] {
+            let (offset, len) = ciphertext_span(start, end, size);
+            let span = &sealed[offset as usize..(offset + len) as usize];
+            assert_eq!(decrypt_range(&info, None, size, start, end, span).unwrap(), &plaintext[start as usize..end as usize]);
+        }
+
+        // Empty ranges at the end of an object that fills its last chunk
+        let (sealed, info) = encrypt(&plaintext[..CHUNK_SIZE as usize], &Sse::S3).unwrap();
+        let (offset, len) = ciphertext_span(CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE);
+        assert!(offset + len <= sealed.len() as u64);
+        let span = &sealed[offset as usize..(offset + len) as usize];
+        assert_eq!(decrypt_range(&info, None, CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE, span).unwrap(), b"");
+        let (sealed, info) = encrypt(&plaintext, &Sse::S3).unwrap();
+
+        // Dropping the final chunk is detected
+        let truncated = &sealed[..3 * (CHUNK_SIZE + TAG_SIZE) as usize];
+        assert!(decrypt(&info, None, truncated).is_err());
+        let (sealed, info) = encrypt(b"", &Sse::S3).unwrap();
+        assert_eq!(decrypt(&info, None, &sealed).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_customer_key_is_not_stored() {
+        let key = CustomerKey::new([7; 32], String::new());
+        let (sealed, info) = encrypt(b"customer data", &Sse::Customer(key.clone())).unwrap();
+        assert_eq!(info.mode, EncryptionMode::Customer);
+        assert!(!serde_json::to_string(&info).unwrap().contains(&hex::encode([7; 32])));
+        assert!(key_matches(&info, &key));
+        assert!(!key_matches(&info, &CustomerKey::new([8; 32], String::new())));
+        assert_eq!(decrypt(&info, Some(&key), &sealed).unwrap(), b"customer data");
+        assert!(decrypt(&info, None, &sealed).is_err());
+    }
+}
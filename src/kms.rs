@@ -0,0 +1,368 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use lazy_static::lazy_static;
+use crate::config;
+
+// Key management for SSE-KMS. Data keys are generated and unwrapped by a
+// `KeyManager` holding named master keys; only the wrapped data key and the
+// master key ID are stored with a version.
+
+const KMS_KEYS_FILE: &str = "kms_keys.json";
+const NONCE_SIZE: usize = 12;
+
+// Key used for `aws:kms` requests that don't name one
+pub const DEFAULT_KEY_ID: &str = "aws/s3";
+
+// A fresh data key and its wrapped form
+pub struct DataKey {
+    pub plaintext: [u8; 32],
+    pub ciphertext: String, // opaque to callers, handed back to `decrypt`
+    pub key_id: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyMetadata {
+    pub key_id: String,
+    pub enabled: bool,
+    pub created_at: u64,
+    pub versions: usize, // rotations + 1; 0 when unknown
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "Keys are managed by the remote KMS")
+}
+
+fn not_found(key_id: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("KMS key {} not found", key_id))
+}
+
+fn disabled(key_id: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, format!("KMS key {} is disabled", key_id))
+}
+
+pub trait KeyManager: Send + Sync {
+    fn generate_data_key(&self, key_id: &str) -> io::Result<DataKey>;
+    fn decrypt(&self, key_id: &str, ciphertext: &str) -> io::Result<[u8; 32]>;
+    fn describe_key(&self, key_id: &str) -> io::Result<KeyMetadata>;
+
+    // Administration; remote services manage their keys themselves
+    fn create_key(&self, _key_id: &str) -> io::Result<KeyMetadata> {
+        Err(unsupported())
+    }
+    fn rotate_key(&self, _key_id: &str) -> io::Result<KeyMetadata> {
+        Err(unsupported())
+    }
+    fn set_key_enabled(&self, _key_id: &str, _enabled: bool) -> io::Result<KeyMetadata> {
+        Err(unsupported())
+    }
+    fn list_keys(&self) -> io::Result<Vec<KeyMetadata>> {
+        Err(unsupported())
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct LocalKey {
+    material: Vec<String>, // hex key material, one entry per rotation
+    enabled: bool,
+    created_at: u64,
+}
+
+impl LocalKey {
+    fn new() -> Self {
+        LocalKey {
+            material: vec![hex::encode(Aes256Gcm::generate_key(OsRng))],
+            enabled: true,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        }
+    }
+
+    fn metadata(&self, key_id: &str) -> KeyMetadata {
+        KeyMetadata {
+            key_id: key_id.to_string(),
+            enabled: self.enabled,
+            created_at: self.created_at,
+            versions: self.material.len(),
+        }
+    }
+
+    fn cipher(&self, version: usize) -> io::Result<Aes256Gcm> {
+        let material = self.material.get(version)
+            .and_then(|m| hex::decode(m).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unknown KMS key version"))?;
+        Aes256Gcm::new_from_slice(&material)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid KMS key material"))
+    }
+}
+
+// KMS backed by a local key store file. Rotation adds new key material;
+// data keys wrapped under older material stay readable.
+pub struct LocalKms {
+    path: PathBuf,
+    keys: Mutex<BTreeMap<String, LocalKey>>,
+}
+
+impl LocalKms {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let keys = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(LocalKms { path, keys: Mutex::new(keys) })
+    }
+
+    fn save(&self, keys: &BTreeMap<String, LocalKey>) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(serde_json::to_string_pretty(keys)?.as_bytes())?;
+        fs::rename(&tmp_path, &self.path)
+    }
+
+    // Run `f` on a key, creating the default key on first use
+    fn with_key<T>(&self, key_id: &str, f: impl FnOnce(&LocalKey) -> io::Result<T>) -> io::Result<T> {
+        let mut keys = self.keys.lock().unwrap();
+        if key_id == DEFAULT_KEY_ID && !keys.contains_key(key_id) {
+            keys.insert(key_id.to_string(), LocalKey::new());
+            self.save(&keys)?;
+        }
+        f(keys.get(key_id).ok_or_else(|| not_found(key_id))?)
+    }
+
+    // Change a key and save the key store
+    fn update_key<T>(&self, key_id: &str, f: impl FnOnce(&mut LocalKey) -> T) -> io::Result<T> {
+        let mut keys = self.keys.lock().unwrap();
+        let result = f(keys.get_mut(key_id).ok_or_else(|| not_found(key_id))?);
+        self.save(&keys)?;
+        Ok(result)
+    }
+}
+
+impl KeyManager for LocalKms {
+    fn generate_data_key(&self, key_id: &str) -> io::Result<DataKey> {
+        self.with_key(key_id, |key| {
+            if !key.enabled {
+                return Err(disabled(key_id));
+            }
+            let version = key.material.len() - 1;
+            let plaintext: [u8; 32] = Aes256Gcm::generate_key(OsRng).into();
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let sealed = key.cipher(version)?
+                .encrypt(&nonce, Payload { msg: &plaintext, aad: key_id.as_bytes() })
+                .map_err(|_| io::Error::other("Failed to wrap data key"))?;
+            Ok(DataKey {
+                plaintext,
+                ciphertext: format!("{}:{}", version, hex::encode([nonce.as_slice(), &sealed].concat())),
+                key_id: key_id.to_string(),
+            })
+        })
+    }
+
+    fn decrypt(&self, key_id: &str, ciphertext: &str) -> io::Result<[u8; 32]> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid wrapped data key");
+        let (version, wrapped) = ciphertext.split_once(':').ok_or_else(invalid)?;
+        let version: usize = version.parse().map_err(|_| invalid())?;
+        let wrapped = hex::decode(wrapped).map_err(|_| invalid())?;
+        if wrapped.len() <= NONCE_SIZE {
+            return Err(invalid());
+        }
+        let (nonce, sealed) = wrapped.split_at(NONCE_SIZE);
+        self.with_key(key_id, |key| {
+            if !key.enabled {
+                return Err(disabled(key_id));
+            }
+            key.cipher(version)?
+                .decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad: key_id.as_bytes() })
+                .ok()
+                .and_then(|plaintext| plaintext.try_into().ok())
+                .ok_or_else(invalid)
+        })
+    }
+
+    fn describe_key(&self, key_id: &str) -> io::Result<KeyMetadata> {
+        self.with_key(key_id, |key| Ok(key.metadata(key_id)))
+    }
+
+    fn create_key(&self, key_id: &str) -> io::Result<KeyMetadata> {
+        let mut keys = self.keys.lock().unwrap();
+        if keys.contains_key(key_id) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("KMS key {} already exists", key_id)));
+        }
+        let key = LocalKey::new();
+        let metadata = key.metadata(key_id);
+        keys.insert(key_id.to_string(), key);
+        self.save(&keys)?;
+        Ok(metadata)
+    }
+
+    fn rotate_key(&self, key_id: &str) -> io::Result<KeyMetadata> {
+        self.update_key(key_id, |key| {
+            key.material.push(hex::encode(Aes256Gcm::generate_key(OsRng)));
+            key.metadata(key_id)
+        })
+    }
+
+    fn set_key_enabled(&self, key_id: &str, enabled: bool) -> io::Result<KeyMetadata> {
+        self.update_key(key_id, |key| {
+            key.enabled = enabled;
+            key.metadata(key_id)
+        })
+    }
+
+    fn list_keys(&self) -> io::Result<Vec<KeyMetadata>> {
+        Ok(self.keys.lock().unwrap().iter()
+            .map(|(key_id, key)| key.metadata(key_id))
+            .collect())
+    }
+}
+
+// Client for a remote KMS speaking the AWS KMS JSON protocol (e.g. a local
+// stand-in such as local-kms). Requests are not signed.
+pub struct HttpKms {
+    endpoint: String,
+    agent: ureq::Agent,
+}
+
+impl HttpKms {
+    pub fn new(endpoint: &str) -> Self {
+        HttpKms {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            agent: ureq::AgentBuilder::new().build(),
+        }
+    }
+
+    fn call(&self, action: &str, body: serde_json::Value) -> io::Result<serde_json::Value> {
+        let response = self.agent.post(&format!("{}/", self.endpoint))
+            .set("X-Amz-Target", &format!("TrentService.{}", action))
+            .set("Content-Type", "application/x-amz-json-1.1")
+            .send_json(body);
+        match response {
+            Ok(response) => response.into_json(),
+            Err(ureq::Error::Status(_, response)) => {
+                let error: serde_json::Value = response.into_json().unwrap_or_default();
+                let kind = error["__type"].as_str().unwrap_or_default();
+                let message = format!("{}: {}", kind, error["message"].as_str().unwrap_or_default());
+                Err(match kind {
+                    k if k.ends_with("NotFoundException") => io::Error::new(io::ErrorKind::NotFound, message),
+                    k if k.ends_with("DisabledException") => io::Error::new(io::ErrorKind::PermissionDenied, message),
+                    _ => io::Error::other(message),
+                })
+            }
+            Err(e) => Err(io::Error::other(e.to_string())),
+        }
+    }
+
+    fn data_key(value: &serde_json::Value) -> io::Result<[u8; 32]> {
+        value.as_str()
+            .and_then(|plaintext| BASE64.decode(plaintext).ok())
+            .and_then(|plaintext| plaintext.try_into().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid data key from KMS"))
+    }
+}
+
+impl KeyManager for HttpKms {
+    fn generate_data_key(&self, key_id: &str) -> io::Result<DataKey> {
+        let response = self.call("GenerateDataKey", serde_json::json!({ "KeyId": key_id, "KeySpec": "AES_256" }))?;
+        Ok(DataKey {
+            plaintext: Self::data_key(&response["Plaintext"])?,
+            ciphertext: response["CiphertextBlob"].as_str()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing CiphertextBlob"))?
+                .to_string(),
+            key_id: response["KeyId"].as_str().unwrap_or(key_id).to_string(),
+        })
+    }
+
+    fn decrypt(&self, key_id: &str, ciphertext: &str) -> io::Result<[u8; 32]> {
+        let response = self.call("Decrypt", serde_json::json!({ "KeyId": key_id, "CiphertextBlob": ciphertext }))?;
+        Self::data_key(&response["Plaintext"])
+    }
+
+    fn describe_key(&self, key_id: &str) -> io::Result<KeyMetadata> {
+        let response = self.call("DescribeKey", serde_json::json!({ "KeyId": key_id }))?;
+        let metadata = &response["KeyMetadata"];
+        Ok(KeyMetadata {
+            key_id: metadata["Arn"].as_str().or(metadata["KeyId"].as_str()).unwrap_or(key_id).to_string(),
+            enabled: metadata["Enabled"].as_bool().unwrap_or(false),
+            created_at: metadata["CreationDate"].as_f64().unwrap_or_default() as u64,
+            versions: 0,
+        })
+    }
+}
+
+lazy_static! {
+    static ref KEY_MANAGER: RwLock<Arc<dyn KeyManager>> = RwLock::new(Arc::new(
+        LocalKms::open(config::metadata_path(KMS_KEYS_FILE)).unwrap_or_else(|_| LocalKms {
+            path: config::metadata_path(KMS_KEYS_FILE),
+            keys: Mutex::new(BTreeMap::new()),
+        })
+    ));
+}
+
+pub fn key_manager() -> Arc<dyn KeyManager> {
+    KEY_MANAGER.read().unwrap().clone()
+}
+
+// Use the remote KMS at `kms_endpoint` if configured, otherwise the local
+// key store
+pub fn load_key_manager() -> io::Result<()> {
+    let key_manager: Arc<dyn KeyManager> = match &config::current().kms_endpoint {
+        Some(endpoint) => Arc::new(HttpKms::new(endpoint)),
+        None => Arc::new(LocalKms::open(config::metadata_path(KMS_KEYS_FILE))?),
+    };
+    *KEY_MANAGER.write().unwrap() = key_manager;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_kms_rotation_and_disable() {
+        let path = config::metadata_path("testkms_keys.json");
+        let _ = fs::remove_file(&path);
+        let kms = LocalKms::open(&path).unwrap();
+        kms.create_key("app").unwrap();
+        assert!(kms.create_key("app").is_err());
+
+        let before = kms.generate_data_key("app").unwrap();
+        assert_eq!(kms.rotate_key("app").unwrap().versions, 2);
+        let after = kms.generate_data_key("app").unwrap();
+        assert!(after.ciphertext.starts_with("1:"));
+
+        // Data keys from before the rotation still unwrap, also after a reload
+        let kms = LocalKms::open(&path).unwrap();
+        assert_eq!(kms.decrypt("app", &before.ciphertext).unwrap(), before.plaintext);
+        assert_eq!(kms.decrypt("app", &after.ciphertext).unwrap(), after.plaintext);
+        assert!(kms.decrypt("other", &before.ciphertext).is_err());
+
+        kms.set_key_enabled("app", false).unwrap();
+        let err = kms.decrypt("app", &before.ciphertext).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(kms.generate_data_key("app").is_err());
+        kms.set_key_enabled("app", true).unwrap();
+        assert!(kms.generate_data_key("app").is_ok());
+
+        // Using a key leaves the key store untouched
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        kms.generate_data_key("app").unwrap();
+        kms.decrypt("app", &after.ciphertext).unwrap();
+        kms.describe_key("app").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), modified);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -1,20 +1,152 @@
-use actix_web::{App, HttpServer};
+use actix_web::{middleware, rt, web, App, HttpServer};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use controller::access_log;
 use controller::bucket;
-use controller::object;
-use controller::multipart;
 use controller::versioning;
 use controller::version;
 use controller::auth;
+use controller::admin;
+use controller::snapshot;
+use controller::lifecycle;
+use controller::metrics;
+use controller::object_lock;
+use controller::tagging;
+use controller::encryption;
+use controller::cors;
+use controller::website;
+use controller::virtual_host;
+use controller::router;
 
+mod blob;
+mod config;
 mod controller;
+mod crypto;
+mod kms;
+mod sigv4;
+mod telemetry;
+mod tls;
+mod xml;
 
-const SERVER_ADDRESS: &str = "127.0.0.1:8080";
+const BLOB_GC_INTERVAL: Duration = Duration::from_secs(600);
+const LIFECYCLE_INTERVAL: Duration = Duration::from_secs(3600);
+const ACCESS_LOG_INTERVAL: Duration = Duration::from_secs(300);
+
+// `r3 presign <access-key-id> <method> <url> [expires]` prints a presigned URL
+fn presign_command(args: &[String]) -> std::io::Result<()> {
+    let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidInput, message.to_string());
+    let [access_key_id, method, url, rest @ ..] = args else {
+        return Err(invalid("usage: r3 presign <access-key-id> <method> <url> [expires-seconds]"));
+    };
+    let expires = match rest.first() {
+        Some(expires) => expires.parse().map_err(|_| invalid("expires must be a number of seconds"))?,
+        None => 3600,
+    };
+    auth::load_access_keys()?;
+    let secret = auth::secret_key(access_key_id).ok_or_else(|| invalid("unknown access key"))?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let url = sigv4::presign_url(&method.to_uppercase(), url, access_key_id, &secret, expires, now)
+        .ok_or_else(|| invalid("invalid URL, or expires is not between 1 second and 7 days"))?;
+    println!("{}", url);
+    Ok(())
+}
+
+// Take `--config <path>` out of `args`
+fn config_flag(args: &mut Vec<String>) -> Result<Option<PathBuf>, String> {
+    let Some(i) = args.iter().position(|arg| arg == "--config") else {
+        return Ok(None);
+    };
+    if i + 1 >= args.len() {
+        return Err("--config requires a file path".to_string());
+    }
+    let path = args.remove(i + 1);
+    args.remove(i);
+    Ok(Some(PathBuf::from(path)))
+}
+
+// Load and validate the configuration, then move to the data directory
+fn configure() -> Result<Vec<String>, String> {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut config = config::load(config_flag(&mut args)?.as_deref())?;
+    config.prepare_dirs()?;
+    std::env::set_current_dir(&config.data_dir)
+        .map_err(|e| format!("data_dir: {}: {}", config.data_dir.display(), e))?;
+    env_logger::Builder::new()
+        .parse_filters(&config.log_level)
+        .format_key_values(telemetry::format_key_values)
+        .init();
+    config::set(config);
+    Ok(args)
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let args = match configure() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(2);
+        }
+    };
+    if args.first().map(String::as_str) == Some("presign") {
+        return presign_command(&args[1..]);
+    }
+    let config = config::current();
+    let tls = config.tls.as_ref().map(|tls| {
+        let resolver = Arc::new(tls::CertResolver::load(tls)?);
+        Ok::<_, std::io::Error>((tls::server_config(tls, resolver.clone())?, resolver))
+    }).transpose().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: tls: {}", e);
+        std::process::exit(2);
+    });
+    let tracer_provider = config.tracing.as_ref().map(telemetry::init).transpose().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(2);
+    });
+    if let Err(e) = auth::load_access_keys() {
+        log::error!(error:% = e; "Failed to load access keys");
+    }
+    virtual_host::load_domains();
     if let Err(e) = bucket::load_policies() {
-        eprintln!("Failed to load bucket policies: {}", e);
+        log::error!(error:% = e; "Failed to load bucket policies");
+    }
+    if let Err(e) = versioning::load_versioning() {
+        log::error!(error:% = e; "Failed to load bucket versioning");
+    }
+    if let Err(e) = versioning::load_versions() {
+        log::error!(error:% = e; "Failed to load object versions");
+    }
+    if let Err(e) = snapshot::load_snapshots() {
+        log::error!(error:% = e; "Failed to load snapshots");
+    }
+    if let Err(e) = object_lock::load_object_lock() {
+        log::error!(error:% = e; "Failed to load bucket object lock");
+    }
+    if let Err(e) = tagging::load_tagging() {
+        log::error!(error:% = e; "Failed to load bucket tagging");
+    }
+    if let Err(e) = cors::load_cors() {
+        log::error!(error:% = e; "Failed to load CORS configuration");
+    }
+    if let Err(e) = website::load_website() {
+        log::error!(error:% = e; "Failed to load website configuration");
+    }
+    if let Err(e) = encryption::load_encryption() {
+        log::error!(error:% = e; "Failed to load bucket encryption");
+    }
+    if let Err(e) = crypto::load_master_key() {
+        log::error!(error:% = e; "Failed to load master key");
+    }
+    if let Err(e) = kms::load_key_manager() {
+        log::error!(error:% = e; "Failed to load KMS keys");
+    }
+    if let Err(e) = lifecycle::load_lifecycle() {
+        log::error!(error:% = e; "Failed to load bucket lifecycle");
+    }
+    if let Err(e) = access_log::load_logging() {
+        log::error!(error:% = e; "Failed to load bucket logging");
     }
     println!(r#"
   ____  ____  _____
@@ -26,30 +158,117 @@ async fn main() -> std::io::Result<()> {
 S3-Compatible Storage Service
 Author: Your Name
 Version: 0.1.0
-Listening on: http://{}
-"#, SERVER_ADDRESS);
-    HttpServer::new(|| {
+Listening on: http://{}{}
+Websites on: http://{}
+Data directory: {}{}
+"#,
+        config.listen,
+        config.tls.as_ref().map(|tls| format!(", https://{}", tls.listen)).unwrap_or_default(),
+        config.website_listen,
+        std::env::current_dir()?.display(),
+        config.tracing.as_ref().map(|tracing| format!("\nTraces to: {}", tracing.otlp_endpoint)).unwrap_or_default()
+    );
+    // Periodically reclaim blobs that no version references anymore
+    rt::spawn(async {
+        let mut interval = rt::time::interval(BLOB_GC_INTERVAL);
+        loop {
+            interval.tick().await;
+            match blob::BLOB_STORE.collect_garbage() {
+                Ok(collected) => {
+                    metrics::record_task("blob_gc", true);
+                    metrics::record_blobs_collected(collected);
+                }
+                Err(e) => {
+                    metrics::record_task("blob_gc", false);
+                    log::error!(error:% = e; "Blob garbage collection failed");
+                }
+            }
+        }
+    });
+    // Apply bucket lifecycle rules in the background
+    rt::spawn(async {
+        let mut interval = rt::time::interval(LIFECYCLE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let report = lifecycle::run_lifecycle(now);
+            metrics::record_task("lifecycle", true);
+            metrics::record_lifecycle(&report);
+        }
+    });
+    // Deliver server access logs to their target buckets
+    rt::spawn(async {
+        let mut interval = rt::time::interval(ACCESS_LOG_INTERVAL);
+        loop {
+            interval.tick().await;
+            match access_log::deliver_logs() {
+                Ok(delivered) => {
+                    metrics::record_task("access_log", true);
+                    log::debug!(objects = delivered; "Delivered server access logs");
+                }
+                Err(e) => {
+                    metrics::record_task("access_log", false);
+                    log::error!(error:% = e; "Server access log delivery failed");
+                }
+            }
+        }
+    });
+    // Static websites are served on their own listener, by Host header
+    let mut website_server = HttpServer::new(|| {
         App::new()
+            .wrap(middleware::from_fn(cors::website_cors_headers))
+            .default_service(web::to(website::serve))
+    })
+    .bind(&config.website_listen)?;
+    if config.workers > 0 {
+        website_server = website_server.workers(config.workers);
+    }
+    let website_server = website_server.run();
+    rt::spawn(website_server);
+    let require_signature = config.auth_mode == config::AuthMode::Sigv4;
+    let mut server = HttpServer::new(move || {
+        App::new()
+            .wrap(middleware::from_fn(auth::verify_payload_hash))
+            .wrap(middleware::Condition::new(require_signature, middleware::from_fn(auth::require_signature)))
+            .wrap(middleware::from_fn(metrics::record))
+            .wrap(middleware::from_fn(cors::cors_headers))
+            .wrap(middleware::from_fn(telemetry::trace_requests))
+            .wrap(middleware::from_fn(access_log::log_requests))
+            .wrap(middleware::from_fn(virtual_host::rewrite_host))
             .service(version)
-            .service(object::create_object)
-            .service(object::read_object)
-            .service(object::delete_object)
+            .service(metrics::get_metrics)
+            .service(admin::restore_prefix)
+            .service(admin::presign)
+            .service(admin::create_kms_key)
+            .service(admin::list_kms_keys)
+            .service(admin::rotate_kms_key)
+            .service(admin::set_kms_key_enabled)
+            .service(snapshot::post_snapshot)
+            .service(snapshot::list_snapshots)
+            .service(snapshot::get_snapshot)
+            .service(snapshot::restore_snapshot)
+            .service(snapshot::delete_snapshot)
             .service(bucket::create_bucket)
-            .service(bucket::delete_bucket)
-            .service(bucket::set_bucket_policy)
-            .service(bucket::get_bucket_policy)
-            .service(multipart::initiate_multipart_upload)
-            .service(multipart::upload_part)
-            .service(multipart::complete_multipart_upload)
-            .service(multipart::abort_multipart_upload)
-            .service(versioning::put_bucket_versioning)
-            .service(versioning::list_object_versions)
-            .service(versioning::get_object_version)
-            .service(versioning::delete_object_version)
             .service(auth::register)
             .service(auth::login)
+            .service(auth::create_access_key)
+            .configure(router::configure)
     })
-    .bind(SERVER_ADDRESS)?
-    .run()
-    .await
+    .on_connect(tls::on_connect)
+    .bind(&config.listen)?;
+    if let (Some(tls_config), Some((server_config, resolver))) = (&config.tls, tls) {
+        server = server.bind_rustls_0_23(&tls_config.listen, server_config)?;
+        tls::watch(resolver, tls_config.clone());
+    }
+    if config.workers > 0 {
+        server = server.workers(config.workers);
+    }
+    server.run().await?;
+    // Export the spans still buffered
+    if let Some(provider) = tracer_provider {
+        if let Err(e) = provider.shutdown() {
+            log::warn!(error:% = e; "Failed to flush traces");
+        }
+    }
+    Ok(())
 }
\ No newline at end of file
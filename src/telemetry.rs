@@ -0,0 +1,212 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage};
+use base64::Engine;
+use lazy_static::lazy_static;
+use opentelemetry::trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::io::{self, Write};
+use crate::config::TracingConfig;
+use crate::controller::{access_log, metrics};
+use crate::xml;
+
+// Request IDs and tracing: every response carries `x-amz-request-id` and
+// `x-amz-id-2`, error bodies and log records written while handling a
+// request name its ID, and with `[tracing]` configured, spans for requests
+// and the storage and metadata work they do are exported over OTLP
+
+const TRACER: &str = "r3";
+
+lazy_static! {
+    // Distinguishes this server process in extended request IDs
+    static ref HOST_SALT: String = uuid::Uuid::new_v4().to_string();
+}
+
+// The ID of the request being handled
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+pub fn new_request_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()[..16].to_uppercase()
+}
+
+// The extended request ID (`x-amz-id-2`, `HostId`) for `request_id`
+pub fn host_id(request_id: &str) -> String {
+    let digest = Sha256::digest(format!("{}/{}", *HOST_SALT, request_id));
+    base64::engine::general_purpose::STANDARD.encode(digest)
+}
+
+// The ID of the request this thread is working on, if any
+pub fn current_request_id() -> Option<String> {
+    Context::current().get::<RequestId>().map(|id| id.0.clone())
+}
+
+// Start exporting spans to the collector; the provider flushes them when
+// shut down
+pub fn init(config: &TracingConfig) -> Result<SdkTracerProvider, String> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&config.otlp_endpoint)
+        .build()
+        .map_err(|e| format!("tracing: {}", e))?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio))))
+        .with_resource(Resource::builder().with_service_name(config.service_name.clone()).build())
+        .build();
+    global::set_tracer_provider(provider.clone());
+    Ok(provider)
+}
+
+// Run `f` in a span that is a child of the current one. Spans are only
+// recorded when tracing is configured.
+pub fn in_span<T>(name: &'static str, attributes: impl IntoIterator<Item = KeyValue>, f: impl FnOnce() -> T) -> T {
+    let tracer = global::tracer(TRACER);
+    let span = tracer.span_builder(name).with_attributes(attributes).start(&tracer);
+    let _guard = Context::current_with_span(span).attach();
+    f()
+}
+
+// `in_span` for a future, which runs in the span each time it is polled
+pub async fn in_span_async<T>(
+    name: &'static str,
+    attributes: impl IntoIterator<Item = KeyValue>,
+    future: impl Future<Output = T>,
+) -> T {
+    let tracer = global::tracer(TRACER);
+    let span = tracer.span_builder(name).with_attributes(attributes).start(&tracer);
+    future.with_context(Context::current_with_span(span)).await
+}
+
+// `env_logger` key-value format: the record's own fields, then the ID of the
+// request being handled
+pub fn format_key_values(buf: &mut env_logger::fmt::Formatter, fields: &dyn log::kv::Source) -> io::Result<()> {
+    env_logger::fmt::default_kv_format(buf, fields)?;
+    match current_request_id() {
+        Some(request_id) if fields.get(log::kv::Key::from("request_id")).is_none() => {
+            write!(buf, " request_id={}", request_id)
+        }
+        _ => Ok(()),
+    }
+}
+
+// S3 error document for an error response
+fn error_body(res: &ServiceResponse, request_id: &str) -> Option<String> {
+    let error = res.response().error()?.to_string();
+    let code = access_log::error_code_for(res.status(), Some(&error), res.request().path());
+    let message = match error.split_once(':') {
+        Some((prefix, message)) if prefix.trim() == code => message.trim(),
+        _ if error.trim() == code => "",
+        _ => error.trim(),
+    };
+    let message = match message {
+        "" => res.status().canonical_reason().unwrap_or_default(),
+        message => message,
+    };
+    Some(format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<Error><Code>{}</Code><Message>{}</Message><Resource>{}</Resource><RequestId>{}</RequestId><HostId>{}</HostId></Error>"#,
+        xml::escape(&code),
+        xml::escape(message),
+        xml::escape(res.request().path()),
+        request_id,
+        host_id(request_id)
+    ))
+}
+
+// Middleware running each request in a span named by its S3 operation,
+// adding the request ID headers and turning errors into S3 error documents.
+// The request ID comes from the access log middleware when it runs first.
+pub async fn trace_requests(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<BoxBody>, Error> {
+    let request_id = req.extensions().get::<RequestId>().map(|id| id.0.clone()).unwrap_or_else(new_request_id);
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+    let tracer = global::tracer(TRACER);
+    let span = tracer.span_builder(metrics::request_operation(&req))
+        .with_kind(SpanKind::Server)
+        .with_attributes([
+            KeyValue::new("http.request.method", req.method().to_string()),
+            KeyValue::new("url.path", req.path().to_string()),
+            KeyValue::new("r3.request_id", request_id.clone()),
+        ])
+        .start(&tracer);
+    let cx = Context::current_with_span(span).with_value(RequestId(request_id.clone()));
+    let result = next.call(req).with_context(cx.clone()).await;
+    let status = match &result {
+        Ok(res) => res.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+    let span = cx.span();
+    span.set_attribute(KeyValue::new("http.response.status_code", status.as_u16() as i64));
+    if status.is_server_error() {
+        span.set_status(Status::error(status.to_string()));
+    }
+    span.end();
+
+    let mut res = result?.map_into_boxed_body();
+
+    let headers = res.headers_mut();
+    for (name, value) in [("x-amz-request-id", request_id.clone()), ("x-amz-id-2", host_id(&request_id))] {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(header::HeaderName::from_static(name), value);
+        }
+    }
+    match error_body(&res, &request_id) {
+        Some(body) => Ok(res.map_body(|head, _| {
+            head.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"));
+            BoxBody::new(body)
+        })),
+        None => Ok(res),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::router;
+    use actix_web::{middleware, test, App};
+
+    #[actix_rt::test]
+    async fn test_request_ids_and_error_documents() {
+        let bucket = "testbucket_request_ids";
+        let _ = std::fs::remove_dir_all(bucket);
+        let app = test::init_service(App::new()
+            .wrap(middleware::from_fn(trace_requests))
+            .wrap(middleware::from_fn(access_log::log_requests))
+            .configure(router::configure)).await;
+
+        let req = test::TestRequest::put().uri(&format!("/{}/a.txt", bucket)).set_payload("traced").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let request_id = resp.headers().get("x-amz-request-id").unwrap().to_str().unwrap().to_string();
+        assert_eq!(request_id.len(), 16);
+        assert_eq!(resp.headers().get("x-amz-id-2").unwrap().to_str().unwrap(), host_id(&request_id));
+
+        // Errors become S3 error documents naming the request
+        let req = test::TestRequest::get().uri(&format!("/{}/missing.txt", bucket)).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 404);
+        assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "application/xml");
+        let request_id = resp.headers().get("x-amz-request-id").unwrap().to_str().unwrap().to_string();
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert_eq!(xml::element_text(&body, "Code"), Some("NoSuchKey"));
+        assert_eq!(xml::element_text(&body, "RequestId"), Some(request_id.as_str()));
+        assert_eq!(xml::element_text(&body, "HostId"), Some(host_id(&request_id).as_str()));
+        let _ = std::fs::remove_dir_all(bucket);
+    }
+
+    #[actix_rt::test]
+    async fn test_request_id_in_context() {
+        assert_eq!(current_request_id(), None);
+        let cx = Context::current().with_value(RequestId("0123456789ABCDEF".to_string()));
+        let _guard = cx.attach();
+        let id = in_span("test", [], current_request_id);
+        assert_eq!(id.as_deref(), Some("0123456789ABCDEF"));
+    }
+}
@@ -0,0 +1,282 @@
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{ProtocolVersion, RootCertStore, ServerConfig};
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use crate::config::TlsConfig;
+use crate::controller::metrics;
+
+// HTTPS termination: certificates come from PEM files, are picked by SNI
+// and are reloaded on SIGHUP or when the files change. Established
+// connections keep the certificate they were opened with. Client
+// certificates (mTLS) authenticate as the user named by their common name;
+// the CA bundle they are verified against is read once, at startup.
+
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+fn invalid(path: &Path, message: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), message))
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path).map(BufReader::new).map_err(|e| invalid(path, e))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| invalid(path, e))?;
+    if certs.is_empty() {
+        return Err(invalid(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|e| invalid(path, e))?
+        .ok_or_else(|| invalid(path, "no private key found"))
+}
+
+fn certified_key(cert: &Path, key: &Path) -> io::Result<Arc<CertifiedKey>> {
+    let signing_key = ring::sign::any_supported_type(&load_key(key)?).map_err(|e| invalid(key, e))?;
+    let certified = CertifiedKey::new(load_certs(cert)?, signing_key);
+    certified.keys_match().map_err(|_| invalid(key, format!("does not match {}", cert.display())))?;
+    Ok(Arc::new(certified))
+}
+
+#[derive(Debug)]
+struct Certificates {
+    default: Arc<CertifiedKey>,
+    // Lowercased server name (or `*.domain`) -> certificate
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl Certificates {
+    fn load(tls: &TlsConfig) -> io::Result<Self> {
+        let mut by_name = HashMap::new();
+        for sni in &tls.sni {
+            by_name.insert(sni.server_name.to_ascii_lowercase(), certified_key(&sni.cert, &sni.key)?);
+        }
+        Ok(Certificates { default: certified_key(&tls.cert, &tls.key)?, by_name })
+    }
+}
+
+// Picks the certificate for each handshake; reloading swaps the whole set
+#[derive(Debug)]
+pub struct CertResolver {
+    certificates: RwLock<Arc<Certificates>>,
+}
+
+impl CertResolver {
+    pub fn load(tls: &TlsConfig) -> io::Result<Self> {
+        Ok(CertResolver { certificates: RwLock::new(Arc::new(Certificates::load(tls)?)) })
+    }
+
+    // Load every certificate again; on error the current ones stay in use
+    pub fn reload(&self, tls: &TlsConfig) -> io::Result<()> {
+        let certificates = Certificates::load(tls)?;
+        *self.certificates.write().unwrap() = Arc::new(certificates);
+        Ok(())
+    }
+
+    // The certificate for `server_name`: an exact match, then a wildcard
+    // for its parent domain, then the default
+    fn certificate_for(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let certificates = self.certificates.read().unwrap().clone();
+        server_name
+            .map(str::to_ascii_lowercase)
+            .and_then(|name| {
+                certificates.by_name.get(&name).or_else(|| {
+                    let (_, parent) = name.split_once('.')?;
+                    certificates.by_name.get(&format!("*.{}", parent))
+                }).cloned()
+            })
+            .unwrap_or_else(|| certificates.default.clone())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.certificate_for(client_hello.server_name()))
+    }
+}
+
+pub fn server_config(tls: &TlsConfig, resolver: Arc<CertResolver>) -> io::Result<ServerConfig> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let builder = match &tls.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca)? {
+                roots.add(cert).map_err(|e| invalid(client_ca, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if tls.require_client_cert { verifier } else { verifier.allow_unauthenticated() };
+            builder.with_client_cert_verifier(verifier.build().map_err(|e| invalid(client_ca, e))?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(builder.with_cert_resolver(resolver))
+}
+
+fn last_modified(tls: &TlsConfig) -> Option<SystemTime> {
+    tls.certificate_files().iter().filter_map(|path| path.metadata().and_then(|m| m.modified()).ok()).max()
+}
+
+// Reload certificates on SIGHUP, and when any of their files changes
+pub fn watch(resolver: Arc<CertResolver>, tls: TlsConfig) {
+    let reload = |resolver: &CertResolver, tls: &TlsConfig| {
+        let result = resolver.reload(tls);
+        metrics::record_task("tls_reload", result.is_ok());
+        match result {
+            Ok(()) if tls.client_ca.is_some() => {
+                log::info!("Reloaded TLS certificates; client_ca changes take effect after a restart")
+            }
+            Ok(()) => log::info!("Reloaded TLS certificates"),
+            Err(e) => log::error!(error:% = e; "Failed to reload TLS certificates"),
+        }
+    };
+    #[cfg(unix)]
+    {
+        let (resolver, tls) = (resolver.clone(), tls.clone());
+        actix_web::rt::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut hangups = match signal(SignalKind::hangup()) {
+                Ok(hangups) => hangups,
+                Err(e) => return log::warn!(error:% = e; "Failed to listen for SIGHUP"),
+            };
+            while hangups.recv().await.is_some() {
+                reload(&resolver, &tls);
+            }
+        });
+    }
+    actix_web::rt::spawn(async move {
+        let mut modified = last_modified(&tls);
+        let mut interval = actix_web::rt::time::interval(RELOAD_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let latest = last_modified(&tls);
+            if latest != modified {
+                modified = latest;
+                reload(&resolver, &tls);
+            }
+        }
+    });
+}
+
+// The user a verified client certificate authenticates as
+#[derive(Clone, Debug)]
+pub struct ClientIdentity(pub String);
+
+fn common_name(cert: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let common_name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(common_name.to_string())
+}
+
+// The TLS version a connection negotiated, as named in access logs
+#[derive(Clone, Debug)]
+pub struct TlsVersion(pub &'static str);
+
+fn version_name(version: ProtocolVersion) -> Option<&'static str> {
+    match version {
+        ProtocolVersion::TLSv1_2 => Some("TLSv1.2"),
+        ProtocolVersion::TLSv1_3 => Some("TLSv1.3"),
+        _ => None,
+    }
+}
+
+// `HttpServer::on_connect` hook recording the negotiated TLS version and
+// the client certificate's user
+pub fn on_connect(connection: &dyn Any, extensions: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+    let (_, session) = stream.get_ref();
+    if let Some(version) = session.protocol_version().and_then(version_name) {
+        extensions.insert(TlsVersion(version));
+    }
+    if let Some(username) = session.peer_certificates().and_then(|certs| certs.first()).and_then(|cert| common_name(cert)) {
+        extensions.insert(ClientIdentity(username));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SniCertificate;
+    use rcgen::{CertificateParams, DnType, KeyPair};
+    use std::path::PathBuf;
+
+    // Write a self-signed certificate for `names` to `dir/name.{crt,key}`
+    fn write_cert(dir: &Path, name: &str, names: &[&str]) -> (PathBuf, PathBuf, CertificateDer<'static>) {
+        let mut params = CertificateParams::new(names.iter().map(|n| n.to_string()).collect::<Vec<_>>()).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        let key = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        let (cert_path, key_path) = (dir.join(format!("{}.crt", name)), dir.join(format!("{}.key", name)));
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key.serialize_pem()).unwrap();
+        (cert_path, key_path, cert.der().clone())
+    }
+
+    #[test]
+    fn test_sni_and_reload() {
+        let dir = Path::new("testbucket_tls");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let (cert, key, default_der) = write_cert(dir, "default", &["localhost"]);
+        let (sni_cert, sni_key, sni_der) = write_cert(dir, "example", &["*.example.com"]);
+        let tls = TlsConfig {
+            cert: cert.clone(),
+            key: key.clone(),
+            sni: vec![SniCertificate { server_name: "*.example.com".to_string(), cert: sni_cert, key: sni_key }],
+            ..Default::default()
+        };
+        let resolver = CertResolver::load(&tls).unwrap();
+        assert_eq!(resolver.certificate_for(Some("localhost")).cert[0], default_der);
+        assert_eq!(resolver.certificate_for(Some("Photos.Example.com")).cert[0], sni_der);
+        assert_eq!(resolver.certificate_for(None).cert[0], default_der);
+        assert!(server_config(&tls, Arc::new(resolver)).is_ok());
+
+        // A reload picks up new files; a broken one keeps the old certificates
+        let resolver = CertResolver::load(&tls).unwrap();
+        let (_, _, renewed_der) = write_cert(dir, "default", &["localhost"]);
+        resolver.reload(&tls).unwrap();
+        assert_eq!(resolver.certificate_for(None).cert[0], renewed_der);
+        std::fs::write(&key, "not a key").unwrap();
+        let err = resolver.reload(&tls).unwrap_err();
+        assert!(err.to_string().contains("default.key"), "{}", err);
+        assert_eq!(resolver.certificate_for(None).cert[0], renewed_der);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_client_common_name() {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        let cert = params.self_signed(&KeyPair::generate().unwrap()).unwrap();
+        assert_eq!(common_name(cert.der()).as_deref(), Some("alice"));
+        assert_eq!(common_name(b"garbage"), None);
+    }
+
+    #[test]
+    fn test_version_name() {
+        assert_eq!(version_name(ProtocolVersion::TLSv1_3), Some("TLSv1.3"));
+        assert_eq!(version_name(ProtocolVersion::TLSv1_2), Some("TLSv1.2"));
+        assert_eq!(version_name(ProtocolVersion::SSLv3), None);
+    }
+}
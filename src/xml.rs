@@ -0,0 +1,111 @@
+// Minimal helpers for reading the small XML documents S3 clients send us.
+// Responses are still built with `format!` in the controllers.
+
+// Escape text for use inside an XML element or attribute
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Reverse `escape`, for text read out of request documents
+pub fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// Format a unix timestamp the way S3 does in XML bodies (ISO 8601, UTC)
+pub fn timestamp(secs: u64) -> String {
+    chrono::DateTime::from_timestamp(secs as i64, 0)
+        .unwrap_or_default()
+        .format("%Y-%m-%dT%H:%M:%S.000Z")
+        .to_string()
+}
+
+// Parse an ISO 8601 / RFC 3339 timestamp (e.g. "2024-05-01T12:00:00Z") into unix seconds
+pub fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    chrono::DateTime::parse_from_rfc3339(timestamp.trim())
+        .ok()
+        .map(|t| t.timestamp().max(0) as u64)
+}
+
+// Return the text of the first `<tag>...</tag>` element in `body`
+pub fn element_text<'a>(body: &'a str, tag: &str) -> Option<&'a str> {
+    elements(body, tag).into_iter().next().map(str::trim)
+}
+
+// Return the inner content of every `<tag>...</tag>` element in `body`
+pub fn elements<'a>(body: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{}", tag);
+    let close = format!("</{}>", tag);
+    let mut found = Vec::new();
+    let mut rest = body;
+    while let Some(start) = rest.find(&open) {
+        let after = &rest[start + open.len()..];
+        // Make sure we matched the whole tag name and not a prefix of another
+        let Some(end_of_tag) = after.find('>') else { break };
+        let attrs = &after[..end_of_tag];
+        if !(attrs.is_empty() || attrs.starts_with(' ') || attrs.starts_with('/')) {
+            rest = after;
+            continue;
+        }
+        if attrs.ends_with('/') {
+            found.push("");
+            rest = &after[end_of_tag + 1..];
+            continue;
+        }
+        let content = &after[end_of_tag + 1..];
+        let Some(end) = content.find(&close) else { break };
+        found.push(&content[..end]);
+        rest = &content[end + close.len()..];
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape(r#"a&b<c>"d"'e'"#), "a&amp;b&lt;c&gt;&quot;d&quot;&apos;e&apos;");
+        assert_eq!(unescape(&escape(r#"a&lt;b"#)), "a&lt;b");
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(timestamp(1_700_000_000), "2023-11-14T22:13:20.000Z");
+        assert_eq!(parse_timestamp("2023-11-14T22:13:20.000Z"), Some(1_700_000_000));
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    fn test_element_text() {
+        let body = r#"<VersioningConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+            <Status> Enabled </Status>
+        </VersioningConfiguration>"#;
+        assert_eq!(element_text(body, "Status"), Some("Enabled"));
+        assert_eq!(element_text(body, "MfaDelete"), None);
+    }
+
+    #[test]
+    fn test_elements_skips_prefixed_tags() {
+        let body = "<Rules><Rule><ID>a</ID></Rule><RuleSet/><Rule><ID>b</ID></Rule></Rules>";
+        let rules = elements(body, "Rule");
+        assert_eq!(rules.len(), 2);
+        assert_eq!(element_text(rules[1], "ID"), Some("b"));
+    }
+}